@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use serde::Deserialize;
+use crate::events::EvtEvent;
+use crate::glob::glob_matches;
+
+const DEFAULT_MAPPINGS: &str = include_str!("attack_mappings.json");
+
+// A condition value is one pattern or a list where any may match
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ConditionValue {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ConditionValue {
+    fn patterns(&self) -> &[String] {
+        match self {
+            ConditionValue::One(pattern) => std::slice::from_ref(pattern),
+            ConditionValue::Many(patterns) => patterns
+        }
+    }
+}
+
+// One entry of the mapping file. An event matches when its provider (if given),
+// channel (if given) and event ID match, and every condition names an EventData
+// field with a value matching one of its patterns. Patterns ignore case and *
+// matches any run of characters. Like the OCSF mappings, extra keys such as "name"
+// are only there for whoever reads the file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AttackMapping {
+    pub provider: Option<String>,
+    pub channel: Option<String>,
+    pub event_ids: Vec<u32>,
+    #[serde(default)]
+    pub conditions: HashMap<String, ConditionValue>,
+    pub techniques: Vec<String>,
+    #[serde(default)]
+    pub tactics: Vec<String>,
+}
+
+impl AttackMapping {
+    fn matches(&self, event: &EvtEvent) -> bool {
+        let system = event.get_system();
+        self.event_ids.contains(&system.get_event_id())
+            && self.provider.as_ref().map(|provider| provider.eq_ignore_ascii_case(system.get_provider_name())).unwrap_or(true)
+            && self.channel.as_ref().map(|channel| channel.eq_ignore_ascii_case(system.get_channel())).unwrap_or(true)
+            && self.conditions.iter().all(|(field, value)| {
+                event.get_event_data().iter()
+                    .filter(|(name, _)| name == field)
+                    .any(|(_, data)| {
+                        let data = data.to_lowercase();
+                        value.patterns().iter().any(|pattern| glob_matches(&pattern.to_lowercase(), &data))
+                    })
+            })
+    }
+}
+
+pub struct AttackTagger {
+    mappings: Vec<AttackMapping>,
+}
+
+impl AttackTagger {
+    // Every matching mapping adds its tags, so a file can add techniques to
+    // well-known events as well as map new ones. Mappings from the file come first
+    // and their tags lead.
+    pub fn new(mapping_path: Option<&str>) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let mut mappings: Vec<AttackMapping> = Vec::new();
+        if let Some(path) = mapping_path {
+            let reader = BufReader::new(File::open(path)?);
+            let custom: Vec<AttackMapping> = serde_json::from_reader(reader)?;
+            mappings.extend(custom);
+        }
+        let defaults: Vec<AttackMapping> = serde_json::from_str(DEFAULT_MAPPINGS)?;
+        mappings.extend(defaults);
+        Ok(Self { mappings: mappings })
+    }
+
+    pub fn tag(&self, event: &mut EvtEvent) {
+        let mut techniques: Vec<String> = Vec::new();
+        let mut tactics: Vec<String> = Vec::new();
+        for mapping in self.mappings.iter().filter(|mapping| mapping.matches(event)) {
+            for technique in &mapping.techniques {
+                if !techniques.contains(technique) {
+                    techniques.push(technique.to_string());
+                }
+            }
+            for tactic in &mapping.tactics {
+                if !tactics.contains(tactic) {
+                    tactics.push(tactic.to_string());
+                }
+            }
+        }
+        event.set_attack_tags(techniques, tactics);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::attack::AttackTagger;
+    use crate::test_support::{temp_path, TestEvent};
+
+    fn event(provider: &str, channel: &str, event_id: u32) -> TestEvent {
+        TestEvent::default().provider(provider).channel(channel).event_id(event_id).level(0).time("2023-05-01T00:00:01.5000000Z").computer("WKS01")
+    }
+
+    #[test]
+    fn test_default_mappings() {
+        let tagger = AttackTagger::new(None).unwrap();
+
+        let mut task_created = event("Microsoft-Windows-Security-Auditing", "Security", 4698).data("TaskName", r"\Updater").build();
+        tagger.tag(&mut task_created);
+        assert_eq!(task_created.get_attack_techniques(), &vec!["T1053.005".to_string()]);
+        assert!(task_created.get_attack_tactics().contains(&"persistence".to_string()));
+
+        // Only remote interactive logons are tagged as RDP
+        let mut rdp_logon = event("Microsoft-Windows-Security-Auditing", "Security", 4624).data("LogonType", "10").build();
+        tagger.tag(&mut rdp_logon);
+        assert_eq!(rdp_logon.get_attack_techniques(), &vec!["T1021.001".to_string()]);
+        let mut network_logon = event("Microsoft-Windows-Security-Auditing", "Security", 4624).data("LogonType", "3").build();
+        tagger.tag(&mut network_logon);
+        assert!(network_logon.get_attack_techniques().is_empty());
+
+        // Image patterns ignore case
+        let mut powershell = event("Microsoft-Windows-Sysmon", "Microsoft-Windows-Sysmon/Operational", 1).data("Image", r"C:\Windows\System32\WindowsPowerShell\v1.0\PowerShell.exe").build();
+        tagger.tag(&mut powershell);
+        assert_eq!(powershell.get_attack_techniques(), &vec!["T1059.001".to_string()]);
+        assert_eq!(powershell.get_attack_tactics(), &vec!["execution".to_string()]);
+    }
+
+    #[test]
+    fn test_custom_mappings_add_tags() {
+        let path = temp_path("attack.json");
+        fs::write(&path, r#"[{"provider": "Microsoft-Windows-Security-Auditing", "event_ids": [4698], "conditions": {"TaskName": ["\\Evil*", "\\Other"]}, "techniques": ["T1000"], "tactics": ["execution"]}]"#).unwrap();
+        let tagger = AttackTagger::new(Some(path.to_str().unwrap())).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut evil = event("Microsoft-Windows-Security-Auditing", "Security", 4698).data("TaskName", r"\EvilTask").build();
+        tagger.tag(&mut evil);
+        assert_eq!(evil.get_attack_techniques(), &vec!["T1000".to_string(), "T1053.005".to_string()]);
+        // execution comes from both mappings but is only listed once
+        assert_eq!(evil.get_attack_tactics().iter().filter(|tactic| *tactic == "execution").count(), 1);
+
+        let mut benign = event("Microsoft-Windows-Security-Auditing", "Security", 4698).data("TaskName", r"\Updater").build();
+        tagger.tag(&mut benign);
+        assert_eq!(benign.get_attack_techniques(), &vec!["T1053.005".to_string()]);
+    }
+}
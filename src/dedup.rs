@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use crate::events::EvtEvent;
+
+// Drops events already seen from another source, e.g. the same record in Security.evtx
+// and an Archive-Security-*.evtx, or in a local log and ForwardedEvents. Records are
+// keyed by (Computer, Channel, EventRecordID) plus a hash of their content, so a record
+// ID reused after a log was cleared isn't taken for a duplicate. The key strings are
+// hashed too, which keeps the table at a few dozen bytes per event.
+#[derive(Default)]
+pub struct Deduplicator {
+    // key -> index of the source the record was first read from
+    seen: HashMap<(u64, u64, u64), usize>,
+    sources: Vec<String>,
+    source_index: HashMap<String, usize>,
+    // (duplicate source, first source) -> events dropped
+    duplicates: HashMap<(usize, usize), usize>,
+}
+
+impl Deduplicator {
+    // Records the event and says whether an identical one was seen before
+    pub fn is_duplicate(&mut self, record_id: u64, event: &EvtEvent) -> bool {
+        let system = event.get_system();
+        let mut hasher = DefaultHasher::new();
+        system.get_computer().to_lowercase().hash(&mut hasher);
+        system.get_channel().to_lowercase().hash(&mut hasher);
+        let log_key = hasher.finish();
+        let key = (log_key, record_id, Self::content_hash(event));
+
+        let source = match self.source_index.get(event.get_source()) {
+            Some(&index) => index,
+            None => {
+                self.sources.push(event.get_source().to_string());
+                self.source_index.insert(event.get_source().to_string(), self.sources.len() - 1);
+                self.sources.len() - 1
+            }
+        };
+        match self.seen.get(&key) {
+            Some(&first_source) => {
+                *self.duplicates.entry((source, first_source)).or_insert(0) += 1;
+                true
+            },
+            None => {
+                self.seen.insert(key, source);
+                false
+            }
+        }
+    }
+
+    // Only what the event recorded, not how it was rendered. A forwarded copy carries
+    // RenderingInfo the local one may not have.
+    fn content_hash(event: &EvtEvent) -> u64 {
+        let system = event.get_system();
+        let mut hasher = DefaultHasher::new();
+        system.get_provider_name().hash(&mut hasher);
+        system.get_event_id().hash(&mut hasher);
+        system.get_system_time().hash(&mut hasher);
+        event.get_event_data().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn duplicate_count(&self) -> usize {
+        self.duplicates.values().sum()
+    }
+
+    // (duplicate source, source it was first read from, events dropped), most first
+    pub fn report(&self) -> Vec<(&str, &str, usize)> {
+        let mut report: Vec<(&str, &str, usize)> = self.duplicates.iter()
+            .map(|(&(source, first_source), &count)| (self.sources[source].as_str(), self.sources[first_source].as_str(), count))
+            .collect();
+        report.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(b.0)).then(a.1.cmp(b.1)));
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dedup::Deduplicator;
+    use crate::events::EvtEvent;
+    use crate::test_support::TestEvent;
+
+    fn event(record_id: u64, user: &str, source: &str) -> EvtEvent {
+        TestEvent::default().provider("Microsoft-Windows-Security-Auditing").event_id(4625).time("2023-05-01T01:00:00.1234567Z")
+            .record_id(record_id).channel("Security").computer("WKS01").data("TargetUserName", user).source(source).build()
+    }
+
+    #[test]
+    fn test_duplicates_across_files() {
+        let mut dedup = Deduplicator::default();
+        for record_id in 1..=3 {
+            assert!(!dedup.is_duplicate(record_id, &event(record_id, "bob", "Security.evtx")));
+        }
+        // The archive overlaps the live log on records 2 and 3
+        for record_id in 2..=4 {
+            let duplicate = dedup.is_duplicate(record_id, &event(record_id, "bob", "Archive-Security-2023.evtx"));
+            assert_eq!(duplicate, record_id != 4);
+        }
+        // Same record ID with different content, as after the log was cleared
+        assert!(!dedup.is_duplicate(1, &event(1, "alice", "ForwardedEvents.evtx")));
+        assert!(dedup.is_duplicate(1, &event(1, "bob", "ForwardedEvents.evtx")));
+
+        assert_eq!(dedup.duplicate_count(), 3);
+        assert_eq!(dedup.report(), vec![
+            ("Archive-Security-2023.evtx", "Security.evtx", 2),
+            ("ForwardedEvents.evtx", "Security.evtx", 1),
+        ]);
+    }
+}
@@ -0,0 +1,139 @@
+use xmltree::{Element, XMLNode};
+
+// Pulls the event payload out of the parsed event XML as ordered name/value pairs.
+// EventData keeps the order of its <Data> elements so positional insertion strings
+// still line up. UserData has no fixed schema, so nested elements are flattened into
+// dotted paths below the UserData element.
+pub fn extract_event_data(root: &Element) -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = Vec::new();
+    if let Some(event_data) = root.get_child("EventData") {
+        let mut data_index = 0;
+        for child in child_elements(event_data) {
+            match child.name.as_str() {
+                "Data" => {
+                    data_index += 1;
+                    // Unnamed Data elements are positional, so name them after their insertion string
+                    let name = match child.attributes.get("Name") {
+                        Some(name) => name.to_string(),
+                        None => format!("param{}", data_index)
+                    };
+                    pairs.push((name, element_text(child)));
+                },
+                "Binary" => pairs.push(("Binary".to_string(), element_text(child))),
+                _ => flatten_element(child, &child.name, &mut pairs)
+            }
+        }
+    }
+    if let Some(user_data) = root.get_child("UserData") {
+        for child in child_elements(user_data) {
+            flatten_element(child, &child.name, &mut pairs);
+        }
+    }
+    pairs
+}
+
+fn flatten_element(element: &Element, path: &str, pairs: &mut Vec<(String, String)>) {
+    // Attributes come out of a HashMap, so sort them to keep the output stable between runs
+    let mut attributes: Vec<(&String, &String)> = element.attributes.iter().collect();
+    attributes.sort();
+    for (name, value) in attributes {
+        if name != "xmlns" {
+            pairs.push((format!("{}.{}", path, name), value.to_string()));
+        }
+    }
+    let mut has_children = false;
+    for child in child_elements(element) {
+        has_children = true;
+        flatten_element(child, &format!("{}.{}", path, child.name), pairs);
+    }
+    let text = element_text(element);
+    // Leaves always get a value, even an empty one, so the field still shows up
+    if !has_children || !text.is_empty() {
+        pairs.push((path.to_string(), text));
+    }
+}
+
+fn child_elements(element: &Element) -> impl Iterator<Item = &Element> {
+    element.children.iter().filter_map(|node| match node {
+        XMLNode::Element(child) => Some(child),
+        _ => None
+    })
+}
+
+// The text exactly as logged. Whitespace around a CommandLine or script block is
+// part of the evidence.
+fn element_text(element: &Element) -> String {
+    match element.get_text() {
+        Some(text) => text.to_string(),
+        None => String::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event_data::extract_event_data;
+    use xmltree::Element;
+
+    fn pairs(xml: &str) -> Vec<(String, String)> {
+        let root = Element::parse(xml.as_bytes()).unwrap();
+        extract_event_data(&root)
+    }
+
+    #[test]
+    fn test_named_event_data_keeps_order() {
+        let data = pairs(r#"<Event><EventData>
+            <Data Name="SubjectUserSid">S-1-5-18</Data>
+            <Data Name="TargetUserName">alice</Data>
+            <Data Name="LogonType">3</Data>
+            <Data Name="IpAddress">-</Data>
+        </EventData></Event>"#);
+        let names: Vec<&str> = data.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["SubjectUserSid", "TargetUserName", "LogonType", "IpAddress"]);
+        assert_eq!(data[1].1, "alice");
+        assert_eq!(data[3].1, "-");
+    }
+
+    #[test]
+    fn test_unnamed_data_and_binary() {
+        let data = pairs(r#"<Event><EventData>
+            <Data>Windows Update</Data>
+            <Data Name="Named">x</Data>
+            <Data></Data>
+            <Binary>0A0B0C</Binary>
+        </EventData></Event>"#);
+        assert_eq!(data, vec![
+            ("param1".to_string(), "Windows Update".to_string()),
+            ("Named".to_string(), "x".to_string()),
+            ("param3".to_string(), "".to_string()),
+            ("Binary".to_string(), "0A0B0C".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_nested_user_data() {
+        let data = pairs(r#"<Event><UserData>
+            <LogFileCleared xmlns="http://manifests.microsoft.com/win/2004/08/windows/eventlog">
+                <SubjectUserName>admin</SubjectUserName>
+                <Details Kind="full">
+                    <Inner>deep</Inner>
+                </Details>
+            </LogFileCleared>
+        </UserData></Event>"#);
+        assert_eq!(data, vec![
+            ("LogFileCleared.SubjectUserName".to_string(), "admin".to_string()),
+            ("LogFileCleared.Details.Kind".to_string(), "full".to_string()),
+            ("LogFileCleared.Details.Inner".to_string(), "deep".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_values_keep_whitespace() {
+        let data = pairs("<Event><EventData><Data Name=\"CommandLine\">  cmd.exe /c dir \n</Data></EventData></Event>");
+        assert_eq!(data[0].1, "  cmd.exe /c dir \n");
+    }
+
+    #[test]
+    fn test_no_payload() {
+        assert!(pairs("<Event><System /></Event>").is_empty());
+    }
+}
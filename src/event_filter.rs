@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use crate::events::RESERVED_KEYWORDS;
+use crate::provider::EvtProvider;
+use crate::xpath::{XPathError, XPathQuery};
+
+// The --event-id, --level, --provider, --channel, --computer and --keyword flags.
+// Values within a flag are alternatives, the flags themselves all have to hold.
+// They compile into one System predicate that goes into the EvtQuery XPath for
+// live channels and is evaluated in-process for .evtx files.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    // Inclusive ranges, a single ID is a range of one
+    pub event_ids: Vec<(u32, u32)>,
+    pub levels: Vec<u8>,
+    pub providers: Vec<String>,
+    pub channels: Vec<String>,
+    pub computers: Vec<String>,
+    pub keywords: Vec<u64>,
+}
+
+// clap value parser for --event-id. Takes an ID or a range like 4624-4634.
+pub fn parse_event_id(value: &str) -> std::result::Result<(u32, u32), String> {
+    let parse = |text: &str| text.trim().parse::<u32>().map_err(|_| format!("Invalid event ID '{}'", text.trim()));
+    match value.split_once('-') {
+        Some((low, high)) => {
+            let (low, high) = (parse(low)?, parse(high)?);
+            if low > high {
+                return Err(format!("Event ID range '{}' runs backwards", value));
+            }
+            Ok((low, high))
+        },
+        None => parse(value).map(|id| (id, id))
+    }
+}
+
+// clap value parser for --level. Takes a level name or number.
+pub fn parse_level(value: &str) -> std::result::Result<u8, String> {
+    match value.trim().to_lowercase().as_str() {
+        "critical" => Ok(1),
+        "error" => Ok(2),
+        "warning" => Ok(3),
+        "information" | "info" => Ok(4),
+        "verbose" => Ok(5),
+        other => other.parse::<u8>().map_err(|_| format!("Unknown level '{}', use critical, error, warning, information, verbose or a number", value))
+    }
+}
+
+// clap value parser for --keyword. Takes a standard keyword name like "Audit Failure"
+// or a mask in hex or decimal.
+pub fn parse_keyword(value: &str) -> std::result::Result<u64, String> {
+    let value = value.trim();
+    let squashed = value.replace([' ', '_', '-'], "").to_lowercase();
+    for (bit, name) in RESERVED_KEYWORDS {
+        if name.replace(' ', "").to_lowercase() == squashed {
+            return Ok(bit);
+        }
+    }
+    let mask = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse::<u64>()
+    };
+    match mask {
+        Ok(mask) if mask != 0 => Ok(mask),
+        _ => Err(format!("Unknown keyword '{}', use a standard keyword name like \"Audit Success\" or a mask like 0x20000000000000", value))
+    }
+}
+
+fn quote(value: &str) -> String {
+    // XPath literals have no escapes, so pick the quote the value doesn't use
+    if value.contains('\'') { format!("\"{}\"", value) } else { format!("'{}'", value) }
+}
+
+fn any_of(conditions: Vec<String>) -> Option<String> {
+    match conditions.len() {
+        0 => None,
+        1 => conditions.into_iter().next(),
+        _ => Some(format!("({})", conditions.join(" or ")))
+    }
+}
+
+impl EventFilter {
+    // String comparisons in the query are case sensitive, so provider and channel
+    // names are spelled the way the publisher metadata spells them where it knows them
+    pub fn canonicalize_names(&mut self, providers: &HashMap<String, EvtProvider>) {
+        for name in self.providers.iter_mut() {
+            if let Some(provider) = providers.values().find(|provider| provider.get_name().eq_ignore_ascii_case(name)) {
+                *name = provider.get_name().to_string();
+            }
+        }
+        for name in self.channels.iter_mut() {
+            let known = providers.values()
+                .flat_map(|provider| provider.get_channels().values())
+                .filter_map(|channel| channel.get("Channel Name"))
+                .find(|channel| channel.eq_ignore_ascii_case(name));
+            if let Some(channel) = known {
+                *name = channel.to_string();
+            }
+        }
+    }
+
+    pub fn allows_provider(&self, provider: &str) -> bool {
+        self.providers.is_empty() || self.providers.iter().any(|name| name.eq_ignore_ascii_case(provider))
+    }
+
+    pub fn allows_channel(&self, channel: &str) -> bool {
+        self.channels.is_empty() || self.channels.iter().any(|name| name.eq_ignore_ascii_case(channel))
+    }
+
+    // Predicate for the System element, the same shape as TimeRange::xpath_condition
+    pub fn xpath_condition(&self) -> Option<String> {
+        let event_ids = self.event_ids.iter().map(|&(low, high)| {
+            if low == high { format!("EventID={}", low) } else { format!("(EventID>={} and EventID<={})", low, high) }
+        }).collect();
+        let levels = self.levels.iter().flat_map(|&level| {
+            // Event Viewer treats win:LogAlways (0) as Information too
+            if level == 4 { vec!["Level=4".to_string(), "Level=0".to_string()] } else { vec![format!("Level={}", level)] }
+        }).collect();
+        let providers = self.providers.iter().map(|name| format!("Provider[@Name={}]", quote(name))).collect();
+        let channels = self.channels.iter().map(|name| format!("Channel={}", quote(name))).collect();
+        let computers = self.computers.iter().map(|name| format!("Computer={}", quote(name))).collect();
+        let keywords = self.keywords.iter().map(|mask| format!("band(Keywords,{})", mask)).collect();
+        let conditions: Vec<String> = [event_ids, levels, providers, channels, computers, keywords]
+            .into_iter()
+            .filter_map(any_of)
+            .collect();
+        if conditions.is_empty() {
+            return None;
+        }
+        Some(conditions.join(" and "))
+    }
+
+    // The whole filter as a query for evaluating parsed events in-process
+    pub fn to_query(&self) -> std::result::Result<Option<XPathQuery>, XPathError> {
+        match self.xpath_condition() {
+            Some(condition) => XPathQuery::parse(&format!("*[System[{}]]", condition)).map(Some),
+            None => Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use xmltree::Element;
+    use crate::event_filter::{parse_event_id, parse_keyword, parse_level, EventFilter};
+
+    #[test]
+    fn test_parse_values() {
+        assert_eq!(parse_event_id("4624").unwrap(), (4624, 4624));
+        assert_eq!(parse_event_id("4624-4634").unwrap(), (4624, 4634));
+        assert!(parse_event_id("4634-4624").is_err());
+        assert_eq!(parse_level("Error").unwrap(), 2);
+        assert_eq!(parse_level("5").unwrap(), 5);
+        assert!(parse_level("loud").is_err());
+        assert_eq!(parse_keyword("Audit Failure").unwrap(), 0x0010000000000000);
+        assert_eq!(parse_keyword("audit_success").unwrap(), 0x0020000000000000);
+        assert_eq!(parse_keyword("0x80000000000000").unwrap(), 0x0080000000000000);
+        assert!(parse_keyword("Logon").is_err());
+    }
+
+    #[test]
+    fn test_compiled_filter() {
+        let filter = EventFilter {
+            event_ids: vec![(4624, 4624), (4625, 4625), (4800, 4803)],
+            levels: vec![4],
+            providers: vec![],
+            channels: vec!["Security".to_string()],
+            computers: vec!["O'Brien-PC".to_string()],
+            keywords: vec![0x0020000000000000],
+        };
+        assert_eq!(
+            filter.xpath_condition().unwrap(),
+            "(EventID=4624 or EventID=4625 or (EventID>=4800 and EventID<=4803)) and (Level=4 or Level=0) and Channel='Security' and Computer=\"O'Brien-PC\" and band(Keywords,9007199254740992)"
+        );
+        let query = filter.to_query().unwrap().unwrap();
+        let event = |event_id: u32, keywords: &str| {
+            let xml = format!("<Event><System><EventID>{}</EventID><Level>0</Level><Keywords>{}</Keywords><Channel>Security</Channel><Computer>O'Brien-PC</Computer></System></Event>", event_id, keywords);
+            Element::parse(xml.as_bytes()).unwrap()
+        };
+        assert!(query.matches(&event(4625, "0x8020000000000000"), Utc::now()));
+        assert!(query.matches(&event(4802, "0x8020000000000000"), Utc::now()));
+        assert!(!query.matches(&event(4634, "0x8020000000000000"), Utc::now()));
+        assert!(!query.matches(&event(4624, "0x8010000000000000"), Utc::now()));
+        assert!(EventFilter::default().to_query().unwrap().is_none());
+    }
+}
@@ -0,0 +1,229 @@
+use chrono::{DateTime, Utc};
+use xmltree::Element;
+use crate::events::EvtError;
+
+// Typed copy of an event's <System> element. Everything is parsed once when the
+// event is created so the writers never have to go back to the XML.
+#[derive(Debug, Clone)]
+pub struct EvtSystem {
+    provider_name: String,
+    provider_guid: Option<String>,
+    event_source_name: Option<String>,
+    event_id: u32,
+    qualifiers: Option<u16>,
+    version: u8,
+    level: u8,
+    task: u16,
+    opcode: u8,
+    keywords: u64,
+    system_time: String,
+    time_created: Option<DateTime<Utc>>,
+    record_id: u64,
+    activity_id: Option<String>,
+    related_activity_id: Option<String>,
+    process_id: Option<u32>,
+    thread_id: Option<u32>,
+    channel: String,
+    computer: String,
+    user_id: Option<String>,
+}
+
+impl EvtSystem {
+    pub fn from_element(system_element: &Element) -> std::result::Result<Self, EvtError> {
+        let provider = system_element.get_child("Provider");
+        let event_id_element = system_element.get_child("EventID").ok_or(EvtError::MissingElement("EventID".to_string()))?;
+        let event_id = Self::get_text(Some(event_id_element)).parse::<u32>()?;
+        let qualifiers = Self::get_attribute(Some(event_id_element), "Qualifiers").and_then(|q| q.parse::<u16>().ok());
+        let record_id = Self::get_text(system_element.get_child("EventRecordID"));
+        if record_id.is_empty() {
+            return Err(EvtError::MissingElement("EventRecordID".to_string()));
+        }
+        let record_id = record_id.parse::<u64>()?;
+
+        // Keywords is the only hex value in System
+        let keywords = Self::get_text(system_element.get_child("Keywords"));
+        let keywords = u64::from_str_radix(keywords.trim_start_matches("0x"), 16).unwrap_or(0);
+
+        // SystemTime comes with 7 or 9 fractional digits. chrono keeps all of them,
+        // so the 100ns ticks survive the round trip.
+        let time_created_element = system_element.get_child("TimeCreated");
+        let system_time = Self::get_attribute(time_created_element, "SystemTime").unwrap_or_default();
+        let time_created = DateTime::parse_from_rfc3339(&system_time).ok().map(|time| time.with_timezone(&Utc));
+
+        let correlation = system_element.get_child("Correlation");
+        let execution = system_element.get_child("Execution");
+
+        Ok(Self {
+            provider_name: Self::get_attribute(provider, "Name").unwrap_or_default(),
+            provider_guid: Self::get_attribute(provider, "Guid"),
+            event_source_name: Self::get_attribute(provider, "EventSourceName"),
+            event_id: event_id,
+            qualifiers: qualifiers,
+            version: Self::get_text(system_element.get_child("Version")).parse::<u8>().unwrap_or(0),
+            level: Self::get_text(system_element.get_child("Level")).parse::<u8>().unwrap_or(0),
+            task: Self::get_text(system_element.get_child("Task")).parse::<u16>().unwrap_or(0),
+            opcode: Self::get_text(system_element.get_child("Opcode")).parse::<u8>().unwrap_or(0),
+            keywords: keywords,
+            system_time: system_time,
+            time_created: time_created,
+            record_id: record_id,
+            activity_id: Self::get_attribute(correlation, "ActivityID"),
+            related_activity_id: Self::get_attribute(correlation, "RelatedActivityID"),
+            process_id: Self::get_attribute(execution, "ProcessID").and_then(|pid| pid.parse::<u32>().ok()),
+            thread_id: Self::get_attribute(execution, "ThreadID").and_then(|tid| tid.parse::<u32>().ok()),
+            channel: Self::get_text(system_element.get_child("Channel")),
+            computer: Self::get_text(system_element.get_child("Computer")),
+            user_id: Self::get_attribute(system_element.get_child("Security"), "UserID"),
+        })
+    }
+
+    fn get_text(element: Option<&Element>) -> String {
+        match element.and_then(|e| e.get_text()) {
+            Some(text) => text.trim().to_string(),
+            None => String::new()
+        }
+    }
+
+    fn get_attribute(element: Option<&Element>, name: &str) -> Option<String> {
+        element.and_then(|e| e.attributes.get(name)).map(|value| value.to_string())
+    }
+
+    pub fn get_provider_name(&self) -> &str {
+        &self.provider_name
+    }
+    pub fn get_provider_guid(&self) -> Option<&str> {
+        self.provider_guid.as_deref()
+    }
+    pub fn get_event_source_name(&self) -> Option<&str> {
+        self.event_source_name.as_deref()
+    }
+    pub fn get_event_id(&self) -> u32 {
+        self.event_id
+    }
+    pub fn get_qualifiers(&self) -> Option<u16> {
+        self.qualifiers
+    }
+    pub fn get_version(&self) -> u8 {
+        self.version
+    }
+    pub fn get_level(&self) -> u8 {
+        self.level
+    }
+    pub fn get_task(&self) -> u16 {
+        self.task
+    }
+    pub fn get_opcode(&self) -> u8 {
+        self.opcode
+    }
+    pub fn get_keywords(&self) -> u64 {
+        self.keywords
+    }
+    pub fn get_system_time(&self) -> &str {
+        &self.system_time
+    }
+    pub fn get_time_created(&self) -> Option<DateTime<Utc>> {
+        self.time_created
+    }
+    pub fn get_record_id(&self) -> u64 {
+        self.record_id
+    }
+    pub fn get_activity_id(&self) -> Option<&str> {
+        self.activity_id.as_deref()
+    }
+    pub fn get_related_activity_id(&self) -> Option<&str> {
+        self.related_activity_id.as_deref()
+    }
+    pub fn get_process_id(&self) -> Option<u32> {
+        self.process_id
+    }
+    pub fn get_thread_id(&self) -> Option<u32> {
+        self.thread_id
+    }
+    pub fn get_channel(&self) -> &str {
+        &self.channel
+    }
+    pub fn get_computer(&self) -> &str {
+        &self.computer
+    }
+    pub fn get_user_id(&self) -> Option<&str> {
+        self.user_id.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event_system::EvtSystem;
+    use chrono::Timelike;
+    use xmltree::Element;
+
+    const LOGON_SYSTEM: &str = r#"<System xmlns="http://schemas.microsoft.com/win/2004/08/events/event">
+        <Provider Name="Microsoft-Windows-Security-Auditing" Guid="{54849625-5478-4994-a5ba-3e3b0328c30d}" />
+        <EventID>4624</EventID>
+        <Version>2</Version>
+        <Level>0</Level>
+        <Task>12544</Task>
+        <Opcode>0</Opcode>
+        <Keywords>0x8020000000000000</Keywords>
+        <TimeCreated SystemTime="2023-05-01T12:34:56.1234567Z" />
+        <EventRecordID>5000000123</EventRecordID>
+        <Correlation ActivityID="{8b5d4d8f-4c2e-0001-2a4e-5d8b2e4cd901}" />
+        <Execution ProcessID="788" ThreadID="6848" />
+        <Channel>Security</Channel>
+        <Computer>WKS01.corp.local</Computer>
+        <Security />
+    </System>"#;
+
+    #[test]
+    fn test_system_parsing() {
+        let element = Element::parse(LOGON_SYSTEM.as_bytes()).unwrap();
+        let system = EvtSystem::from_element(&element).unwrap();
+
+        assert_eq!(system.get_provider_name(), "Microsoft-Windows-Security-Auditing");
+        assert_eq!(system.get_provider_guid(), Some("{54849625-5478-4994-a5ba-3e3b0328c30d}"));
+        assert_eq!(system.get_event_source_name(), None);
+        assert_eq!(system.get_event_id(), 4624);
+        assert_eq!(system.get_qualifiers(), None);
+        assert_eq!(system.get_version(), 2);
+        assert_eq!(system.get_task(), 12544);
+        assert_eq!(system.get_keywords(), 0x8020000000000000);
+        assert_eq!(system.get_record_id(), 5000000123);
+        assert_eq!(system.get_activity_id(), Some("{8b5d4d8f-4c2e-0001-2a4e-5d8b2e4cd901}"));
+        assert_eq!(system.get_related_activity_id(), None);
+        assert_eq!(system.get_process_id(), Some(788));
+        assert_eq!(system.get_thread_id(), Some(6848));
+        assert_eq!(system.get_channel(), "Security");
+        assert_eq!(system.get_computer(), "WKS01.corp.local");
+        assert_eq!(system.get_user_id(), None);
+
+        // 100ns precision has to survive parsing
+        let time_created = system.get_time_created().unwrap();
+        assert_eq!(time_created.nanosecond(), 123456700);
+    }
+
+    #[test]
+    fn test_classic_event_parsing() {
+        let xml = r#"<System>
+            <Provider Name="Service Control Manager" Guid="{555908d1-a6d7-4695-8e1e-26931d2012f4}" EventSourceName="Service Control Manager" />
+            <EventID Qualifiers="16384">7036</EventID>
+            <EventRecordID>42</EventRecordID>
+            <Channel>System</Channel>
+            <Security UserID="S-1-5-18" />
+        </System>"#;
+        let element = Element::parse(xml.as_bytes()).unwrap();
+        let system = EvtSystem::from_element(&element).unwrap();
+
+        assert_eq!(system.get_event_source_name(), Some("Service Control Manager"));
+        assert_eq!(system.get_event_id(), 7036);
+        assert_eq!(system.get_qualifiers(), Some(16384));
+        assert_eq!(system.get_user_id(), Some("S-1-5-18"));
+        assert_eq!(system.get_time_created(), None);
+        assert_eq!(system.get_process_id(), None);
+    }
+
+    #[test]
+    fn test_missing_record_id() {
+        let xml = "<System><EventID>1</EventID></System>";
+        let element = Element::parse(xml.as_bytes()).unwrap();
+        assert!(EvtSystem::from_element(&element).is_err());
+    }
+}
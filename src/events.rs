@@ -4,97 +4,187 @@ use windows::core::*;
 use windows::Win32::Foundation::*;
 use windows::Win32::System::EventLog::*;
 use std::num::ParseIntError;
+use std::collections::HashMap;
 use std::iter::once;
 use std::ffi::OsString;
 use std::os::windows::ffi::OsStrExt;
 use xmltree::Element;
 use crate::winevt::evt_open_publisher_metadata;
 use crate::metadata_cache::EvtCache;
+use crate::provider::EvtProvider;
+use crate::event_system::EvtSystem;
+use crate::event_data::extract_event_data;
+
+// Keyword bits reserved by winmeta.xml. Providers don't define these in their own
+// keyword metadata, so they have to be named here.
+pub const RESERVED_KEYWORDS: [(u64, &str); 8] = [
+    (0x0001000000000000, "Response Time"),
+    (0x0002000000000000, "WDI Context"),
+    (0x0004000000000000, "WDI Diag"),
+    (0x0008000000000000, "SQM"),
+    (0x0010000000000000, "Audit Failure"),
+    (0x0020000000000000, "Audit Success"),
+    (0x0040000000000000, "Correlation Hint"),
+    (0x0080000000000000, "Classic"),
+];
 
 #[derive(Debug, Clone)]
 pub struct EvtEvent {
-    channel: String,
-    provider: String,
+    system: EvtSystem,
+    event_data: Vec<(String, String)>,
     xml: String,
-    time_written: String,
-    record_id: u32,
     message: String,
-    keywords: Vec<String>
+    level_name: String,
+    task_name: String,
+    opcode_name: String,
+    keywords: Vec<String>,
+    // Channel name or .evtx path the event was read from
+    source: String,
+    // ATT&CK technique IDs and tactic names from the mapping stage
+    attack_techniques: Vec<String>,
+    attack_tactics: Vec<String>
 }
 impl EvtEvent {
-    pub fn new(h_event: &EVT_HANDLE) -> std::result::Result<Self, EvtError> {
+    // Renders the event and hands it to keep before the message is formatted, which
+    // is the expensive part. keep also gets the parsed XML, so queries don't parse it
+    // again. Events keep turns down come back as None.
+    pub fn new_filtered(h_event: &EVT_HANDLE, config: &EvtCache, keep: &dyn Fn(&EvtEvent, &Element) -> bool) -> std::result::Result<Option<Self>, EvtError> {
         let xml = Self::format_event_message(h_event, &EVT_HANDLE(0), EvtFormatMessageXml)?;
-        let element = Element::parse(xml.as_bytes()).unwrap();
+        let element = Element::parse(xml.as_bytes())?;
+        let mut event = Self::from_element(&element, xml)?;
+        if !keep(&event, &element) {
+            return Ok(None);
+        }
+        event.message = Self::generate_event_message(h_event, &event.system.get_provider_name().to_string());
+        event.add_provider_metadata(config);
+        Ok(Some(event))
+    }
+
+    pub fn from_xml(xml: String) -> std::result::Result<Self, EvtError> {
+        let element = Element::parse(xml.as_bytes())?;
+        Self::from_element(&element, xml)
+    }
+
+    // element is xml already parsed
+    fn from_element(element: &Element, xml: String) -> std::result::Result<Self, EvtError> {
         let empty = Element::new("0");
-        let system_element = element.get_child("System").unwrap_or(&empty);
-        let provider = system_element.get_child("Provider").unwrap_or(&empty).attributes.get("Name").unwrap_or(&String::new()).to_string();
-        let channel = system_element.get_child("Channel").unwrap_or(&empty).get_text().unwrap_or(std::borrow::Cow::Borrowed("")).to_string();
-        let level = element.get_child("Level").unwrap_or(system_element.get_child("Level").unwrap_or(&empty)).get_text().unwrap_or(std::borrow::Cow::Borrowed("")).to_string();
-        //let keyword = element.get_child("Keywords").unwrap_or(system_element.get_child("Keyword").unwrap_or(&empty)).get_text().unwrap_or(std::borrow::Cow::Borrowed("")).to_string();
-        let time_written = system_element.get_child("TimeCreated").unwrap_or(&empty).attributes.get("SystemTime").unwrap_or(&"1970-01-01T00:00:00.0000000Z".to_string()).to_string();
-        let record = system_element.get_child("EventRecordID").unwrap().get_text().unwrap_or(std::borrow::Cow::Borrowed("")).to_string();
-        let record_id = record.parse::<u32>()?;
-        let message = Self::generate_event_message(h_event, &provider);
+        let system_element = element.get_child("System").ok_or(EvtError::MissingElement("System".to_string()))?;
+        let system = EvtSystem::from_element(system_element)?;
+        let event_data = extract_event_data(element);
 
+        // Take whatever was already rendered. add_provider_metadata fills in the rest.
+        let empty_text = std::borrow::Cow::Borrowed("");
+        let rendering_element = element.get_child("RenderingInfo").unwrap_or(&empty);
+        let message = rendering_element.get_child("Message").and_then(|e| e.get_text()).unwrap_or(empty_text.clone()).to_string();
+        let level_name = rendering_element.get_child("Level").and_then(|e| e.get_text()).unwrap_or(empty_text.clone()).to_string();
+        let task_name = rendering_element.get_child("Task").and_then(|e| e.get_text()).unwrap_or(empty_text.clone()).to_string();
+        let opcode_name = rendering_element.get_child("Opcode").and_then(|e| e.get_text()).unwrap_or(empty_text.clone()).to_string();
         let mut keywords: Vec<String> = vec![];
         let empty_element: Element = Element::new("empty");
-        if let Some(rendering_element) = element.get_child("RenderingInfo") {
-            if let Some(keywords_element) = rendering_element.get_child("Keywords") {
-                for keyword_node in &keywords_element.children {
-                    let keyword_element = keyword_node.as_element().unwrap_or(&empty_element);
-                    match keyword_element.get_text() {
-                        Some(thing) => keywords.push(thing.to_string()),
-                        None => {
-                            // Get keyword number and look it up in provider metadata here
-                        }
-                    };
+        if let Some(keywords_element) = rendering_element.get_child("Keywords") {
+            for keyword_node in &keywords_element.children {
+                let keyword_element = keyword_node.as_element().unwrap_or(&empty_element);
+                if let Some(thing) = keyword_element.get_text() {
+                    keywords.push(thing.to_string());
                 }
             }
         }
         //println!("XML: {}", &xml);
         Ok(Self {
-            channel: channel,
-            provider: provider,
+            system: system,
+            event_data: event_data,
             xml: xml,
-            time_written: time_written,
-            record_id: record_id,
             message: message,
-            keywords: keywords
+            level_name: level_name,
+            task_name: task_name,
+            opcode_name: opcode_name,
+            keywords: keywords,
+            source: String::new(),
+            attack_techniques: Vec::new(),
+            attack_tactics: Vec::new()
         })
     }
-    pub fn add_provider_metadata(&self, config: &EvtCache) {
-        /*let prv_data = match config.get_provider(&self.provider) {
-            Some(thing) => thing,
-            None => {
-                println!("No provider data found for {}.", {&self.provider});
-                return Ok(())
-            }
-        };
-        let document = Element::parse(self.xml.as_bytes()).unwrap();
-
-        // Specify the new values you want to use.
-        let level_code_cow = document.get_child("System").unwrap().get_child("Level").unwrap().get_text().unwrap().to_string();
-        let level_code = u64::from_str_radix(&level_code_cow, 10).unwrap();
-        let level = match prv_data.get_levels().get(&level_code) {
-            Some(thing) => thing,
-            None => 
-        };
-        let opcode = "new opcode";
-        let task = "new task";
-        let keywords = "new keywords";
-
-        // Find and modify the elements.
-        if let Some(system) = document.get_mut_child("System") {
-            system.get_mut_child("Level").map(|e| e.text = Some(level.to_string()));
-            system.get_mut_child("Opcode").map(|e| e.text = Some(opcode.to_string()));
-            system.get_mut_child("Task").map(|e| e.text = Some(task.to_string()));
-            system.get_mut_child("Keywords").map(|e| e.text = Some(keywords.to_string()));
+
+    // Rebuilds an event from its XML and the strings that were rendered for it, for
+    // events that were written out to disk and read back.
+    pub fn from_rendered(xml: String, message: String, level_name: String, task_name: String, opcode_name: String, keywords: Vec<String>) -> std::result::Result<Self, EvtError> {
+        let mut event = Self::from_xml(xml)?;
+        event.message = message;
+        event.level_name = level_name;
+        event.task_name = task_name;
+        event.opcode_name = opcode_name;
+        event.keywords = keywords;
+        Ok(event)
+    }
+
+    pub fn add_provider_metadata(&mut self, config: &EvtCache) {
+        // Anything left empty after RenderingInfo gets resolved from the cached provider metadata.
+        // Providers without cached metadata still get the well-known values from winmeta.xml.
+        let prv_data = config.get_provider(self.system.get_provider_name());
+        let level = self.system.get_level() as u64;
+        let task = self.system.get_task() as u64;
+        let opcode = self.system.get_opcode() as u64;
+        if self.level_name.is_empty() {
+            let level_name = prv_data.and_then(|prv| Self::lookup_metadata_name(prv.get_levels(), level, "Level"));
+            self.level_name = level_name.unwrap_or_else(|| Self::standard_level_name(level));
+        }
+        if self.task_name.is_empty() {
+            let task_name = prv_data.and_then(|prv| Self::lookup_metadata_name(prv.get_tasks(), task, "Task"));
+            self.task_name = match task_name {
+                Some(name) => name,
+                None if task == 0 => "None".to_string(),
+                None => task.to_string()
+            };
+        }
+        if self.opcode_name.is_empty() {
+            // Opcode metadata is keyed by the opcode in the high word and the task it
+            // belongs to in the low word. Opcodes shared by every task have a task of 0.
+            let opcode_name = prv_data.and_then(|prv| {
+                Self::lookup_metadata_name(prv.get_opcodes(), (opcode << 16) | task, "Opcode")
+                    .or_else(|| Self::lookup_metadata_name(prv.get_opcodes(), opcode << 16, "Opcode"))
+            });
+            self.opcode_name = opcode_name.unwrap_or_else(|| Self::standard_opcode_name(opcode));
         }
+        if self.keywords.is_empty() {
+            self.keywords = Self::decode_keywords(self.system.get_keywords(), prv_data);
+        }
+    }
 
-        // Write the document back to an XML string.
-        let output = document.write_to_string(xmltree::EmitterConfig::default());
+    fn lookup_metadata_name(metadata: &HashMap<u64, HashMap<String, String>>, value: u64, kind: &str) -> Option<String> {
+        // Prefer the display message like Event Viewer does, fall back to the symbolic name
+        let value_map = metadata.get(&value)?;
+        let name = value_map.get(&format!("{} Message", kind)).or(value_map.get(&format!("{} Name", kind)))?;
+        Some(name.trim_end().to_string())
+    }
 
-*/
+    fn standard_level_name(level: u64) -> String {
+        match level {
+            // Event Viewer shows win:LogAlways as Information
+            0 => "Information".to_string(),
+            1 => "Critical".to_string(),
+            2 => "Error".to_string(),
+            3 => "Warning".to_string(),
+            4 => "Information".to_string(),
+            5 => "Verbose".to_string(),
+            other => other.to_string()
+        }
+    }
+
+    fn standard_opcode_name(opcode: u64) -> String {
+        match opcode {
+            0 => "Info".to_string(),
+            1 => "Start".to_string(),
+            2 => "Stop".to_string(),
+            3 => "DCStart".to_string(),
+            4 => "DCStop".to_string(),
+            5 => "Extension".to_string(),
+            6 => "Reply".to_string(),
+            7 => "Resume".to_string(),
+            8 => "Suspend".to_string(),
+            9 => "Send".to_string(),
+            240 => "Receive".to_string(),
+            other => other.to_string()
+        }
     }
 
     fn format_event_message(h_event: &EVT_HANDLE, h_publisher: &EVT_HANDLE, flag: EVT_FORMAT_MESSAGE_FLAGS) -> Result<String> {
@@ -173,21 +263,94 @@ impl EvtEvent {
         //println!("{}", &message);
         message
     }
-    pub fn get_timestamp(&self) -> String {
-        self.time_written.clone()
-    }
     pub fn get_xml(&self) -> String {
         self.xml.clone()
     }
-    pub fn get_record_id (&self) -> u32 {
-        self.record_id
+    pub fn get_record_id (&self) -> u64 {
+        self.system.get_record_id()
+    }
+    pub fn get_system(&self) -> &EvtSystem {
+        &self.system
+    }
+    pub fn get_event_data(&self) -> &[(String, String)] {
+        &self.event_data
+    }
+    pub fn get_keywords(&self) -> &Vec<String> {
+        &self.keywords
+    }
+    pub fn get_level_name(&self) -> &str {
+        &self.level_name
+    }
+    pub fn get_task_name(&self) -> &str {
+        &self.task_name
+    }
+    pub fn get_opcode_name(&self) -> &str {
+        &self.opcode_name
+    }
+    pub fn get_source(&self) -> &str {
+        &self.source
+    }
+    pub fn set_source(&mut self, source: String) {
+        self.source = source;
+    }
+    pub fn get_attack_techniques(&self) -> &Vec<String> {
+        &self.attack_techniques
+    }
+    pub fn get_attack_tactics(&self) -> &Vec<String> {
+        &self.attack_tactics
+    }
+    pub fn set_attack_tags(&mut self, techniques: Vec<String>, tactics: Vec<String>) {
+        self.attack_techniques = techniques;
+        self.attack_tactics = tactics;
+    }
+
+    // Rough heap footprint, used to keep buffered events inside a memory budget
+    pub fn approximate_size(&self) -> usize {
+        let event_data: usize = self.event_data.iter().map(|(name, value)| name.len() + value.len() + 48).sum();
+        let keywords: usize = self.keywords.iter().map(|keyword| keyword.len() + 24).sum();
+        let attack_tags: usize = self.attack_techniques.iter().chain(self.attack_tactics.iter()).map(|tag| tag.len() + 24).sum();
+        std::mem::size_of::<Self>() + self.xml.len() + self.message.len() + self.level_name.len()
+            + self.task_name.len() + self.opcode_name.len() + self.source.len() + event_data + keywords + attack_tags
+            + self.system.get_provider_name().len() + self.system.get_channel().len() + self.system.get_computer().len()
+            + self.system.get_system_time().len()
+    }
+
+    pub fn decode_keywords(mask: u64, provider: Option<&EvtProvider>) -> Vec<String> {
+        // Collect (bit, name) pairs first so the names come out in bit order
+        let mut matches: Vec<(u64, String)> = vec![];
+        for (bit, name) in RESERVED_KEYWORDS {
+            if mask & bit != 0 {
+                matches.push((bit, name.to_string()));
+            }
+        }
+        if let Some(prv) = provider {
+            for &key in prv.get_keywords().keys() {
+                // Keyword values can span several bits. Only count the ones fully set in the mask.
+                if key == 0 || mask & key != key {
+                    continue;
+                }
+                if let Some(name) = Self::lookup_metadata_name(prv.get_keywords(), key, "Keyword") {
+                    matches.push((key, name));
+                }
+            }
+        }
+        matches.sort();
+        let mut names: Vec<String> = vec![];
+        for (_bit, name) in matches {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
     }
 }
 
 #[derive(Debug)]
 pub enum EvtError {
     Win32Error(Error),
-    ParseIntError(ParseIntError)
+    ParseIntError(ParseIntError),
+    XmlParseError(xmltree::ParseError),
+    MissingElement(String)
     // Add more as needed...
 }
 
@@ -203,6 +366,12 @@ impl From<ParseIntError> for EvtError {
     }
 }
 
+impl From<xmltree::ParseError> for EvtError {
+    fn from(err: xmltree::ParseError) -> EvtError {
+        EvtError::XmlParseError(err)
+    }
+}
+
 impl std::error::Error for EvtError {}
 
 impl std::fmt::Display for EvtError {
@@ -210,7 +379,40 @@ impl std::fmt::Display for EvtError {
         match self {
             EvtError::Win32Error(err) => write!(f, "Win32 error: {}", err.message()),
             EvtError::ParseIntError(err) => write!(f, "ParseIntError error: {}", err.to_string()),
+            EvtError::XmlParseError(err) => write!(f, "XML parse error: {}", err),
+            EvtError::MissingElement(name) => write!(f, "Missing '{}' element", name),
             // More as needed...
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metadata_cache::EvtCache;
+    use crate::test_support::{temp_path, TestEvent};
+
+    #[test]
+    fn test_provider_opcode_names() {
+        let dir = temp_path("opcodes");
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache_path = dir.join("config.cfg");
+        // Opcode 10 of task 5, a global opcode 11, and an entry whose key is the raw
+        // number 10 (opcode 0 of task 10) that must not be picked up for opcode 10
+        let cache = r#"{"Test-Provider": {"name": "Test-Provider", "hostname": "HOST", "channels": {}, "levels": {}, "tasks": {},
+            "opcodes": {"655365": {"Opcode Name": "TaskOpcode"}, "720896": {"Opcode Name": "GlobalOpcode"}, "10": {"Opcode Name": "Wrong"}},
+            "keywords": {}, "events": []}}"#;
+        std::fs::write(&cache_path, cache).unwrap();
+        let config = EvtCache::new(cache_path.to_str().unwrap()).unwrap();
+
+        let opcode_name = |opcode: u8, task: u16| {
+            let mut event = TestEvent::default().task(task).opcode(opcode).build();
+            event.add_provider_metadata(&config);
+            event.get_opcode_name().to_string()
+        };
+        assert_eq!(opcode_name(10, 5), "TaskOpcode");
+        assert_eq!(opcode_name(11, 5), "GlobalOpcode");
+        assert_eq!(opcode_name(10, 6), "10");
+        assert_eq!(opcode_name(2, 5), "Stop");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Lines, Write};
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use crate::events::EvtEvent;
+use crate::output::EvtOutput;
+
+// What a spilled event looks like on disk. The XML is parsed again when it's read
+// back, everything that came from the provider metadata is kept as rendered.
+#[derive(Serialize, Deserialize)]
+struct SpilledEvent {
+    sequence: u64,
+    xml: String,
+    message: String,
+    level_name: String,
+    task_name: String,
+    opcode_name: String,
+    keywords: Vec<String>,
+    source: String,
+    attack_techniques: Vec<String>,
+    attack_tactics: Vec<String>,
+}
+
+impl SpilledEvent {
+    fn from_event(sequence: u64, event: &EvtEvent) -> Self {
+        Self {
+            sequence: sequence,
+            xml: event.get_xml(),
+            message: event.get_event_message(),
+            level_name: event.get_level_name().to_string(),
+            task_name: event.get_task_name().to_string(),
+            opcode_name: event.get_opcode_name().to_string(),
+            keywords: event.get_keywords().clone(),
+            source: event.get_source().to_string(),
+            attack_techniques: event.get_attack_techniques().clone(),
+            attack_tactics: event.get_attack_tactics().clone(),
+        }
+    }
+
+    fn into_event(self) -> std::result::Result<(u64, EvtEvent), Box<dyn std::error::Error>> {
+        let mut event = EvtEvent::from_rendered(self.xml, self.message, self.level_name, self.task_name, self.opcode_name, self.keywords)?;
+        event.set_source(self.source);
+        event.set_attack_tags(self.attack_techniques, self.attack_tactics);
+        Ok((self.sequence, event))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    // Time created, then record ID within the same computer and channel
+    Time,
+    // Record ID within each computer and channel
+    RecordId,
+    // Each file or channel in turn, in the order its records were written
+    Source,
+}
+
+impl SortOrder {
+    pub fn parse(value: &str) -> Option<SortOrder> {
+        match value {
+            "time" => Some(SortOrder::Time),
+            "record-id" => Some(SortOrder::RecordId),
+            "source" => Some(SortOrder::Source),
+            _ => None
+        }
+    }
+}
+
+// Whatever the order, events that still tie fall back to the order they arrived in,
+// so the sort is stable through every spill and merge. Times are compared as parsed
+// values rather than strings, so "...:01Z" and "...:01.5Z" land the right way round,
+// and events without a readable time go last instead of first.
+struct SortEntry {
+    sequence: u64,
+    order: SortOrder,
+    event: EvtEvent,
+}
+
+impl SortEntry {
+    fn compare(&self, other: &Self) -> Ordering {
+        let (system, other_system) = (self.event.get_system(), other.event.get_system());
+        let ordering = match self.order {
+            SortOrder::Time => {
+                let time = system.get_time_created();
+                let other_time = other_system.get_time_created();
+                (time.is_none(), time, system.get_computer(), system.get_channel(), system.get_record_id())
+                    .cmp(&(other_time.is_none(), other_time, other_system.get_computer(), other_system.get_channel(), other_system.get_record_id()))
+            },
+            SortOrder::RecordId => (system.get_computer(), system.get_channel(), system.get_record_id())
+                .cmp(&(other_system.get_computer(), other_system.get_channel(), other_system.get_record_id())),
+            SortOrder::Source => (self.event.get_source(), system.get_record_id())
+                .cmp(&(other.event.get_source(), other_system.get_record_id())),
+        };
+        ordering.then(self.sequence.cmp(&other.sequence))
+    }
+}
+
+impl PartialEq for SortEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.compare(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SortEntry {}
+
+impl PartialOrd for SortEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SortEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.compare(other)
+    }
+}
+
+// Sorts events without holding all of them in memory. Events are buffered
+// until the buffer passes the memory budget, then the buffer is sorted and spilled
+// to a run file. At the end the runs are k-way merged straight into the output.
+// If everything fits in the budget no file is ever written.
+pub struct ExternalSorter {
+    order: SortOrder,
+    memory_budget: usize,
+    temp_dir: PathBuf,
+    buffer: Vec<SortEntry>,
+    buffered_bytes: usize,
+    runs: Vec<PathBuf>,
+    sequence: u64,
+}
+
+impl ExternalSorter {
+    pub fn new(order: SortOrder, memory_budget: usize, temp_dir: PathBuf) -> Self {
+        Self {
+            order: order,
+            memory_budget: memory_budget,
+            temp_dir: temp_dir,
+            buffer: Vec::new(),
+            buffered_bytes: 0,
+            runs: Vec::new(),
+            sequence: 0,
+        }
+    }
+
+    pub fn push(&mut self, event: EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        self.buffered_bytes += event.approximate_size();
+        self.buffer.push(SortEntry { sequence: self.sequence, order: self.order, event: event });
+        self.sequence += 1;
+        if self.buffered_bytes >= self.memory_budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        self.buffer.sort_unstable();
+        let path = self.temp_dir.join(format!("evtrustler_run_{}_{}.jsonl", std::process::id(), self.runs.len()));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for entry in self.buffer.drain(..) {
+            serde_json::to_writer(&mut writer, &SpilledEvent::from_event(entry.sequence, &entry.event))?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        self.runs.push(path);
+        self.buffered_bytes = 0;
+        Ok(())
+    }
+
+    // Writes every event to the output in sorted order and removes the run files
+    pub fn finish(mut self, output: &mut dyn EvtOutput) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        if self.runs.is_empty() {
+            self.buffer.sort_unstable();
+            for entry in self.buffer.drain(..) {
+                output.write_event(&entry.event)?;
+            }
+            return Ok(());
+        }
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        println!("Merging {} sorted runs", self.runs.len());
+        let result = self.merge(output);
+        for path in &self.runs {
+            let _ = fs::remove_file(path);
+        }
+        result
+    }
+
+    fn merge(&self, output: &mut dyn EvtOutput) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut readers: Vec<Lines<BufReader<File>>> = Vec::new();
+        for path in &self.runs {
+            readers.push(BufReader::new(File::open(path)?).lines());
+        }
+        // Holds the next event of every run, smallest on top
+        let mut heap: BinaryHeap<Reverse<(SortEntry, usize)>> = BinaryHeap::new();
+        for run in 0..readers.len() {
+            if let Some(entry) = self.next_entry(&mut readers[run])? {
+                heap.push(Reverse((entry, run)));
+            }
+        }
+        while let Some(Reverse((entry, run))) = heap.pop() {
+            output.write_event(&entry.event)?;
+            if let Some(next) = self.next_entry(&mut readers[run])? {
+                heap.push(Reverse((next, run)));
+            }
+        }
+        Ok(())
+    }
+
+    fn next_entry(&self, reader: &mut Lines<BufReader<File>>) -> std::result::Result<Option<SortEntry>, Box<dyn std::error::Error>> {
+        match reader.next() {
+            Some(line) => {
+                let spilled: SpilledEvent = serde_json::from_str(&line?)?;
+                let (sequence, event) = spilled.into_event()?;
+                Ok(Some(SortEntry { sequence: sequence, order: self.order, event: event }))
+            },
+            None => Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::EvtEvent;
+    use crate::external_sort::{ExternalSorter, SortOrder};
+    use crate::output::EvtOutput;
+    use crate::test_support::{temp_path, TestEvent};
+
+    struct Collect {
+        record_ids: Vec<u64>,
+        messages: Vec<String>,
+    }
+
+    impl EvtOutput for Collect {
+        fn write_event(&mut self, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
+            self.record_ids.push(event.get_record_id());
+            self.messages.push(event.get_event_message());
+            Ok(())
+        }
+
+        fn finish(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    fn event(record_id: u64, second: u32) -> EvtEvent {
+        let xml = TestEvent::default().time(&format!("2023-05-01T00:00:{:02}.0000000Z", second)).record_id(record_id).xml();
+        EvtEvent::from_rendered(xml, format!("message {}", record_id), "Information".to_string(), "None".to_string(), "Info".to_string(), vec!["Classic".to_string()]).unwrap()
+    }
+
+    fn sort(memory_budget: usize, temp_dir: std::path::PathBuf) -> Collect {
+        let mut sorter = ExternalSorter::new(SortOrder::Time, memory_budget, temp_dir);
+        // Record IDs 0..40 with the seconds running backwards, two events per second
+        for record_id in 0..40u64 {
+            sorter.push(event(record_id, 59 - (record_id / 2) as u32)).unwrap();
+        }
+        let mut output = Collect { record_ids: Vec::new(), messages: Vec::new() };
+        sorter.finish(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_spilled_merge_matches_in_memory_sort() {
+        let temp_dir = temp_path("sort");
+        std::fs::create_dir_all(&temp_dir).unwrap();
+        // A tiny budget spills every few events
+        let spilled = sort(2000, temp_dir.clone());
+        assert_eq!(std::fs::read_dir(&temp_dir).unwrap().count(), 0);
+        std::fs::remove_dir(&temp_dir).unwrap();
+        let in_memory = sort(usize::MAX, std::env::temp_dir());
+
+        assert_eq!(spilled.record_ids, in_memory.record_ids);
+        assert_eq!(&spilled.record_ids[..4], &[38, 39, 36, 37]);
+        // Rendered strings survive the round trip through the run files
+        assert_eq!(spilled.messages[0], "message 38");
+    }
+
+    fn timed_event(record_id: u64, channel: &str, time: &str, source: &str) -> EvtEvent {
+        TestEvent::default().time(time).record_id(record_id).channel(channel).source(source).build()
+    }
+
+    fn sort_events(order: SortOrder, events: Vec<EvtEvent>) -> Vec<u64> {
+        let mut sorter = ExternalSorter::new(order, usize::MAX, std::env::temp_dir());
+        for event in events {
+            sorter.push(event).unwrap();
+        }
+        let mut output = Collect { record_ids: Vec::new(), messages: Vec::new() };
+        sorter.finish(&mut output).unwrap();
+        output.record_ids
+    }
+
+    #[test]
+    fn test_sort_orders() {
+        let events = || vec![
+            timed_event(5, "System", "", "System"),
+            timed_event(4, "Security", "2023-05-01T00:00:01.5Z", "b.evtx"),
+            timed_event(3, "Security", "2023-05-01T00:00:01Z", "b.evtx"),
+            timed_event(2, "Security", "2023-05-01T00:00:01.0000000Z", "a.evtx"),
+            timed_event(1, "Security", "2023-05-01T00:00:00.9999999Z", "a.evtx"),
+        ];
+        // Differing precision compares by value, the same instant breaks by record ID,
+        // and the event without a time goes last
+        assert_eq!(sort_events(SortOrder::Time, events()), vec![1, 2, 3, 4, 5]);
+        assert_eq!(sort_events(SortOrder::RecordId, events()), vec![1, 2, 3, 4, 5]);
+        assert_eq!(sort_events(SortOrder::Source, events()), vec![5, 1, 2, 3, 4]);
+    }
+}
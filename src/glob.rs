@@ -0,0 +1,30 @@
+// Matches value against a pattern where * matches any run of characters, including
+// none. Everything else is compared as is, callers lowercase both sides if case
+// shouldn't matter.
+pub fn glob_matches(pattern: &str, value: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == value,
+        Some((prefix, rest)) => {
+            let Some(remaining) = value.strip_prefix(prefix) else { return false };
+            // Try every split point for the rest of the pattern
+            (0..=remaining.len()).filter(|&i| remaining.is_char_boundary(i)).any(|i| glob_matches(rest, &remaining[i..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::glob::glob_matches;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("selection_*", "selection_img"));
+        assert!(glob_matches("selection_*", "selection_"));
+        assert!(!glob_matches("selection_*", "filter_main"));
+        assert!(glob_matches(r"*\powershell.exe", r"c:\windows\powershell.exe"));
+        assert!(glob_matches("a*b*c", "a-ü-b-c"));
+        assert!(!glob_matches("a*b*c", "a-c-b"));
+        assert!(glob_matches("exact", "exact"));
+        assert!(!glob_matches("exact", "Exact"));
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use aho_corasick::{AhoCorasick, MatchKind};
+use ipnet::IpNet;
+use serde::Serialize;
+use crate::events::EvtEvent;
+use crate::sigma::EventRef;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IocType {
+    Hash,
+    Ip,
+    Domain,
+    Path,
+    User,
+    Cmd,
+}
+
+impl IocType {
+    fn from_prefix(prefix: &str) -> Option<Self> {
+        match prefix.to_lowercase().as_str() {
+            "hash" | "md5" | "sha1" | "sha256" => Some(IocType::Hash),
+            "ip" | "cidr" => Some(IocType::Ip),
+            "domain" => Some(IocType::Domain),
+            "path" => Some(IocType::Path),
+            "user" => Some(IocType::User),
+            "cmd" => Some(IocType::Cmd),
+            _ => None
+        }
+    }
+
+    // Lines without a type prefix. Usernames and command-line pieces look like
+    // anything, so those always need one.
+    fn detect(value: &str) -> Option<Self> {
+        if [32, 40, 64].contains(&value.len()) && value.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Some(IocType::Hash);
+        }
+        if value.parse::<IpNet>().is_ok() || value.parse::<IpAddr>().is_ok() {
+            return Some(IocType::Ip);
+        }
+        if value.contains('\\') || value.starts_with('/') {
+            return Some(IocType::Path);
+        }
+        let is_domain = value.contains('.') && !value.starts_with('.') && !value.ends_with('.')
+            && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+        is_domain.then_some(IocType::Domain)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ioc {
+    pub value: String,
+    pub ioc_type: IocType,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TrieNode {
+    children: [Option<usize>; 2],
+    ioc: Option<usize>,
+}
+
+// Binary prefix trie over address bits. A lookup walks the address from the top
+// bit and keeps the most specific network it passes.
+#[derive(Debug, Clone)]
+struct CidrTrie {
+    width: u32,
+    nodes: Vec<TrieNode>,
+}
+
+impl CidrTrie {
+    fn new(width: u32) -> Self {
+        Self { width: width, nodes: vec![TrieNode { children: [None, None], ioc: None }] }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.nodes.len() == 1 && self.nodes[0].ioc.is_none()
+    }
+
+    fn bit(&self, address: u128, depth: u32) -> usize {
+        ((address >> (self.width - 1 - depth)) & 1) as usize
+    }
+
+    fn insert(&mut self, address: u128, prefix_len: u32, ioc: usize) {
+        let mut node = 0;
+        for depth in 0..prefix_len {
+            let bit = self.bit(address, depth);
+            node = match self.nodes[node].children[bit] {
+                Some(child) => child,
+                None => {
+                    self.nodes.push(TrieNode { children: [None, None], ioc: None });
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = Some(child);
+                    child
+                }
+            };
+        }
+        // The first IOC listed for a network wins
+        self.nodes[node].ioc.get_or_insert(ioc);
+    }
+
+    fn lookup(&self, address: u128) -> Option<usize> {
+        let mut node = 0;
+        let mut found = self.nodes[0].ioc;
+        for depth in 0..self.width {
+            match self.nodes[node].children[self.bit(address, depth)] {
+                Some(child) => node = child,
+                None => break
+            }
+            found = self.nodes[node].ioc.or(found);
+        }
+        found
+    }
+}
+
+// An IOC that matched, and the EventData field (or Message) it matched in
+#[derive(Debug, Clone, PartialEq)]
+pub struct IocMatch {
+    pub ioc: usize,
+    pub field: String,
+}
+
+// Every string IOC goes into one case-insensitive Aho-Corasick automaton, so a field
+// is scanned once no matter how many IOCs are loaded. Addresses are pulled out of
+// the field text and looked up in the CIDR tries.
+pub struct IocMatcher {
+    iocs: Vec<Ioc>,
+    automaton: Option<AhoCorasick>,
+    // IOC behind each automaton pattern
+    pattern_iocs: Vec<usize>,
+    ipv4: CidrTrie,
+    ipv6: CidrTrie,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+impl IocMatcher {
+    // One IOC per line as "type:value" or just a value whose type is detected. An
+    // optional description follows a tab. Blank lines and lines starting with # are skipped.
+    pub fn load(paths: &[String]) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let mut iocs = Vec::new();
+        for path in paths {
+            let mut skipped = 0;
+            for line in fs::read_to_string(path)?.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match Self::parse_line(line) {
+                    Some(ioc) => iocs.push(ioc),
+                    None => skipped += 1
+                }
+            }
+            if skipped > 0 {
+                println!("Skipped {} IOC lines in {} whose type couldn't be worked out, prefix them with hash:, ip:, domain:, path:, user: or cmd:", skipped, path);
+            }
+        }
+        Self::new(iocs)
+    }
+
+    fn parse_line(line: &str) -> Option<Ioc> {
+        let (entry, description) = line.split_once('\t').unwrap_or((line, ""));
+        let entry = entry.trim();
+        let (ioc_type, value) = match entry.split_once(':').and_then(|(prefix, value)| IocType::from_prefix(prefix).map(|ioc_type| (ioc_type, value))) {
+            Some((ioc_type, value)) => (ioc_type, value.trim()),
+            None => (IocType::detect(entry)?, entry)
+        };
+        if value.is_empty() {
+            return None;
+        }
+        Some(Ioc { value: value.to_string(), ioc_type: ioc_type, description: description.trim().to_string() })
+    }
+
+    pub fn new(iocs: Vec<Ioc>) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let mut patterns = Vec::new();
+        let mut pattern_iocs = Vec::new();
+        let mut ipv4 = CidrTrie::new(32);
+        let mut ipv6 = CidrTrie::new(128);
+        for (index, ioc) in iocs.iter().enumerate() {
+            if ioc.ioc_type == IocType::Ip {
+                let network = ioc.value.parse::<IpNet>().or_else(|_| ioc.value.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("Invalid IP address or CIDR '{}'", ioc.value))?;
+                match network {
+                    IpNet::V4(network) => ipv4.insert(u32::from(network.network()) as u128, network.prefix_len() as u32, index),
+                    IpNet::V6(network) => ipv6.insert(u128::from(network.network()), network.prefix_len() as u32, index),
+                }
+            } else {
+                patterns.push(ioc.value.clone());
+                pattern_iocs.push(index);
+            }
+        }
+        // Standard match kind so overlapping IOCs are all reported
+        let automaton = if patterns.is_empty() {
+            None
+        } else {
+            Some(AhoCorasick::builder().ascii_case_insensitive(true).match_kind(MatchKind::Standard).build(&patterns)?)
+        };
+        Ok(Self { iocs: iocs, automaton: automaton, pattern_iocs: pattern_iocs, ipv4: ipv4, ipv6: ipv6 })
+    }
+
+    pub fn ioc_count(&self) -> usize {
+        self.iocs.len()
+    }
+
+    pub fn get_ioc(&self, index: usize) -> &Ioc {
+        &self.iocs[index]
+    }
+
+    // Hashes, domains and usernames have to stand on their own in the text, so a hash
+    // inside a longer hex string or evil.com inside notevil.com doesn't count
+    fn at_boundary(ioc_type: IocType, text: &str, start: usize, end: usize) -> bool {
+        let before = text[..start].chars().next_back();
+        let after = text[end..].chars().next();
+        match ioc_type {
+            IocType::Hash => !before.map(|c| c.is_ascii_alphanumeric()).unwrap_or(false) && !after.map(|c| c.is_ascii_alphanumeric()).unwrap_or(false),
+            IocType::Domain => {
+                // A dot before is a subdomain, a dot after followed by more labels is a different domain
+                let continues = match after {
+                    Some('.') => text[end + 1..].chars().next().map(|c| c.is_ascii_alphanumeric()).unwrap_or(false),
+                    Some(c) => is_word_char(c),
+                    None => false
+                };
+                !before.map(is_word_char).unwrap_or(false) && !continues
+            },
+            IocType::User => {
+                let inside = |c: char| is_word_char(c) || c == '.' || c == '$';
+                !before.map(inside).unwrap_or(false) && !after.map(inside).unwrap_or(false)
+            },
+            _ => true
+        }
+    }
+
+    fn lookup_address(&self, address: IpAddr) -> Option<usize> {
+        match address {
+            IpAddr::V4(address) => self.ipv4.lookup(u32::from(address) as u128),
+            IpAddr::V6(address) => match address.to_ipv4_mapped() {
+                Some(mapped) => self.ipv4.lookup(u32::from(mapped) as u128),
+                None => self.ipv6.lookup(u128::from(address))
+            }
+        }
+    }
+
+    fn match_text(&self, field: &str, text: &str, found: &mut Vec<IocMatch>, seen: &mut HashSet<(usize, String)>) {
+        let mut add = |ioc: usize| {
+            if seen.insert((ioc, field.to_string())) {
+                found.push(IocMatch { ioc: ioc, field: field.to_string() });
+            }
+        };
+        if let Some(automaton) = &self.automaton {
+            for hit in automaton.find_overlapping_iter(text) {
+                let ioc = self.pattern_iocs[hit.pattern().as_usize()];
+                if Self::at_boundary(self.iocs[ioc].ioc_type, text, hit.start(), hit.end()) {
+                    add(ioc);
+                }
+            }
+        }
+        if !self.ipv4.is_empty() || !self.ipv6.is_empty() {
+            let tokens = text.split(|c: char| !(c.is_ascii_hexdigit() || c == '.' || c == ':' || c == '[' || c == ']'));
+            for token in tokens.filter(|token| token.len() >= 2) {
+                // Addresses can come with a port, like 10.0.0.1:443 or [::1]:443
+                let address = token.parse::<IpAddr>().ok().or_else(|| token.parse::<SocketAddr>().ok().map(|socket| socket.ip()));
+                if let Some(ioc) = address.and_then(|address| self.lookup_address(address)) {
+                    add(ioc);
+                }
+            }
+        }
+    }
+
+    pub fn matches(&self, event: &EvtEvent) -> Vec<IocMatch> {
+        let mut found = Vec::new();
+        let mut seen = HashSet::new();
+        for (name, value) in event.get_event_data() {
+            self.match_text(name, value, &mut found, &mut seen);
+        }
+        self.match_text("Message", &event.get_event_message(), &mut found, &mut seen);
+        found
+    }
+}
+
+// One line of the IOC report
+#[derive(Serialize)]
+struct IocHit<'a> {
+    ioc: &'a str,
+    ioc_type: IocType,
+    ioc_description: &'a str,
+    field: &'a str,
+    #[serde(flatten)]
+    event: EventRef,
+}
+
+// IOC matches as JSON lines, one per IOC and field that matched
+pub struct IocReport {
+    writer: BufWriter<File>,
+    count: usize,
+}
+
+impl IocReport {
+    pub fn new(path: &str) -> std::io::Result<Self> {
+        Ok(Self { writer: BufWriter::new(File::create(path)?), count: 0 })
+    }
+
+    pub fn write(&mut self, ioc: &Ioc, field: &str, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let hit = IocHit {
+            ioc: &ioc.value,
+            ioc_type: ioc.ioc_type,
+            ioc_description: &ioc.description,
+            field: field,
+            event: EventRef::from_event(event),
+        };
+        serde_json::to_writer(&mut self.writer, &hit)?;
+        self.writer.write_all(b"\n")?;
+        self.count += 1;
+        Ok(())
+    }
+
+    // Returns how many matches were written
+    pub fn finish(&mut self) -> std::io::Result<usize> {
+        self.writer.flush()?;
+        Ok(self.count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ioc::{IocMatcher, IocType};
+    use crate::test_support::{temp_path, TestEvent};
+
+    const IOCS: &str = "# test list
+44d88612fea8a8f36de82e1278abb02f\tEICAR
+ip:10.20.0.0/16\tinternal staging range
+203.0.113.7
+2001:db8::/32
+evil.example.com
+path:C:\\Users\\Public\\payload.exe
+user:svc_backup
+cmd:-nop -w hidden
+not sure what this is
+";
+
+    fn matcher() -> IocMatcher {
+        let path = temp_path("iocs.txt");
+        std::fs::write(&path, IOCS).unwrap();
+        let matcher = IocMatcher::load(&[path.to_string_lossy().to_string()]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        matcher
+    }
+
+    fn matched(matcher: &IocMatcher, data: &[(&str, &str)]) -> Vec<(String, String)> {
+        let event = data.iter().fold(TestEvent::default(), |event, (name, value)| event.data(name, value)).build();
+        matcher.matches(&event).iter().map(|hit| (matcher.get_ioc(hit.ioc).value.clone(), hit.field.clone())).collect()
+    }
+
+    #[test]
+    fn test_types() {
+        let matcher = matcher();
+        assert_eq!(matcher.ioc_count(), 8);
+        assert_eq!(matcher.get_ioc(0).ioc_type, IocType::Hash);
+        assert_eq!(matcher.get_ioc(0).description, "EICAR");
+        assert_eq!(matcher.get_ioc(2).ioc_type, IocType::Ip);
+        assert_eq!(matcher.get_ioc(4).ioc_type, IocType::Domain);
+    }
+
+    #[test]
+    fn test_matches() {
+        let matcher = matcher();
+        let pair = |ioc: &str, field: &str| (ioc.to_string(), field.to_string());
+        assert_eq!(matched(&matcher, &[("Hashes", "MD5=44D88612FEA8A8F36DE82E1278ABB02F,SHA256=00")]), vec![pair("44d88612fea8a8f36de82e1278abb02f", "Hashes")]);
+        // Part of a longer hex string isn't the hash
+        assert!(matched(&matcher, &[("Hashes", "SHA256=0044d88612fea8a8f36de82e1278abb02f00")]).is_empty());
+        assert_eq!(matched(&matcher, &[("DestinationIp", "10.20.3.4"), ("SourceIp", "203.0.113.7:51234")]),
+            vec![pair("10.20.0.0/16", "DestinationIp"), pair("203.0.113.7", "SourceIp")]);
+        assert_eq!(matched(&matcher, &[("IpAddress", "::ffff:10.20.9.9"), ("Other", "2001:db8:1::5")]),
+            vec![pair("10.20.0.0/16", "IpAddress"), pair("2001:db8::/32", "Other")]);
+        assert!(matched(&matcher, &[("DestinationIp", "10.21.0.1")]).is_empty());
+        assert_eq!(matched(&matcher, &[("QueryName", "cdn.EVIL.example.com")]), vec![pair("evil.example.com", "QueryName")]);
+        assert!(matched(&matcher, &[("QueryName", "notevil.example.com"), ("Other", "evil.example.com.au")]).is_empty());
+        assert_eq!(matched(&matcher, &[("Image", "c:\\users\\public\\PAYLOAD.EXE")]), vec![pair("C:\\Users\\Public\\payload.exe", "Image")]);
+        assert_eq!(matched(&matcher, &[("TargetUserName", "CORP\\svc_backup")]), vec![pair("svc_backup", "TargetUserName")]);
+        assert!(matched(&matcher, &[("TargetUserName", "svc_backup2")]).is_empty());
+        assert_eq!(matched(&matcher, &[("CommandLine", "powershell.exe -NoP -nop -w hidden -c iex")]), vec![pair("-nop -w hidden", "CommandLine")]);
+    }
+}
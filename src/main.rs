@@ -4,20 +4,56 @@ mod events;
 mod managed_variant;
 mod metadata_cache;
 mod event_meta;
+mod event_system;
+mod event_data;
+mod attack;
+mod dedup;
+mod event_filter;
+mod external_sort;
+mod glob;
+mod ioc;
+mod output;
+mod query_list;
+mod sigma;
+#[cfg(test)]
+mod test_support;
+mod time_filter;
+mod time_format;
+mod xpath;
 use events::EvtEvent;
 use provider::EvtProvider;
 use winevt::*;
 use managed_variant::*;
 use metadata_cache::*;
-
-use clap::{Arg, Command};
+use attack::AttackTagger;
+use dedup::Deduplicator;
+use event_filter::{parse_event_id, parse_keyword, parse_level, EventFilter};
+use external_sort::{ExternalSorter, SortOrder};
+use ioc::{IocMatcher, IocReport};
+use time_filter::{parse_time_arg, TimeRange};
+use time_format::{parse_time_format, parse_timezone, OutputZone, TimeFormat};
+use xpath::{parse_query_arg, XPathQuery};
+use query_list::QueryList;
+use sigma::{DetectionReport, SigmaConfig, SigmaEngine};
+use output::EvtOutput;
+use output::csv_output::{CsvOutput, CsvOptions};
+use output::ecs_output::EcsOutput;
+use output::evtx_output::EvtxOutput;
+use output::http_output::{HttpOutput, HttpOptions, HttpTarget};
+use output::jsonl_output::JsonlOutput;
+use output::ocsf_output::OcsfOutput;
+use output::parquet_output::{ParquetOutput, ParquetOptions};
+use output::sqlite_output::SqliteOutput;
+use output::syslog_output::{SyslogOutput, SyslogOptions};
+
+use chrono::{DateTime, Utc};
+use clap::{Arg, ArgAction, ArgMatches, Command};
 use std::path::Path;
 use std::ffi::OsString;
 use windows::core::*;
 use windows::Win32::Foundation::*;
 use windows::Win32::System::EventLog::*;
 use std::fs;
-use xmltree::Element;
 use std::iter::once;
 use std::io::{BufWriter, Write, SeekFrom, Seek};
 use std::collections::HashMap;
@@ -25,31 +61,109 @@ use std::collections::HashSet;
 use std::os::windows::ffi::OsStrExt;
 use std::io;
 use regex::Regex;
-use std::sync::mpsc::channel;
+use xmltree::Element;
+use std::sync::mpsc::{channel, sync_channel};
+use std::sync::Arc;
 use std::thread;
 use std::fs::File;
 
 
 fn main() {
     let mut flags: EVT_QUERY_FLAGS = EvtQueryChannelPath;
-    let channels_from_args: HashSet<String> = parse_cmdline_args(&mut flags).unwrap();
-    let meta_cache: EvtCache = enumerate_publishers(".\\config.cfg").unwrap();
-    let tasks: HashMap<String, HashSet<String>> = divvy_tasks_from_providers(meta_cache.get_data(), &channels_from_args);
+    let (channels_from_args, matches) = parse_cmdline_args(&mut flags).unwrap();
+    let meta_cache: Arc<EvtCache> = Arc::new(enumerate_publishers(".\\config.cfg").unwrap());
+    let query_list: Arc<Option<QueryList>> = Arc::new(matches.get_one::<QueryList>("query-file").cloned());
+    let tasks: HashMap<String, HashSet<String>> = match query_list.as_ref() {
+        Some(query_list) => tasks_from_query_list(meta_cache.get_data(), query_list),
+        None => divvy_tasks_from_providers(meta_cache.get_data(), &channels_from_args)
+    };
+
+    let time_range = TimeRange::new(
+        matches.get_one::<DateTime<Utc>>("since").copied(),
+        matches.get_one::<DateTime<Utc>>("until").copied(),
+    ).unwrap();
+    let query: Arc<Option<XPathQuery>> = Arc::new(matches.get_one::<XPathQuery>("query").cloned());
+    let mut event_filter = EventFilter {
+        event_ids: matches.get_many::<(u32, u32)>("event-id").map(|values| values.copied().collect()).unwrap_or_default(),
+        levels: matches.get_many::<u8>("level").map(|values| values.copied().collect()).unwrap_or_default(),
+        providers: matches.get_many::<String>("provider").map(|values| values.cloned().collect()).unwrap_or_default(),
+        channels: matches.get_many::<String>("channel").map(|values| values.cloned().collect()).unwrap_or_default(),
+        computers: matches.get_many::<String>("computer").map(|values| values.cloned().collect()).unwrap_or_default(),
+        keywords: matches.get_many::<u64>("keyword").map(|values| values.copied().collect()).unwrap_or_default(),
+    };
+    event_filter.canonicalize_names(meta_cache.get_data());
+    let filter_condition: Option<String> = event_filter.xpath_condition();
+    let filter_query: Arc<Option<XPathQuery>> = Arc::new(event_filter.to_query().unwrap());
+
+    // Open the output before any querying starts so a bad destination fails fast
+    let mut output: Box<dyn EvtOutput> = build_output(&matches, &meta_cache).unwrap();
+    let mut sigma: Option<(SigmaEngine, DetectionReport)> = match matches.get_one::<String>("sigma-rules") {
+        Some(rules_path) => {
+            let config = SigmaConfig::load(matches.get_one::<String>("sigma-config").map(|path| path.as_str())).unwrap();
+            let engine = SigmaEngine::load(rules_path, &config).unwrap();
+            println!("Loaded {} Sigma rules", engine.rule_count());
+            Some((engine, DetectionReport::new(matches.get_one::<String>("detections").unwrap()).unwrap()))
+        },
+        None => None
+    };
+    let mut dedup: Option<Deduplicator> = matches.get_flag("dedup").then(Deduplicator::default);
+    let attack_tagger = AttackTagger::new(matches.get_one::<String>("attack-mapping").map(|path| path.as_str())).unwrap();
+    let mut iocs: Option<(IocMatcher, IocReport)> = match matches.get_many::<String>("ioc") {
+        Some(paths) => {
+            let matcher = IocMatcher::load(&paths.cloned().collect::<Vec<String>>()).unwrap();
+            println!("Loaded {} IOCs", matcher.ioc_count());
+            Some((matcher, IocReport::new(matches.get_one::<String>("ioc-report").unwrap()).unwrap()))
+        },
+        None => None
+    };
 
-    let (output_sender, output_receiver) = channel();
+    // Bounded, so the query threads wait on the writer instead of piling events up in memory
+    let (output_sender, output_receiver) = sync_channel(4096);
     let (error_sender, error_receiver) = channel();
 
     let mut handles = vec![];
 
     for (channel, providers) in tasks {
+        // A query file can mix live channels and .evtx files, so this is decided per task
+        let is_file_query = flags == EvtQueryFilePath || Path::new(&channel).is_file();
+        let query_flags = if is_file_query { EvtQueryFilePath } else { flags };
+        // Live channels and providers outside the filter don't need a thread at all
+        if !is_file_query && !event_filter.allows_channel(&channel) {
+            continue;
+        }
+        // No providers means a file no channel claims, read in a single pass without a
+        // provider predicate
+        let providers: Vec<Option<String>> = if providers.is_empty() {
+            vec![None]
+        } else {
+            providers.into_iter().map(Some).collect()
+        };
         for provider in providers {
+            if provider.as_ref().map(|provider| !event_filter.allows_provider(provider)).unwrap_or(false) {
+                continue;
+            }
             let output_sender = output_sender.clone();
             let error_sender = error_sender.clone();
-            let provider = provider.clone();
             let channel = channel.clone();
+            let meta_cache = Arc::clone(&meta_cache);
+            let query = Arc::clone(&query);
+            let query_list = Arc::clone(&query_list);
+            let filter_condition = filter_condition.clone();
+            let filter_query = Arc::clone(&filter_query);
 
             let handle = thread::spawn(move || {
-                let query_str: String = format!("*[System[Provider[@Name='{}']]]", provider);
+                // Live channels get the time window and the event filter pushed down into the
+                // query. Files are checked as each record is parsed, before its message is formatted.
+                let mut conditions: Vec<String> = provider.iter().map(|provider| format!("Provider[@Name='{}']", provider)).collect();
+                if !is_file_query {
+                    conditions.extend(time_range.xpath_condition());
+                    conditions.extend(filter_condition);
+                }
+                let query_str: String = if conditions.is_empty() {
+                    "*".to_string()
+                } else {
+                    format!("*[System[{}]]", conditions.join(" and "))
+                };
                 let channel_vec: Vec<u16> = OsString::from(&channel).encode_wide().chain(once(0)).collect();
                 let p_channel: PCWSTR = PCWSTR(channel_vec.as_ptr());
                 let query_vec: Vec<u16> = OsString::from(&query_str).encode_wide().chain(once(0)).collect();
@@ -58,7 +172,7 @@ fn main() {
                         None,
                         p_channel,
                         PCWSTR(query_vec.as_ptr()),
-                        flags.0,
+                        query_flags.0,
                     )
                 };
 
@@ -87,17 +201,28 @@ fn main() {
                         let h_event  = EVT_HANDLE(next_buffer[0]);
 
                         // Get the provider name from the XML of the event
-                        let evt: EvtEvent = match EvtEvent::new(&h_event) {
-                            Ok(event) => event,
-                            Err(_e) => {
-                                println!("Problem with event. Skipping.");
-                                continue;
-                            }
+                        // --query and --query-file are evaluated here rather than by EvtQuery so
+                        // they behave the same on every platform and alongside the per-provider queries
+                        let keep = |event: &EvtEvent, root: &Element| {
+                            let now = Utc::now();
+                            time_range.contains(event.get_system().get_time_created())
+                                && (!is_file_query || filter_query.as_ref().as_ref().map(|filter| filter.matches(root, now)).unwrap_or(true))
+                                && query.as_ref().as_ref().map(|query| query.matches(root, now)).unwrap_or(true)
+                                && query_list.as_ref().as_ref().map(|query_list| query_list.matches(&channel, root, now)).unwrap_or(true)
                         };
-                        output_sender.send((evt.get_record_id(), evt)).unwrap();
+                        let evt = EvtEvent::new_filtered(&h_event, &meta_cache, &keep);
 
                         // Free resources allocated for the current event
                         unsafe { EvtClose(h_event) };
+
+                        match evt {
+                            Ok(Some(mut evt)) => {
+                                evt.set_source(channel.clone());
+                                output_sender.send((evt.get_record_id(), evt)).unwrap()
+                            },
+                            Ok(None) => (),
+                            Err(_e) => println!("Problem with event. Skipping.")
+                        }
                     }
                     unsafe { EvtClose(query_handle) };
                 } else if let Err(e) = query_handle_result {
@@ -109,32 +234,87 @@ fn main() {
         }
     }
 
+    // Only the query threads hold senders now, so the receivers end once they're all done
+    drop(output_sender);
+    drop(error_sender);
+
+    // Events are tagged with ATT&CK techniques and checked against the Sigma rules and
+    // IOCs as they arrive, before any sorting
+    let mut detect = |event: &EvtEvent| {
+        if let Some((engine, report)) = sigma.as_mut() {
+            for rule in engine.evaluate(event) {
+                report.write(rule, event).unwrap();
+            }
+        }
+        if let Some((matcher, report)) = iocs.as_mut() {
+            for hit in matcher.matches(event) {
+                report.write(matcher.get_ioc(hit.ioc), &hit.field, event).unwrap();
+            }
+        }
+    };
+
+    // Events are written as they arrive unless a sort order is asked for. Sorting goes
+    // through the external sorter, which keeps at most the memory budget of events buffered.
+    let sort_order = if matches.get_flag("sort-by-time") {
+        Some(SortOrder::Time)
+    } else {
+        SortOrder::parse(matches.get_one::<String>("sort").unwrap())
+    };
+    if let Some(sort_order) = sort_order {
+        let memory_budget = *matches.get_one::<usize>("memory-budget").unwrap() * 1024 * 1024;
+        let temp_dir = match matches.get_one::<String>("temp-dir") {
+            Some(dir) => Path::new(dir).to_path_buf(),
+            None => std::env::temp_dir()
+        };
+        let mut sorter = ExternalSorter::new(sort_order, memory_budget, temp_dir);
+        for (record_id, mut event) in output_receiver.iter() {
+            if dedup.as_mut().map(|dedup| dedup.is_duplicate(record_id, &event)).unwrap_or(false) {
+                continue;
+            }
+            attack_tagger.tag(&mut event);
+            detect(&event);
+            sorter.push(event).unwrap();
+        }
+        sorter.finish(output.as_mut()).unwrap();
+    } else {
+        for (record_id, mut event) in output_receiver.iter() {
+            if dedup.as_mut().map(|dedup| dedup.is_duplicate(record_id, &event)).unwrap_or(false) {
+                continue;
+            }
+            attack_tagger.tag(&mut event);
+            detect(&event);
+            output.write_event(&event).unwrap();
+        }
+    }
+
     // Wait for all threads to finish processing
     for handle in handles {
         handle.join().unwrap();
     }
 
-    drop(output_sender);
-    drop(error_sender);
-
     println!("done fetching");
 
-    // Dump output to disk
-    let output_path = Path::new("output.csv");
-    let error_path = Path::new("error.txt");
+    output.finish().unwrap();
+    if let Some(dedup) = dedup.as_ref() {
+        println!("Dropped {} duplicate events", dedup.duplicate_count());
+        for (source, first_source, count) in dedup.report() {
+            println!("  {} from {} (already read from {})", count, source, first_source);
+        }
+    }
+    if let Some((engine, report)) = sigma.as_mut() {
+        for (rule, alert) in engine.correlate() {
+            report.write_alert(rule, &alert).unwrap();
+        }
+        let count = report.finish().unwrap();
+        println!("{} Sigma detections written to {}", count, matches.get_one::<String>("detections").unwrap());
+    }
+    if let Some((_matcher, report)) = iocs.as_mut() {
+        let count = report.finish().unwrap();
+        println!("{} IOC matches written to {}", count, matches.get_one::<String>("ioc-report").unwrap());
+    }
 
-    let mut output_file = File::create(&output_path).unwrap();
+    let error_path = Path::new("error.txt");
     let mut error_file = File::create(&error_path).unwrap();
-
-    let mut events: Vec<EvtEvent> = output_receiver.iter().map(|(_id, event)| event.clone()).collect();
-    events.sort_unstable_by(|a, b| {
-        let time_a = a.get_timestamp();
-        let time_b = b.get_timestamp();
-        time_a.cmp(&time_b)
-    });
-    for event in events {
-        write_to_csv(&mut output_file, &event).unwrap();
-    }
     for error_msg in error_receiver {
         write_to_txt(&mut error_file, &error_msg).unwrap();
     }
@@ -152,42 +332,356 @@ fn divvy_tasks_from_providers(providers: &HashMap<String, EvtProvider>, channels
     }
 
     if !channels_from_args.is_empty() {
-        let mut used_channels = HashMap::new();
-        let file_name_pattern = Regex::new(r"Archive-([^-]+)-.*").unwrap();
-        for channel_entry in channels_from_args {
-            let mut file_key = String::new();
-            let file_path = Path::new(&channel_entry);
-            let file_name = file_path.with_extension("").file_name().unwrap().to_str().unwrap().replace("%4", "/");
-            if file_name.starts_with("Archive-") {
-                let caps = file_name_pattern.captures(&file_name).unwrap();
-                file_key = caps.get(1).unwrap().as_str().to_string();
-            } else {
-                file_key = file_name;
-            }
-            if tasks.contains_key(&file_key){
-                let value = tasks.remove(&file_key).unwrap();
-                //add used channel to used_channels
-                //used_channels.push(channel_entry.clone());
-                used_channels.insert(channel_entry.to_string(), value);
-            }
-        }
-        tasks = used_channels;
+        tasks = tasks_for_files(&tasks, channels_from_args);
     }
     // Return HashMap
     tasks
 }
 
-fn parse_cmdline_args(flags: &mut EVT_QUERY_FLAGS) -> std::result::Result<HashSet<String>, Error> {
+// Each .evtx file is queried for the providers of the channel its name points to.
+// Several files can share a channel, e.g. Security.evtx and its Archive-Security-*.evtx
+// copies. Files whose name isn't a channel any provider writes to, like
+// ForwardedEvents.evtx, can hold events from anywhere and get an empty provider set,
+// which is read in one pass without a provider predicate.
+fn tasks_for_files(channel_tasks: &HashMap<String, HashSet<String>>, files: &HashSet<String>) -> HashMap<String, HashSet<String>> {
+    let mut used_channels = HashMap::new();
+    // Windows names archives Archive-<channel>-YYYY-MM-DD-HH-MM-SS-mmm, and channel
+    // names can have hyphens of their own
+    let file_name_pattern = Regex::new(r"^Archive-(.+)-\d{4}-\d{2}-\d{2}-\d{2}-\d{2}-\d{2}-\d{3}$").unwrap();
+    for channel_entry in files {
+        let file_path = Path::new(&channel_entry);
+        let file_name = file_path.with_extension("").file_name().unwrap().to_str().unwrap().replace("%4", "/");
+        let file_key = match file_name_pattern.captures(&file_name) {
+            Some(caps) => caps.get(1).unwrap().as_str().to_string(),
+            None => file_name
+        };
+        let value = channel_tasks.get(&file_key).cloned().unwrap_or_default();
+        used_channels.insert(channel_entry.to_string(), value);
+    }
+    used_channels
+}
+
+// Tasks for the channels and .evtx files named by the Select rules of a query file
+fn tasks_from_query_list(providers: &HashMap<String, EvtProvider>, query_list: &QueryList) -> HashMap<String, HashSet<String>> {
+    let channels = query_list.channels();
+    let mut tasks: HashMap<String, HashSet<String>> = divvy_tasks_from_providers(providers, &HashSet::new())
+        .into_iter()
+        .filter(|(channel, _)| channels.contains(&channel.to_lowercase()))
+        .collect();
+    let files = query_list.files();
+    if !files.is_empty() {
+        tasks.extend(divvy_tasks_from_providers(providers, &files));
+    }
+    tasks
+}
+
+fn parse_cmdline_args(flags: &mut EVT_QUERY_FLAGS) -> std::result::Result<(HashSet<String>, ArgMatches), Error> {
     let matches = Command::new("Event Log Parser")
         .version("1.0")
         .author("Adam Boretos")
-        .about("Parses Windows event logs and outputs a CSV file")
+        .about("Parses Windows event logs and writes the events to CSV, JSON Lines, Parquet, SQLite, ECS, OCSF, EVTX, a syslog collector, Elasticsearch or Splunk")
         .arg(
             Arg::new("path")
                 .short('p')
                 .long("path")
                 .help("Path to an .evtx file or a directory containing .evtx files")
         )
+        .arg(
+            Arg::new("format")
+                .short('f')
+                .long("format")
+                .value_parser(["csv", "jsonl", "parquet", "sqlite", "ecs", "ocsf", "syslog", "elasticsearch", "splunk", "evtx"])
+                .default_value("csv")
+                .help("Output format")
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .help("File to write the parsed events to. Defaults to output.<format>, or output.ndjson for ecs and ocsf")
+        )
+        .arg(
+            Arg::new("since")
+                .long("since")
+                .value_parser(parse_time_arg)
+                .help("Only keep events created at or after this time. Takes an RFC 3339 timestamp, a date, a duration back from now like 24h or 7d, or epoch seconds/milliseconds")
+        )
+        .arg(
+            Arg::new("until")
+                .long("until")
+                .value_parser(parse_time_arg)
+                .help("Only keep events created at or before this time. Takes the same values as --since")
+        )
+        .arg(
+            Arg::new("query")
+                .long("query")
+                .value_parser(parse_query_arg)
+                .help("Event log XPath filter, e.g. \"*[System[EventID=4624] and EventData[Data[@Name='LogonType']=10]]\"")
+        )
+        .arg(
+            Arg::new("event-id")
+                .long("event-id")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .value_parser(parse_event_id)
+                .help("Only keep these event IDs, e.g. 4624,4625 or a range like 4624-4634")
+        )
+        .arg(
+            Arg::new("level")
+                .long("level")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .value_parser(parse_level)
+                .help("Only keep these levels: critical, error, warning, information, verbose or a level number")
+        )
+        .arg(
+            Arg::new("provider")
+                .long("provider")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .help("Only keep events from these providers")
+        )
+        .arg(
+            Arg::new("channel")
+                .long("channel")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .help("Only keep events from these channels, e.g. Security,System")
+        )
+        .arg(
+            Arg::new("computer")
+                .long("computer")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .help("Only keep events logged by these computers. Matched exactly as written in the event")
+        )
+        .arg(
+            Arg::new("keyword")
+                .long("keyword")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .value_parser(parse_keyword)
+                .help("Only keep events with any of these keywords: a standard name like \"Audit Failure\" or a mask like 0x10000000000000")
+        )
+        .arg(
+            Arg::new("query-file")
+                .long("query-file")
+                .conflicts_with("path")
+                .value_parser(|value: &str| QueryList::from_file(value).map_err(|e| e.to_string()))
+                .help("Event Viewer QueryList XML file. Its Select paths pick the channels and file:// .evtx files to read, and its Select/Suppress rules filter the events")
+        )
+        .arg(
+            Arg::new("sigma-rules")
+                .long("sigma-rules")
+                .help("Directory of Sigma rules (.yml/.yaml) to check every event against. Correlation rules are evaluated once all events are read, so every hit of a rule a correlation references is kept until then, a few hundred bytes each and outside --memory-budget")
+        )
+        .arg(
+            Arg::new("sigma-config")
+                .long("sigma-config")
+                .help("YAML file with extra Sigma field name and logsource mappings")
+        )
+        .arg(
+            Arg::new("detections")
+                .long("detections")
+                .default_value("detections.jsonl")
+                .help("File for the Sigma detections report")
+        )
+        .arg(
+            Arg::new("attack-mapping")
+                .long("attack-mapping")
+                .help("JSON file of extra provider/event ID/EventData conditions to tag events with ATT&CK techniques and tactics. Their tags are added to the built-in ones")
+        )
+        .arg(
+            Arg::new("ioc")
+                .long("ioc")
+                .action(ArgAction::Append)
+                .help("IOC list to match EventData and messages against, one per line as type:value or a bare hash, IP/CIDR, domain or path. Types are hash, ip, domain, path, user and cmd. Can be given more than once")
+        )
+        .arg(
+            Arg::new("ioc-report")
+                .long("ioc-report")
+                .default_value("ioc_matches.jsonl")
+                .help("File for the IOC matches report")
+        )
+        .arg(
+            Arg::new("dedup")
+                .long("dedup")
+                .action(ArgAction::SetTrue)
+                .help("Drop events already read from another file or channel, e.g. overlapping archives or ForwardedEvents. Matched on computer, channel, record ID and content. Keeps about 40 bytes per event for the whole run, outside --memory-budget")
+        )
+        .arg(
+            Arg::new("sort")
+                .long("sort")
+                .value_parser(["none", "time", "record-id", "source"])
+                .default_value("none")
+                .help("Order to write events in: as they are read, by time created, by record ID within each log, or each file/channel in turn in record order. Ties keep the order events were read in")
+        )
+        .arg(
+            Arg::new("sort-by-time")
+                .long("sort-by-time")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("sort")
+                .help("Same as --sort time")
+        )
+        .arg(
+            Arg::new("memory-budget")
+                .long("memory-budget")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("512")
+                .help("Megabytes of events to hold while sorting before spilling sorted runs to disk, and to buffer across open Parquet partitions before writing the largest out")
+        )
+        .arg(
+            Arg::new("temp-dir")
+                .long("temp-dir")
+                .help("Directory for the sorted runs. Defaults to the system temp directory")
+        )
+        .arg(
+            Arg::new("expand-event-data")
+                .long("expand-event-data")
+                .action(ArgAction::SetTrue)
+                .help("Write one CSV column per EventData/UserData field instead of a single EventData column")
+        )
+        .arg(
+            Arg::new("flatten-newlines")
+                .long("flatten-newlines")
+                .action(ArgAction::SetTrue)
+                .help("Replace line breaks inside CSV values with '|'")
+        )
+        .arg(
+            Arg::new("timezone")
+                .long("timezone")
+                .value_parser(parse_timezone)
+                .help("Write times in this zone instead of UTC: an IANA name like Europe/Berlin or an offset like +02:00. Fields that must be RFC 3339 or epoch values keep their format, Parquet keeps the instant and records the zone")
+        )
+        .arg(
+            Arg::new("time-format")
+                .long("time-format")
+                .value_parser(parse_time_format)
+                .help("strftime pattern for written times, e.g. \"%Y-%m-%d %H:%M:%S%.3f %z\". Defaults to RFC 3339")
+        )
+        .arg(
+            Arg::new("utc-column")
+                .long("utc-column")
+                .action(ArgAction::SetTrue)
+                .help("Also write the original UTC TimeCreated next to the converted one")
+        )
+        .arg(
+            Arg::new("partition-by")
+                .long("partition-by")
+                .value_parser(["none", "channel", "date", "channel-date"])
+                .default_value("none")
+                .help("Split Parquet output into a directory tree by channel and/or date")
+        )
+        .arg(
+            Arg::new("row-group-size")
+                .long("row-group-size")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("131072")
+                .help("Rows per Parquet row group")
+        )
+        .arg(
+            Arg::new("max-open-partitions")
+                .long("max-open-partitions")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("64")
+                .help("Parquet partition files to keep open at once. The least recently written is closed when another is needed, and gets a new events-N.parquet file if more of its events follow")
+        )
+        .arg(
+            Arg::new("compression")
+                .long("compression")
+                .value_parser(["zstd", "snappy", "none"])
+                .default_value("zstd")
+                .help("Parquet compression codec")
+        )
+        .arg(
+            Arg::new("ocsf-mapping")
+                .long("ocsf-mapping")
+                .help("JSON file of extra OCSF class mappings, checked before the built-in ones")
+        )
+        .arg(
+            Arg::new("syslog-target")
+                .long("syslog-target")
+                .default_value("127.0.0.1:514")
+                .help("Syslog collector to send events to, as host:port")
+        )
+        .arg(
+            Arg::new("syslog-transport")
+                .long("syslog-transport")
+                .value_parser(["udp", "tcp", "tls"])
+                .default_value("udp")
+                .help("Transport used to reach the syslog collector")
+        )
+        .arg(
+            Arg::new("syslog-format")
+                .long("syslog-format")
+                .value_parser(["rfc5424", "cef", "leef"])
+                .default_value("rfc5424")
+                .help("Message format sent to the syslog collector")
+        )
+        .arg(
+            Arg::new("syslog-facility")
+                .long("syslog-facility")
+                .value_parser(clap::value_parser!(u8).range(0..24))
+                .default_value("16")
+                .help("Syslog facility number. The severity comes from the event level")
+        )
+        .arg(
+            Arg::new("syslog-tls-insecure")
+                .long("syslog-tls-insecure")
+                .action(ArgAction::SetTrue)
+                .help("Accept self-signed or mismatched certificates from the syslog collector")
+        )
+        .arg(
+            Arg::new("http-url")
+                .long("http-url")
+                .help("Elasticsearch/OpenSearch or Splunk HEC URL. Defaults to http://localhost:9200 or https://localhost:8088")
+        )
+        .arg(
+            Arg::new("http-index")
+                .long("http-index")
+                .help("Elasticsearch index or data stream, or Splunk index. Defaults to evtrustler for Elasticsearch")
+        )
+        .arg(
+            Arg::new("http-batch-size")
+                .long("http-batch-size")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("500")
+                .help("Events per bulk request")
+        )
+        .arg(
+            Arg::new("http-retries")
+                .long("http-retries")
+                .value_parser(clap::value_parser!(u32))
+                .default_value("5")
+                .help("Times a failed batch is retried, with exponential backoff, before going to the dead-letter file")
+        )
+        .arg(
+            Arg::new("http-token")
+                .long("http-token")
+                .help("Elasticsearch API key or Splunk HEC token")
+        )
+        .arg(
+            Arg::new("http-user")
+                .long("http-user")
+                .help("User for HTTP basic authentication")
+        )
+        .arg(
+            Arg::new("http-password")
+                .long("http-password")
+                .help("Password for HTTP basic authentication")
+        )
+        .arg(
+            Arg::new("http-insecure")
+                .long("http-insecure")
+                .action(ArgAction::SetTrue)
+                .help("Accept self-signed or mismatched certificates from the HTTP endpoint")
+        )
+        .arg(
+            Arg::new("dead-letter")
+                .long("dead-letter")
+                .default_value("dead_letter.ndjson")
+                .help("File for documents the HTTP endpoint rejected")
+        )
         .get_matches();
         
     let mut channel_results: HashSet<String> = HashSet::new();
@@ -234,7 +728,7 @@ fn parse_cmdline_args(flags: &mut EVT_QUERY_FLAGS) -> std::result::Result<HashSe
             panic!("Invalid path provided. Please provide a valid .evtx file or directory path.");
         }
     }
-    Ok(channel_results)
+    Ok((channel_results, matches))
 }
 
 fn enumerate_publishers(config_path: &str) -> Result<EvtCache> {
@@ -290,68 +784,81 @@ fn enumerate_publishers(config_path: &str) -> Result<EvtCache> {
 
 }
 
-fn write_to_csv(file: &mut File, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let root = Element::parse(event.get_xml().as_bytes())?;
-    let system = root.get_child("System").ok_or("Missing 'System' Element")?;
-
-    let mut headers = Vec::new();
-    let mut rows = Vec::new();
-
-    // Explicitly save values of certain children as attributes
-    let provider_name = system.get_child("Provider").and_then(|e| e.attributes.get("Name")).unwrap_or(&"".to_string()).clone();
-    let system_time = system.get_child("TimeCreated").and_then(|e| e.attributes.get("SystemTime")).unwrap_or(&"".to_string()).clone();
-    let activity_id = system.get_child("Correlation").and_then(|e| e.attributes.get("ActivityID")).unwrap_or(&"".to_string()).clone();
-    let process_id = system.get_child("Execution").and_then(|e| e.attributes.get("ProcessID")).unwrap_or(&"".to_string()).clone();
-
-    for child in system.children.iter() {
-        let name = child.as_element().unwrap().name.to_string();
-        let value = if name == "Provider" {
-            provider_name.to_string()
-        } else if name == "TimeCreated" {
-            system_time.to_string()
-        } else if name == "Correlation" {
-            activity_id.to_string()
-        } else if name == "Execution" {
-            process_id.to_string()
-        } else { 
-            child.as_element().unwrap().get_text().unwrap_or(std::borrow::Cow::Borrowed("")).to_string()
-        };
-
-        if !headers.contains(&name) {
-            headers.push(name);
+fn build_output(matches: &ArgMatches, config: &EvtCache) -> std::result::Result<Box<dyn EvtOutput>, Box<dyn std::error::Error>> {
+    let format = matches.get_one::<String>("format").unwrap();
+    let output_path = match matches.get_one::<String>("output") {
+        Some(path) => path.to_string(),
+        None if format == "ecs" || format == "ocsf" => "output.ndjson".to_string(),
+        None => format!("output.{}", format)
+    };
+    let time_format = TimeFormat {
+        zone: matches.get_one::<OutputZone>("timezone").copied(),
+        pattern: matches.get_one::<String>("time-format").cloned(),
+        include_utc: matches.get_flag("utc-column"),
+    };
+    let output: Box<dyn EvtOutput> = match format.as_str() {
+        "jsonl" => Box::new(JsonlOutput::new(&output_path, time_format)?),
+        "parquet" => {
+            let parquet_options = ParquetOptions {
+                partition: ParquetOptions::parse_partition(matches.get_one::<String>("partition-by").unwrap()).unwrap(),
+                row_group_size: *matches.get_one::<usize>("row-group-size").unwrap(),
+                max_open_partitions: *matches.get_one::<usize>("max-open-partitions").unwrap(),
+                memory_budget: *matches.get_one::<usize>("memory-budget").unwrap() * 1024 * 1024,
+                compression: ParquetOptions::parse_compression(matches.get_one::<String>("compression").unwrap()).unwrap(),
+                time_format: time_format,
+            };
+            Box::new(ParquetOutput::new(&output_path, parquet_options)?)
+        },
+        "sqlite" => Box::new(SqliteOutput::new(&output_path, config, time_format)?),
+        "ecs" => Box::new(EcsOutput::new(&output_path, time_format)?),
+        "evtx" => Box::new(EvtxOutput::new(&output_path)?),
+        "ocsf" => {
+            let mapping_path = matches.get_one::<String>("ocsf-mapping").map(|path| path.as_str());
+            Box::new(OcsfOutput::new(&output_path, mapping_path, time_format)?)
+        },
+        "syslog" => {
+            let syslog_options = SyslogOptions {
+                target: matches.get_one::<String>("syslog-target").unwrap().to_string(),
+                transport: SyslogOptions::parse_transport(matches.get_one::<String>("syslog-transport").unwrap()).unwrap(),
+                format: SyslogOptions::parse_format(matches.get_one::<String>("syslog-format").unwrap()).unwrap(),
+                facility: *matches.get_one::<u8>("syslog-facility").unwrap(),
+                tls_insecure: matches.get_flag("syslog-tls-insecure"),
+                time_format: time_format,
+            };
+            Box::new(SyslogOutput::new(syslog_options)?)
+        },
+        "elasticsearch" | "splunk" => {
+            let (target, default_url) = match format.as_str() {
+                "splunk" => (HttpTarget::Splunk, "https://localhost:8088"),
+                _ => (HttpTarget::Elasticsearch, "http://localhost:9200")
+            };
+            let http_options = HttpOptions {
+                target: target,
+                url: matches.get_one::<String>("http-url").map(|url| url.as_str()).unwrap_or(default_url).to_string(),
+                index: matches.get_one::<String>("http-index").cloned(),
+                batch_size: *matches.get_one::<usize>("http-batch-size").unwrap(),
+                max_retries: *matches.get_one::<u32>("http-retries").unwrap(),
+                token: matches.get_one::<String>("http-token").cloned(),
+                username: matches.get_one::<String>("http-user").cloned(),
+                password: matches.get_one::<String>("http-password").cloned(),
+                insecure: matches.get_flag("http-insecure"),
+                dead_letter_path: matches.get_one::<String>("dead-letter").unwrap().to_string(),
+                time_format: time_format,
+            };
+            Box::new(HttpOutput::new(http_options)?)
+        },
+        _ => {
+            let csv_options = CsvOptions {
+                expand_event_data: matches.get_flag("expand-event-data"),
+                flatten_newlines: matches.get_flag("flatten-newlines"),
+                time_format: time_format,
+            };
+            Box::new(CsvOutput::new(&output_path, csv_options)?)
         }
-
-        rows.push(value.to_string());
-    }
-
-    if !headers.contains(&"Message".to_string()) {
-        headers.push("Message".to_string());
-    }
-    let msg_string = event.get_event_message();
-    let msg_lines = msg_string.lines();
-    for msg_line in msg_lines {
-        rows.push(msg_line.to_string());
-        break;
-    }
-    if !headers.contains(&"XML".to_string()) {
-        headers.push("XML".to_string());
-    }
-    rows.push(event.get_xml().replace("\r\n", "|").replace("\n", "|"));
-
-    let mut writer = BufWriter::new(file);
-    if writer.seek(SeekFrom::End(0)).is_err() {
-        return Err(Box::new(io::Error::new(io::ErrorKind::Other, "Failed to seek to end of file")));
-    }
-    if writer.stream_position().unwrap() == 0 {
-        writeln!(writer, "{}", headers.join(","))?;
-    }
-    writeln!(writer, "{}", rows.join(","))?;
-
-    Ok(())
+    };
+    Ok(output)
 }
 
-
-
 fn write_to_txt(file: &mut File, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
     let mut writer = BufWriter::new(file);
     if writer.seek(SeekFrom::End(0)).is_err() {
@@ -361,3 +868,34 @@ fn write_to_txt(file: &mut File, event: &EvtEvent) -> std::result::Result<(), Bo
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use crate::tasks_for_files;
+
+    #[test]
+    fn test_tasks_for_files() {
+        let mut channel_tasks: HashMap<String, HashSet<String>> = HashMap::new();
+        channel_tasks.insert("Security".to_string(), HashSet::from(["Microsoft-Windows-Security-Auditing".to_string()]));
+        channel_tasks.insert("Microsoft-Windows-Sysmon/Operational".to_string(), HashSet::from(["Microsoft-Windows-Sysmon".to_string()]));
+
+        let files = HashSet::from([
+            "C:/Logs/Security.evtx".to_string(),
+            "C:/Logs/Archive-Security-2023-05-01-10-00-00-000.evtx".to_string(),
+            "C:/Logs/Microsoft-Windows-Sysmon%4Operational.evtx".to_string(),
+            "C:/Logs/Archive-Microsoft-Windows-Sysmon%4Operational-2023-06-01-08-30-00-123.evtx".to_string(),
+            "C:/Logs/ForwardedEvents.evtx".to_string(),
+        ]);
+        let tasks = tasks_for_files(&channel_tasks, &files);
+        assert_eq!(tasks.len(), 5);
+        // Both files of the Security channel are queried for its provider
+        assert_eq!(tasks["C:/Logs/Security.evtx"], channel_tasks["Security"]);
+        assert_eq!(tasks["C:/Logs/Archive-Security-2023-05-01-10-00-00-000.evtx"], channel_tasks["Security"]);
+        assert_eq!(tasks["C:/Logs/Microsoft-Windows-Sysmon%4Operational.evtx"], channel_tasks["Microsoft-Windows-Sysmon/Operational"]);
+        // Channel names with hyphens keep them, only the timestamp is cut off
+        assert_eq!(tasks["C:/Logs/Archive-Microsoft-Windows-Sysmon%4Operational-2023-06-01-08-30-00-123.evtx"], channel_tasks["Microsoft-Windows-Sysmon/Operational"]);
+        // Files no channel claims are read once without a provider predicate
+        assert!(tasks["C:/Logs/ForwardedEvents.evtx"].is_empty());
+    }
+}
@@ -0,0 +1,300 @@
+use std::fs::{self, File};
+use std::collections::HashMap;
+use csv::{QuoteStyle, ReaderBuilder, Writer, WriterBuilder};
+use crate::events::EvtEvent;
+use crate::output::EvtOutput;
+use crate::time_format::TimeFormat;
+
+// Columns every CSV starts with, in this order. They come from the typed System
+// fields and the resolved metadata, so the header is the same for every event and
+// every channel:
+//   TimeCreated        TimeCreated/@SystemTime as written in the log (UTC), or
+//                      converted with --timezone/--time-format
+//   EventRecordID      EventRecordID
+//   Computer           Computer
+//   Channel            Channel
+//   Provider           Provider/@Name
+//   EventID            EventID
+//   Qualifiers         EventID/@Qualifiers, empty for manifest-based events
+//   Version            Version
+//   Level              Level
+//   LevelName          Level resolved through the provider metadata
+//   Task               Task
+//   TaskName           Task resolved through the provider metadata
+//   Opcode             Opcode
+//   OpcodeName         Opcode resolved through the provider metadata
+//   Keywords           Keywords mask in hex
+//   KeywordNames       Keywords mask decoded into names, separated by ';'
+//   ProcessID          Execution/@ProcessID
+//   ThreadID           Execution/@ThreadID
+//   ActivityID         Correlation/@ActivityID
+//   RelatedActivityID  Correlation/@RelatedActivityID
+//   UserID             Security/@UserID
+//   AttackTechnique    ATT&CK technique IDs from the mapping stage, separated by ';'
+//   AttackTactic       ATT&CK tactics from the mapping stage, separated by ';'
+//   Message            Rendered event message
+//   EventData          EventData/UserData as name=value pairs separated by ';'
+//   XML                The full event XML
+// With expand_event_data the EventData column is replaced by one column per
+// EventData/UserData field name, appended after XML in the order first seen. Field
+// names that are also a column name get an "EventData." prefix. When the
+// time format asks for the original UTC value it goes in a TimeCreatedUTC column
+// right after TimeCreated.
+pub const BASE_COLUMNS: [&str; 26] = [
+    "TimeCreated", "EventRecordID", "Computer", "Channel", "Provider", "EventID",
+    "Qualifiers", "Version", "Level", "LevelName", "Task", "TaskName", "Opcode",
+    "OpcodeName", "Keywords", "KeywordNames", "ProcessID", "ThreadID", "ActivityID",
+    "RelatedActivityID", "UserID", "AttackTechnique", "AttackTactic", "Message",
+    "EventData", "XML",
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct CsvOptions {
+    // One column per EventData/UserData field instead of the single EventData column
+    pub expand_event_data: bool,
+    // Replace line breaks inside values with '|' for tools that can't read quoted multiline fields
+    pub flatten_newlines: bool,
+    pub time_format: TimeFormat,
+}
+
+pub struct CsvOutput {
+    writer: Writer<File>,
+    options: CsvOptions,
+    // Only used with expand_event_data. The full set of field names isn't known until
+    // the last event, so rows go to a file next to the output until finish writes the
+    // header and copies them over. New fields only ever add columns at the end, so a
+    // row spilled early is just short and gets padded.
+    path: String,
+    rows: Option<Writer<File>>,
+    field_names: Vec<String>,
+    field_index: HashMap<String, usize>,
+}
+
+impl CsvOutput {
+    pub fn new(path: &str, options: CsvOptions) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let writer = WriterBuilder::new()
+            .quote_style(QuoteStyle::Necessary)
+            .flexible(false)
+            .from_path(path)?;
+        let rows = if options.expand_event_data {
+            Some(WriterBuilder::new().flexible(true).from_path(Self::rows_path(path))?)
+        } else {
+            None
+        };
+        let mut output = Self {
+            writer: writer,
+            options: options,
+            path: path.to_string(),
+            rows: rows,
+            field_names: Vec::new(),
+            field_index: HashMap::new(),
+        };
+        if !output.options.expand_event_data {
+            output.writer.write_record(output.columns())?;
+        }
+        Ok(output)
+    }
+
+    fn rows_path(path: &str) -> String {
+        format!("{}.rows", path)
+    }
+
+    fn columns(&self) -> Vec<&'static str> {
+        let mut columns = BASE_COLUMNS.to_vec();
+        if self.options.time_format.include_utc {
+            columns.insert(1, "TimeCreatedUTC");
+        }
+        columns
+    }
+
+    fn base_row(&self, event: &EvtEvent) -> Vec<String> {
+        let system = event.get_system();
+        let event_data: Vec<String> = event.get_event_data().iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+        let mut row = vec![
+            self.options.time_format.time_created(system),
+            system.get_record_id().to_string(),
+            system.get_computer().to_string(),
+            system.get_channel().to_string(),
+            system.get_provider_name().to_string(),
+            system.get_event_id().to_string(),
+            system.get_qualifiers().map(|q| q.to_string()).unwrap_or_default(),
+            system.get_version().to_string(),
+            system.get_level().to_string(),
+            event.get_level_name().to_string(),
+            system.get_task().to_string(),
+            event.get_task_name().to_string(),
+            system.get_opcode().to_string(),
+            event.get_opcode_name().to_string(),
+            format!("0x{:016x}", system.get_keywords()),
+            event.get_keywords().join(";"),
+            system.get_process_id().map(|pid| pid.to_string()).unwrap_or_default(),
+            system.get_thread_id().map(|tid| tid.to_string()).unwrap_or_default(),
+            system.get_activity_id().unwrap_or_default().to_string(),
+            system.get_related_activity_id().unwrap_or_default().to_string(),
+            system.get_user_id().unwrap_or_default().to_string(),
+            event.get_attack_techniques().join(";"),
+            event.get_attack_tactics().join(";"),
+            event.get_event_message(),
+            event_data.join(";"),
+            event.get_xml(),
+        ];
+        if self.options.time_format.include_utc {
+            row.insert(1, system.get_system_time().to_string());
+        }
+        row.into_iter().map(|value| self.clean_value(value)).collect()
+    }
+
+    fn clean_value(&self, value: String) -> String {
+        if self.options.flatten_newlines {
+            value.replace("\r\n", "|").replace('\n', "|").replace('\r', "|")
+        } else {
+            value
+        }
+    }
+}
+
+impl EvtOutput for CsvOutput {
+    fn write_event(&mut self, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut row = self.base_row(event);
+        if !self.options.expand_event_data {
+            self.writer.write_record(&row)?;
+            return Ok(());
+        }
+        let event_data_column = self.columns().iter().position(|&column| column == "EventData").unwrap();
+        row.remove(event_data_column);
+        let base_len = row.len();
+        for (name, value) in event.get_event_data() {
+            let index = match self.field_index.get(name) {
+                Some(&index) => index,
+                None => {
+                    self.field_index.insert(name.to_string(), self.field_names.len());
+                    self.field_names.push(name.to_string());
+                    self.field_names.len() - 1
+                }
+            };
+            if row.len() <= base_len + index {
+                row.resize(base_len + index + 1, String::new());
+            }
+            // Repeated UserData paths end up in the same column, joined like the EventData column
+            let value = self.clean_value(value.to_string());
+            let slot = &mut row[base_len + index];
+            if !slot.is_empty() {
+                slot.push(';');
+            }
+            slot.push_str(&value);
+        }
+        self.rows.as_mut().unwrap().write_record(&row)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        if let Some(mut rows) = self.rows.take() {
+            rows.flush()?;
+            drop(rows);
+            let columns = self.columns();
+            let mut headers: Vec<String> = columns.iter().filter(|&&column| column != "EventData").map(|column| column.to_string()).collect();
+            for name in &self.field_names {
+                if columns.iter().any(|column| column.eq_ignore_ascii_case(name)) {
+                    headers.push(format!("EventData.{}", name));
+                } else {
+                    headers.push(name.to_string());
+                }
+            }
+            self.writer.write_record(&headers)?;
+            let rows_path = Self::rows_path(&self.path);
+            let mut reader = ReaderBuilder::new().has_headers(false).flexible(true).from_path(&rows_path)?;
+            for record in reader.records() {
+                let mut row: Vec<String> = record?.iter().map(|value| value.to_string()).collect();
+                row.resize(headers.len(), String::new());
+                self.writer.write_record(&row)?;
+            }
+            fs::remove_file(&rows_path)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::output::EvtOutput;
+    use crate::output::csv_output::{CsvOutput, CsvOptions, BASE_COLUMNS};
+    use crate::test_support::{temp_path, TestEvent};
+    use crate::time_format::{parse_timezone, TimeFormat};
+
+    const COMMAND: &str = "a, \"b\"\nc";
+
+    fn event() -> TestEvent {
+        TestEvent::default().namespace().level(4).keywords("0x0").time("2023-05-01T12:34:56.1234567Z").record_id(7)
+            .message("Line one, with comma\nLine two")
+    }
+
+    fn write_csv(options: CsvOptions) -> Vec<csv::StringRecord> {
+        let path = temp_path(&format!("csv_{}_{}.csv", options.expand_event_data, options.time_format.include_utc));
+        let path = path.to_str().unwrap().to_string();
+        let event = event().data("Command", COMMAND).build();
+        let mut output = CsvOutput::new(&path, options).unwrap();
+        output.write_event(&event).unwrap();
+        output.write_event(&event).unwrap();
+        output.finish().unwrap();
+        let mut reader = csv::ReaderBuilder::new().has_headers(false).from_path(&path).unwrap();
+        let records = reader.records().map(|record| record.unwrap()).collect();
+        std::fs::remove_file(&path).unwrap();
+        records
+    }
+
+    #[test]
+    fn test_quoting_round_trips() {
+        let records = write_csv(CsvOptions::default());
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].iter().collect::<Vec<&str>>(), BASE_COLUMNS.to_vec());
+        let message_column = BASE_COLUMNS.iter().position(|&column| column == "Message").unwrap();
+        let data_column = BASE_COLUMNS.iter().position(|&column| column == "EventData").unwrap();
+        assert_eq!(&records[1][message_column], "Line one, with comma\nLine two");
+        assert_eq!(&records[1][data_column], "Command=a, \"b\"\nc");
+        assert_eq!(records[1].len(), BASE_COLUMNS.len());
+    }
+
+    #[test]
+    fn test_expanded_event_data() {
+        let records = write_csv(CsvOptions { expand_event_data: true, flatten_newlines: true, time_format: TimeFormat::default() });
+        let header: Vec<&str> = records[0].iter().collect();
+        assert!(!header.contains(&"EventData"));
+        assert_eq!(header.last(), Some(&"Command"));
+        assert_eq!(records[2].get(header.len() - 1), Some("a, \"b\"|c"));
+    }
+
+    #[test]
+    fn test_converted_time_with_utc_column() {
+        let time_format = TimeFormat { zone: Some(parse_timezone("Europe/Berlin").unwrap()), pattern: Some("%Y-%m-%d %H:%M:%S".to_string()), include_utc: true };
+        let records = write_csv(CsvOptions { expand_event_data: false, flatten_newlines: false, time_format: time_format });
+        assert_eq!(&records[0][0], "TimeCreated");
+        assert_eq!(&records[0][1], "TimeCreatedUTC");
+        assert_eq!(&records[1][0], "2023-05-01 14:34:56");
+        assert_eq!(&records[1][1], "2023-05-01T12:34:56.1234567Z");
+        assert_eq!(records[1].len(), BASE_COLUMNS.len() + 1);
+    }
+
+    #[test]
+    fn test_expanded_fields_added_later_and_colliding_names() {
+        let path = temp_path("csv_collisions.csv");
+        let path = path.to_str().unwrap().to_string();
+        let mut output = CsvOutput::new(&path, CsvOptions { expand_event_data: true, flatten_newlines: false, time_format: TimeFormat::default() }).unwrap();
+        output.write_event(&event().data("Command", COMMAND).build()).unwrap();
+        output.write_event(&event().data("Channel", "Setup").data("Command", COMMAND).build()).unwrap();
+        output.finish().unwrap();
+        let mut reader = csv::ReaderBuilder::new().has_headers(false).from_path(&path).unwrap();
+        let records: Vec<csv::StringRecord> = reader.records().map(|record| record.unwrap()).collect();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!std::path::Path::new(&format!("{}.rows", path)).exists());
+
+        let header: Vec<&str> = records[0].iter().collect();
+        assert_eq!(&header[header.len() - 2..], &["Command", "EventData.Channel"]);
+        assert_eq!(header.iter().filter(|&&column| column == "Channel").count(), 1);
+        // The first event was written before EventData.Channel existed
+        assert_eq!(records[1].len(), header.len());
+        assert_eq!(&records[1][header.len() - 1], "");
+        assert_eq!(&records[2][header.len() - 1], "Setup");
+        assert_eq!(&records[2][3], "Application");
+    }
+}
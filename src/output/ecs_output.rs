@@ -0,0 +1,156 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use chrono::SecondsFormat;
+use serde_json::{json, Map, Value};
+use crate::events::EvtEvent;
+use crate::output::{group_event_data, EvtOutput};
+use crate::time_format::TimeFormat;
+
+const ECS_VERSION: &str = "8.11.0";
+const AUDIT_SUCCESS: u64 = 0x0020000000000000;
+const AUDIT_FAILURE: u64 = 0x0010000000000000;
+
+// Maps events onto Elastic Common Schema the same way Winlogbeat does, so the output
+// can be bulk loaded into the same indices as live agent data. Everything Winlogbeat
+// keeps under winlog.* is kept there too, and the ECS fields are filled from it.
+// @timestamp has to stay RFC 3339, so only the time format's zone applies to it.
+pub fn to_ecs(event: &EvtEvent, time_format: &TimeFormat) -> Value {
+    let system = event.get_system();
+    let mut event_data = Map::new();
+    for (name, values) in group_event_data(event.get_event_data()) {
+        let value = if values.len() == 1 { json!(values[0]) } else { json!(values) };
+        event_data.insert(name.to_string(), value);
+    }
+
+    let mut winlog = Map::new();
+    winlog.insert("api".to_string(), json!("wineventlog"));
+    winlog.insert("channel".to_string(), json!(system.get_channel()));
+    winlog.insert("computer_name".to_string(), json!(system.get_computer()));
+    winlog.insert("event_id".to_string(), json!(system.get_event_id().to_string()));
+    winlog.insert("provider_name".to_string(), json!(system.get_provider_name()));
+    winlog.insert("record_id".to_string(), json!(system.get_record_id().to_string()));
+    winlog.insert("version".to_string(), json!(system.get_version()));
+    winlog.insert("task".to_string(), json!(event.get_task_name()));
+    winlog.insert("opcode".to_string(), json!(event.get_opcode_name()));
+    winlog.insert("keywords".to_string(), json!(event.get_keywords()));
+    if !event_data.is_empty() {
+        winlog.insert("event_data".to_string(), Value::Object(event_data));
+    }
+    if let Some(guid) = system.get_provider_guid() {
+        winlog.insert("provider_guid".to_string(), json!(guid));
+    }
+    if let Some(activity_id) = system.get_activity_id() {
+        winlog.insert("activity_id".to_string(), json!(activity_id));
+    }
+    if let Some(related_activity_id) = system.get_related_activity_id() {
+        winlog.insert("related_activity_id".to_string(), json!(related_activity_id));
+    }
+    if let Some(pid) = system.get_process_id() {
+        winlog.insert("process".to_string(), json!({"pid": pid, "thread": {"id": system.get_thread_id()}}));
+    }
+    if let Some(user_id) = system.get_user_id() {
+        winlog.insert("user".to_string(), json!({"identifier": user_id}));
+    }
+
+    let mut ecs_event = Map::new();
+    ecs_event.insert("kind".to_string(), json!("event"));
+    ecs_event.insert("code".to_string(), json!(system.get_event_id().to_string()));
+    ecs_event.insert("provider".to_string(), json!(system.get_provider_name()));
+    if !event.get_task_name().is_empty() && event.get_task_name() != "None" {
+        ecs_event.insert("action".to_string(), json!(event.get_task_name()));
+    }
+    // Winlogbeat derives the outcome from the audit keywords
+    if system.get_keywords() & AUDIT_SUCCESS != 0 {
+        ecs_event.insert("outcome".to_string(), json!("success"));
+    } else if system.get_keywords() & AUDIT_FAILURE != 0 {
+        ecs_event.insert("outcome".to_string(), json!("failure"));
+    }
+
+    let mut document = Map::new();
+    if let Some(time) = system.get_time_created() {
+        document.insert("@timestamp".to_string(), json!(time_format.rfc3339(time, SecondsFormat::Nanos)));
+    }
+    if time_format.include_utc {
+        winlog.insert("time_created".to_string(), json!(system.get_system_time()));
+    }
+    document.insert("ecs".to_string(), json!({"version": ECS_VERSION}));
+    document.insert("event".to_string(), Value::Object(ecs_event));
+    document.insert("host".to_string(), json!({"name": system.get_computer()}));
+    document.insert("log".to_string(), json!({"level": event.get_level_name().to_lowercase()}));
+    document.insert("message".to_string(), json!(event.get_event_message()));
+    if let Some(pid) = system.get_process_id() {
+        document.insert("process".to_string(), json!({"pid": pid, "thread": {"id": system.get_thread_id()}}));
+    }
+    if let Some(user_id) = system.get_user_id() {
+        document.insert("user".to_string(), json!({"id": user_id}));
+    }
+    if !event.get_attack_techniques().is_empty() {
+        document.insert("threat".to_string(), json!({
+            "framework": "MITRE ATT&CK",
+            "technique": {"id": event.get_attack_techniques()},
+            "tactic": {"name": event.get_attack_tactics()},
+        }));
+    }
+    document.insert("winlog".to_string(), Value::Object(winlog));
+    Value::Object(document)
+}
+
+pub struct EcsOutput {
+    writer: BufWriter<File>,
+    time_format: TimeFormat,
+}
+
+impl EcsOutput {
+    pub fn new(path: &str, time_format: TimeFormat) -> std::io::Result<Self> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+            time_format: time_format,
+        })
+    }
+}
+
+impl EvtOutput for EcsOutput {
+    fn write_event(&mut self, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        serde_json::to_writer(&mut self.writer, &to_ecs(event, &self.time_format))?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::EvtEvent;
+    use crate::output::ecs_output::to_ecs;
+    use crate::time_format::{parse_timezone, TimeFormat};
+
+    #[test]
+    fn test_logon_mapping() {
+        let xml = r#"<Event><System><Provider Name="Microsoft-Windows-Security-Auditing" Guid="{54849625-5478-4994-a5ba-3e3b0328c30d}" /><EventID>4624</EventID><Version>2</Version><Level>0</Level><Task>12544</Task><Opcode>0</Opcode><Keywords>0x8020000000000000</Keywords><TimeCreated SystemTime="2023-05-01T12:34:56.1234567Z" /><EventRecordID>99</EventRecordID><Execution ProcessID="788" ThreadID="6848" /><Channel>Security</Channel><Computer>WKS01</Computer><Security /></System><EventData><Data Name="TargetUserName">alice</Data></EventData><RenderingInfo><Message>An account was successfully logged on.</Message><Level>Information</Level><Task>Logon</Task></RenderingInfo></Event>"#;
+        let event = EvtEvent::from_xml(xml.to_string()).unwrap();
+        let document = to_ecs(&event, &TimeFormat::default());
+
+        assert_eq!(document["@timestamp"], "2023-05-01T12:34:56.123456700Z");
+        assert_eq!(document["event"]["code"], "4624");
+        assert_eq!(document["event"]["provider"], "Microsoft-Windows-Security-Auditing");
+        assert_eq!(document["event"]["action"], "Logon");
+        assert_eq!(document["event"]["outcome"], "success");
+        assert_eq!(document["log"]["level"], "information");
+        assert_eq!(document["host"]["name"], "WKS01");
+        assert_eq!(document["process"]["pid"], 788);
+        assert_eq!(document["message"], "An account was successfully logged on.");
+        assert_eq!(document["winlog"]["channel"], "Security");
+        assert_eq!(document["winlog"]["record_id"], "99");
+        assert_eq!(document["winlog"]["event_data"]["TargetUserName"], "alice");
+        assert!(document.get("user").is_none());
+
+        let time_format = TimeFormat { zone: Some(parse_timezone("+02:00").unwrap()), pattern: Some("%H:%M".to_string()), include_utc: true };
+        let document = to_ecs(&event, &time_format);
+        assert_eq!(document["@timestamp"], "2023-05-01T14:34:56.123456700+02:00");
+        assert_eq!(document["winlog"]["time_created"], "2023-05-01T12:34:56.1234567Z");
+    }
+}
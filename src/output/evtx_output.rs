@@ -0,0 +1,794 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use chrono::{DateTime, Utc};
+use xmltree::{Element, XMLNode};
+use crate::events::EvtEvent;
+use crate::output::EvtOutput;
+
+const FILE_HEADER_SIZE: usize = 4096;
+const CHUNK_SIZE: usize = 65536;
+const CHUNK_HEADER_SIZE: usize = 512;
+const STRING_TABLE_OFFSET: usize = 128;
+const TEMPLATE_TABLE_OFFSET: usize = 384;
+// 1601-01-01 to 1970-01-01 in 100ns ticks
+const FILETIME_UNIX_EPOCH: i64 = 116444736000000000;
+
+// BinXML tokens. Tokens that can be followed by a sibling of the same kind carry 0x40.
+const TOKEN_OPEN_START_ELEMENT: u8 = 0x01;
+const TOKEN_CLOSE_START_ELEMENT: u8 = 0x02;
+const TOKEN_CLOSE_EMPTY_ELEMENT: u8 = 0x03;
+const TOKEN_END_ELEMENT: u8 = 0x04;
+const TOKEN_VALUE: u8 = 0x05;
+const TOKEN_ATTRIBUTE: u8 = 0x06;
+const TOKEN_TEMPLATE_INSTANCE: u8 = 0x0c;
+const TOKEN_OPTIONAL_SUBSTITUTION: u8 = 0x0e;
+const TOKEN_FRAGMENT_HEADER: u8 = 0x0f;
+const TOKEN_END_OF_STREAM: u8 = 0x00;
+const TOKEN_MORE: u8 = 0x40;
+
+// BinXML value types
+const TYPE_NULL: u8 = 0x00;
+const TYPE_STRING: u8 = 0x01;
+const TYPE_UINT8: u8 = 0x04;
+const TYPE_UINT16: u8 = 0x06;
+const TYPE_UINT32: u8 = 0x08;
+const TYPE_UINT64: u8 = 0x0a;
+const TYPE_BINARY: u8 = 0x0e;
+const TYPE_GUID: u8 = 0x0f;
+const TYPE_FILETIME: u8 = 0x11;
+const TYPE_SID: u8 = 0x13;
+const TYPE_HEXINT64: u8 = 0x15;
+
+// An event's XML with every value pulled out into substitutions. Events with the same
+// shape share one template per chunk, the same way Windows writes them.
+#[derive(Debug, Hash)]
+enum TemplateNode {
+    Element { name: String, attributes: Vec<(String, TemplateValue)>, children: Vec<TemplateNode> },
+    Value(TemplateValue),
+}
+
+#[derive(Debug, Hash)]
+enum TemplateValue {
+    Text(String),
+    Substitution(u16, u8),
+}
+
+struct TemplateBuilder {
+    record_id: u64,
+    values: Vec<(u8, Vec<u8>)>,
+}
+
+impl TemplateBuilder {
+    fn substitute(&mut self, value_type: u8, data: Vec<u8>) -> TemplateValue {
+        let index = self.values.len() as u16;
+        self.values.push((value_type, data));
+        TemplateValue::Substitution(index, value_type)
+    }
+
+    fn string(&mut self, text: &str) -> TemplateValue {
+        if text.is_empty() {
+            return self.substitute(TYPE_NULL, Vec::new());
+        }
+        self.substitute(TYPE_STRING, utf16_bytes(text))
+    }
+
+    // System values get the types Windows gives them so the rendered properties come
+    // back as numbers, GUIDs and SIDs. Anything that doesn't parse is kept as a string.
+    fn system_value(&mut self, element: &str, attribute: Option<&str>, text: &str) -> TemplateValue {
+        let typed = match (element, attribute) {
+            ("Provider", Some("Guid")) | ("Correlation", Some(_)) => parse_guid(text).map(|data| (TYPE_GUID, data)),
+            ("EventID", None) | ("EventID", Some("Qualifiers")) | ("Task", None) => text.parse::<u16>().ok().map(|value| (TYPE_UINT16, value.to_le_bytes().to_vec())),
+            ("Version", None) | ("Level", None) | ("Opcode", None) => text.parse::<u8>().ok().map(|value| (TYPE_UINT8, vec![value])),
+            ("Keywords", None) => u64::from_str_radix(text.trim_start_matches("0x"), 16).ok().map(|value| (TYPE_HEXINT64, value.to_le_bytes().to_vec())),
+            ("TimeCreated", Some("SystemTime")) => DateTime::parse_from_rfc3339(text).ok().map(|time| (TYPE_FILETIME, filetime(&time.with_timezone(&Utc)).to_le_bytes().to_vec())),
+            // Records are renumbered as they're written, so the XML has to agree with the record header
+            ("EventRecordID", None) => Some((TYPE_UINT64, self.record_id.to_le_bytes().to_vec())),
+            ("Execution", Some(_)) => text.parse::<u32>().ok().map(|value| (TYPE_UINT32, value.to_le_bytes().to_vec())),
+            ("Security", Some("UserID")) => parse_sid(text).map(|data| (TYPE_SID, data)),
+            _ => None
+        };
+        match typed {
+            Some((value_type, data)) => self.substitute(value_type, data),
+            None => self.string(text)
+        }
+    }
+
+    fn build_node(&mut self, element: &Element, parent: &str, parent_namespace: Option<&str>) -> TemplateNode {
+        let mut attributes: Vec<(String, TemplateValue)> = Vec::new();
+        // xmltree keeps the default namespace apart from the attributes
+        if element.namespace.is_some() && element.namespace.as_deref() != parent_namespace {
+            attributes.push(("xmlns".to_string(), TemplateValue::Text(element.namespace.clone().unwrap())));
+        }
+        let mut names: Vec<&String> = element.attributes.keys().collect();
+        names.sort();
+        for name in names {
+            let value = &element.attributes[name];
+            let template_value = match (parent, element.name.as_str(), name.as_str()) {
+                ("System", element_name, attribute) => self.system_value(element_name, Some(attribute), value),
+                // Data names are part of the event's shape, like in the provider's own template
+                ("EventData", "Data", "Name") => TemplateValue::Text(value.to_string()),
+                _ => self.string(value)
+            };
+            attributes.push((name.to_string(), template_value));
+        }
+
+        let mut children: Vec<TemplateNode> = Vec::new();
+        let mut has_elements = false;
+        for node in &element.children {
+            if let XMLNode::Element(child) = node {
+                // Rendered strings aren't part of a stored event
+                if child.name == "RenderingInfo" {
+                    continue;
+                }
+                has_elements = true;
+                children.push(self.build_node(child, &element.name, element.namespace.as_deref()));
+            }
+        }
+        if !has_elements {
+            let text = element.get_text().map(|text| text.trim().to_string()).unwrap_or_default();
+            match (parent, element.name.as_str()) {
+                ("System", _) if text.is_empty() => (),
+                ("System", name) => children.push(TemplateNode::Value(self.system_value(name, None, &text))),
+                ("EventData", "Binary") => {
+                    let value = match decode_hex(&text) {
+                        Some(data) if !data.is_empty() => self.substitute(TYPE_BINARY, data),
+                        _ => self.string(&text)
+                    };
+                    children.push(TemplateNode::Value(value));
+                },
+                _ => children.push(TemplateNode::Value(self.string(&text)))
+            }
+        }
+        TemplateNode::Element { name: element.name.clone(), attributes: attributes, children: children }
+    }
+}
+
+fn utf16_bytes(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect()
+}
+
+fn filetime(time: &DateTime<Utc>) -> u64 {
+    (time.timestamp() * 10_000_000 + (time.timestamp_subsec_nanos() / 100) as i64 + FILETIME_UNIX_EPOCH) as u64
+}
+
+// {00112233-4455-6677-8899-aabbccddeeff} in the mixed-endian layout of a Windows GUID
+fn parse_guid(text: &str) -> Option<Vec<u8>> {
+    let hex: String = text.trim_matches(|c| c == '{' || c == '}').split('-').collect();
+    let bytes = decode_hex(&hex)?;
+    if bytes.len() != 16 {
+        return None;
+    }
+    let mut guid = Vec::with_capacity(16);
+    guid.extend(bytes[0..4].iter().rev());
+    guid.extend(bytes[4..6].iter().rev());
+    guid.extend(bytes[6..8].iter().rev());
+    guid.extend(&bytes[8..16]);
+    Some(guid)
+}
+
+// S-1-5-21-... as the binary SID structure
+fn parse_sid(text: &str) -> Option<Vec<u8>> {
+    let mut parts = text.strip_prefix("S-")?.split('-');
+    let revision = parts.next()?.parse::<u8>().ok()?;
+    let authority = parts.next()?.parse::<u64>().ok()?;
+    let sub_authorities: Vec<u32> = parts.map(|part| part.parse::<u32>()).collect::<Result<_, _>>().ok()?;
+    let mut sid = vec![revision, sub_authorities.len() as u8];
+    sid.extend(&authority.to_be_bytes()[2..]);
+    for sub_authority in sub_authorities {
+        sid.extend(sub_authority.to_le_bytes());
+    }
+    Some(sid)
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// Name hash used by the chunk string table
+fn name_hash(name: &str) -> u16 {
+    let mut hash: u32 = 0;
+    for unit in name.encode_utf16() {
+        hash = hash.wrapping_mul(65599).wrapping_add(unit as u32);
+    }
+    hash as u16
+}
+
+fn template_guid(template: &TemplateNode) -> [u8; 16] {
+    let mut guid = [0u8; 16];
+    for (half, seed) in [0u64, 1].iter().enumerate() {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        template.hash(&mut hasher);
+        guid[half * 8..half * 8 + 8].copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    guid
+}
+
+fn patch_u32(buffer: &mut [u8], position: usize, value: u32) {
+    buffer[position..position + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// One 64KB chunk. Names and template definitions are written inline the first time
+// they're used in the chunk and referenced by offset after that.
+struct Chunk {
+    data: Vec<u8>,
+    free_offset: usize,
+    last_record_offset: usize,
+    first_record_id: u64,
+    last_record_id: u64,
+    names: HashMap<String, u32>,
+    templates: HashMap<[u8; 16], u32>,
+    string_table: [u32; 64],
+    template_table: [u32; 32],
+}
+
+// A record encoded against the chunk it's going into, along with the names and
+// template it adds to that chunk. Nothing is kept unless the record fits.
+struct EncodedRecord {
+    data: Vec<u8>,
+    new_names: Vec<(String, u32)>,
+    new_template: Option<([u8; 16], u32)>,
+}
+
+struct RecordEncoder<'a> {
+    chunk: &'a Chunk,
+    base: usize,
+    out: Vec<u8>,
+    new_names: Vec<(String, u32)>,
+}
+
+impl<'a> RecordEncoder<'a> {
+    fn offset(&self) -> u32 {
+        (self.base + self.out.len()) as u32
+    }
+
+    fn push_u16(&mut self, value: u16) {
+        self.out.extend(value.to_le_bytes());
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.out.extend(value.to_le_bytes());
+    }
+
+    fn write_name(&mut self, name: &str) {
+        let existing = self.chunk.names.get(name).copied()
+            .or(self.new_names.iter().find(|(new_name, _)| new_name == name).map(|(_, offset)| *offset));
+        if let Some(offset) = existing {
+            self.push_u32(offset);
+            return;
+        }
+        // The name follows right after its own offset
+        let offset = self.offset() + 4;
+        self.push_u32(offset);
+        self.new_names.push((name.to_string(), offset));
+        self.push_u32(0);
+        self.push_u16(name_hash(name));
+        self.push_u16(name.encode_utf16().count() as u16);
+        self.out.extend(utf16_bytes(name));
+        self.push_u16(0);
+    }
+
+    fn write_value(&mut self, value: &TemplateValue) {
+        match value {
+            TemplateValue::Text(text) => {
+                self.out.push(TOKEN_VALUE);
+                self.out.push(TYPE_STRING);
+                self.push_u16(text.encode_utf16().count() as u16);
+                self.out.extend(utf16_bytes(text));
+            },
+            TemplateValue::Substitution(index, value_type) => {
+                self.out.push(TOKEN_OPTIONAL_SUBSTITUTION);
+                self.push_u16(*index);
+                self.out.push(*value_type);
+            }
+        }
+    }
+
+    fn write_node(&mut self, node: &TemplateNode) {
+        let (name, attributes, children) = match node {
+            TemplateNode::Element { name, attributes, children } => (name, attributes, children),
+            TemplateNode::Value(value) => return self.write_value(value)
+        };
+        self.out.push(if attributes.is_empty() { TOKEN_OPEN_START_ELEMENT } else { TOKEN_OPEN_START_ELEMENT | TOKEN_MORE });
+        // Dependency identifier, none
+        self.push_u16(0xffff);
+        let size_position = self.out.len();
+        self.push_u32(0);
+        self.write_name(name);
+        if !attributes.is_empty() {
+            let list_position = self.out.len();
+            self.push_u32(0);
+            for (position, (attribute_name, value)) in attributes.iter().enumerate() {
+                let more = if position + 1 < attributes.len() { TOKEN_MORE } else { 0 };
+                self.out.push(TOKEN_ATTRIBUTE | more);
+                self.write_name(attribute_name);
+                self.write_value(value);
+            }
+            let list_size = self.out.len() - list_position - 4;
+            patch_u32(&mut self.out, list_position, list_size as u32);
+        }
+        if children.is_empty() {
+            self.out.push(TOKEN_CLOSE_EMPTY_ELEMENT);
+        } else {
+            self.out.push(TOKEN_CLOSE_START_ELEMENT);
+            for child in children {
+                self.write_node(child);
+            }
+            self.out.push(TOKEN_END_ELEMENT);
+        }
+        let element_size = self.out.len() - size_position - 4;
+        patch_u32(&mut self.out, size_position, element_size as u32);
+    }
+
+    fn write_fragment_header(&mut self) {
+        self.out.extend([TOKEN_FRAGMENT_HEADER, 1, 1, 0]);
+    }
+}
+
+impl Chunk {
+    fn new() -> Self {
+        Self {
+            data: vec![0; CHUNK_SIZE],
+            free_offset: CHUNK_HEADER_SIZE,
+            last_record_offset: 0,
+            first_record_id: 0,
+            last_record_id: 0,
+            names: HashMap::new(),
+            templates: HashMap::new(),
+            string_table: [0; 64],
+            template_table: [0; 32],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.free_offset == CHUNK_HEADER_SIZE
+    }
+
+    fn encode(&self, record_id: u64, written_time: u64, template: &TemplateNode, values: &[(u8, Vec<u8>)]) -> EncodedRecord {
+        let mut encoder = RecordEncoder { chunk: self, base: self.free_offset, out: Vec::new(), new_names: Vec::new() };
+        encoder.out.extend([0x2a, 0x2a, 0x00, 0x00]);
+        encoder.push_u32(0);
+        encoder.out.extend(record_id.to_le_bytes());
+        encoder.out.extend(written_time.to_le_bytes());
+
+        encoder.write_fragment_header();
+        let guid = template_guid(template);
+        let template_id = u32::from_le_bytes([guid[0], guid[1], guid[2], guid[3]]);
+        encoder.out.push(TOKEN_TEMPLATE_INSTANCE);
+        encoder.out.push(1);
+        encoder.push_u32(template_id);
+        let mut new_template = None;
+        match self.templates.get(&guid) {
+            Some(&offset) => encoder.push_u32(offset),
+            None => {
+                // First use in this chunk, so the definition follows inline
+                let offset = encoder.offset() + 4;
+                encoder.push_u32(offset);
+                new_template = Some((guid, offset));
+                encoder.push_u32(0);
+                encoder.out.extend(guid);
+                let size_position = encoder.out.len();
+                encoder.push_u32(0);
+                encoder.write_fragment_header();
+                encoder.write_node(template);
+                encoder.out.push(TOKEN_END_OF_STREAM);
+                let template_size = encoder.out.len() - size_position - 4;
+                patch_u32(&mut encoder.out, size_position, template_size as u32);
+            }
+        }
+
+        encoder.push_u32(values.len() as u32);
+        for (value_type, data) in values {
+            encoder.push_u16(data.len() as u16);
+            encoder.out.push(*value_type);
+            encoder.out.push(0);
+        }
+        for (_, data) in values {
+            encoder.out.extend(data);
+        }
+
+        let record_size = (encoder.out.len() + 4) as u32;
+        encoder.push_u32(record_size);
+        patch_u32(&mut encoder.out, 4, record_size);
+        EncodedRecord { data: encoder.out, new_names: encoder.new_names, new_template: new_template }
+    }
+
+    fn fits(&self, record: &EncodedRecord) -> bool {
+        self.free_offset + record.data.len() <= CHUNK_SIZE
+    }
+
+    fn add(&mut self, record_id: u64, record: EncodedRecord) {
+        let offset = self.free_offset;
+        self.data[offset..offset + record.data.len()].copy_from_slice(&record.data);
+        // Chain the new names and template into their hash buckets
+        for (name, name_offset) in record.new_names {
+            let bucket = (name_hash(&name) % 64) as usize;
+            patch_u32(&mut self.data, name_offset as usize, self.string_table[bucket]);
+            self.string_table[bucket] = name_offset;
+            self.names.insert(name, name_offset);
+        }
+        if let Some((guid, template_offset)) = record.new_template {
+            let bucket = (u32::from_le_bytes([guid[0], guid[1], guid[2], guid[3]]) % 32) as usize;
+            patch_u32(&mut self.data, template_offset as usize, self.template_table[bucket]);
+            self.template_table[bucket] = template_offset;
+            self.templates.insert(guid, template_offset);
+        }
+        if self.is_empty() {
+            self.first_record_id = record_id;
+        }
+        self.last_record_id = record_id;
+        self.last_record_offset = offset;
+        self.free_offset += record.data.len();
+    }
+
+    fn finalize(&mut self) -> &[u8] {
+        self.data[0..8].copy_from_slice(b"ElfChnk\0");
+        // Record numbers and identifiers are the same thing in every file Windows writes
+        self.data[8..16].copy_from_slice(&self.first_record_id.to_le_bytes());
+        self.data[16..24].copy_from_slice(&self.last_record_id.to_le_bytes());
+        self.data[24..32].copy_from_slice(&self.first_record_id.to_le_bytes());
+        self.data[32..40].copy_from_slice(&self.last_record_id.to_le_bytes());
+        patch_u32(&mut self.data, 40, 128);
+        patch_u32(&mut self.data, 44, self.last_record_offset as u32);
+        patch_u32(&mut self.data, 48, self.free_offset as u32);
+        let records_checksum = crc32fast::hash(&self.data[CHUNK_HEADER_SIZE..self.free_offset]);
+        patch_u32(&mut self.data, 52, records_checksum);
+        for (bucket, offset) in self.string_table.iter().enumerate() {
+            patch_u32(&mut self.data, STRING_TABLE_OFFSET + bucket * 4, *offset);
+        }
+        for (bucket, offset) in self.template_table.iter().enumerate() {
+            patch_u32(&mut self.data, TEMPLATE_TABLE_OFFSET + bucket * 4, *offset);
+        }
+        // The header checksum skips the checksum field and the flags next to it
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.data[0..120]);
+        hasher.update(&self.data[128..CHUNK_HEADER_SIZE]);
+        patch_u32(&mut self.data, 124, hasher.finalize());
+        &self.data
+    }
+}
+
+// Writes the events back out as an .evtx file that Event Viewer and the Windows event
+// log API can open. Records are renumbered from 1 in the order they're written, so a
+// filtered subset or several merged logs still form one gapless log.
+pub struct EvtxOutput {
+    writer: BufWriter<File>,
+    chunk: Chunk,
+    chunk_count: u64,
+    next_record_id: u64,
+    // Records too large for an empty chunk
+    skipped: u64,
+}
+
+impl EvtxOutput {
+    pub fn new(path: &str) -> std::io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        // The header is written last, once the chunk count is known
+        writer.write_all(&[0u8; FILE_HEADER_SIZE])?;
+        Ok(Self {
+            writer: writer,
+            chunk: Chunk::new(),
+            chunk_count: 0,
+            next_record_id: 1,
+            skipped: 0,
+        })
+    }
+
+    fn flush_chunk(&mut self) -> std::io::Result<()> {
+        let mut chunk = std::mem::replace(&mut self.chunk, Chunk::new());
+        self.writer.write_all(chunk.finalize())?;
+        self.chunk_count += 1;
+        Ok(())
+    }
+
+    fn file_header(&self) -> Vec<u8> {
+        let mut header = vec![0u8; FILE_HEADER_SIZE];
+        header[0..8].copy_from_slice(b"ElfFile\0");
+        header[8..16].copy_from_slice(&0u64.to_le_bytes());
+        header[16..24].copy_from_slice(&self.chunk_count.saturating_sub(1).to_le_bytes());
+        header[24..32].copy_from_slice(&self.next_record_id.to_le_bytes());
+        patch_u32(&mut header, 32, 128);
+        // Version 3.1
+        header[36..38].copy_from_slice(&1u16.to_le_bytes());
+        header[38..40].copy_from_slice(&3u16.to_le_bytes());
+        header[40..42].copy_from_slice(&(FILE_HEADER_SIZE as u16).to_le_bytes());
+        header[42..44].copy_from_slice(&(self.chunk_count as u16).to_le_bytes());
+        // Flags stay 0, the file is closed cleanly
+        let checksum = crc32fast::hash(&header[0..120]);
+        patch_u32(&mut header, 124, checksum);
+        header
+    }
+}
+
+impl EvtOutput for EvtxOutput {
+    fn write_event(&mut self, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        if self.chunk_count >= u16::MAX as u64 {
+            return Err("EVTX files can't hold more than 65535 chunks".into());
+        }
+        let root = Element::parse(event.get_xml().as_bytes())?;
+        let record_id = self.next_record_id;
+        let mut builder = TemplateBuilder { record_id: record_id, values: Vec::new() };
+        let template = builder.build_node(&root, "", None);
+        let written_time = filetime(&event.get_system().get_time_created().unwrap_or_else(Utc::now));
+
+        let mut record = self.chunk.encode(record_id, written_time, &template, &builder.values);
+        if !self.chunk.fits(&record) && !self.chunk.is_empty() {
+            self.flush_chunk()?;
+            record = self.chunk.encode(record_id, written_time, &template, &builder.values);
+        }
+        // One huge script block shouldn't end the export, so the record is left out
+        if !self.chunk.fits(&record) {
+            println!("Skipping event {} from {}: too large for an EVTX chunk", event.get_record_id(), event.get_source());
+            self.skipped += 1;
+            return Ok(());
+        }
+        self.chunk.add(record_id, record);
+        self.next_record_id += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        if !self.chunk.is_empty() {
+            self.flush_chunk()?;
+        }
+        let header = self.file_header();
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&header)?;
+        self.writer.flush()?;
+        if self.skipped > 0 {
+            println!("EVTX: skipped {} events too large for a chunk", self.skipped);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::EvtEvent;
+    use crate::output::EvtOutput;
+    use crate::output::evtx_output::EvtxOutput;
+    use crate::test_support::{temp_path, TestEvent};
+
+    fn read_u16(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_u64(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    // Just enough of a BinXML reader to turn a record back into XML
+    struct Decoder<'a> {
+        chunk: &'a [u8],
+        position: usize,
+    }
+
+    impl<'a> Decoder<'a> {
+        fn name(&mut self) -> String {
+            let offset = read_u32(self.chunk, self.position) as usize;
+            self.position += 4;
+            let count = read_u16(self.chunk, offset + 6) as usize;
+            let units: Vec<u16> = (0..count).map(|i| read_u16(self.chunk, offset + 8 + i * 2)).collect();
+            if offset == self.position {
+                self.position += 8 + count * 2 + 2;
+            }
+            String::from_utf16(&units).unwrap()
+        }
+
+        fn value(&mut self, values: &[String]) -> String {
+            let token = self.chunk[self.position];
+            self.position += 1;
+            if token & 0x1f == 0x05 {
+                let count = read_u16(self.chunk, self.position + 1) as usize;
+                let units: Vec<u16> = (0..count).map(|i| read_u16(self.chunk, self.position + 3 + i * 2)).collect();
+                self.position += 3 + count * 2;
+                return String::from_utf16(&units).unwrap();
+            }
+            let index = read_u16(self.chunk, self.position) as usize;
+            self.position += 3;
+            values[index].clone()
+        }
+
+        fn element(&mut self, values: &[String]) -> String {
+            let token = self.chunk[self.position];
+            let size = read_u32(self.chunk, self.position + 3) as usize;
+            self.position += 7;
+            let end = self.position + size;
+            let name = self.name();
+            let mut xml = format!("<{}", name);
+            if token & 0x40 != 0 {
+                self.position += 4;
+                loop {
+                    let more = self.chunk[self.position] & 0x40 != 0;
+                    self.position += 1;
+                    let attribute = self.name();
+                    xml.push_str(&format!(" {}=\"{}\"", attribute, self.value(values)));
+                    if !more {
+                        break;
+                    }
+                }
+            }
+            let close = self.chunk[self.position];
+            self.position += 1;
+            if close == 0x03 {
+                xml.push_str(" />");
+            } else {
+                xml.push('>');
+                while self.chunk[self.position] != 0x04 {
+                    if self.chunk[self.position] & 0x1f == 0x01 {
+                        xml.push_str(&self.element(values));
+                    } else {
+                        xml.push_str(&self.value(values));
+                    }
+                }
+                self.position += 1;
+                xml.push_str(&format!("</{}>", name));
+            }
+            assert_eq!(self.position, end);
+            xml
+        }
+
+        // Substitution values rendered as text, numbers in decimal and everything else in hex
+        fn record(&mut self) -> String {
+            assert_eq!(&self.chunk[self.position..self.position + 5], &[0x0f, 1, 1, 0, 0x0c]);
+            let definition = read_u32(self.chunk, self.position + 10) as usize;
+            self.position += 14;
+            let mut template_end = self.position;
+            if definition == self.position {
+                template_end = definition + 24 + read_u32(self.chunk, definition + 20) as usize;
+            }
+            let count = read_u32(self.chunk, template_end) as usize;
+            let mut data_position = template_end + 4 + count * 4;
+            let mut values = Vec::new();
+            for i in 0..count {
+                let size = read_u16(self.chunk, template_end + 4 + i * 4) as usize;
+                let value_type = self.chunk[template_end + 6 + i * 4];
+                let data = &self.chunk[data_position..data_position + size];
+                values.push(match value_type {
+                    0x01 => String::from_utf16(&data.chunks(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect::<Vec<u16>>()).unwrap(),
+                    0x04 => data[0].to_string(),
+                    0x06 => read_u16(data, 0).to_string(),
+                    0x08 => read_u32(data, 0).to_string(),
+                    0x0a => read_u64(data, 0).to_string(),
+                    _ => data.iter().map(|byte| format!("{:02x}", byte)).collect()
+                });
+                data_position += size;
+            }
+            let end_of_values = data_position;
+            self.position = definition + 28;
+            let xml = self.element(&values);
+            assert_eq!(self.chunk[self.position], 0x00);
+            self.position = end_of_values;
+            xml
+        }
+    }
+
+    fn event(record_id: u64, payload: &str) -> EvtEvent {
+        TestEvent::default().namespace().provider("Microsoft-Windows-Security-Auditing").guid("{54849625-5478-4994-a5ba-3e3b0328c30d}")
+            .event_id(4624).version(2).level(0).task(12544).opcode(0).keywords("0x8020000000000000").time("2023-05-01T12:34:56.1234567Z")
+            .record_id(record_id).correlation().execution(788, 6848).channel("Security").computer("WKS01").user_id("S-1-5-18")
+            .data("TargetUserName", payload).data("IpAddress", "").build()
+    }
+
+    fn write_file(name: &str, events: &[EvtEvent]) -> Vec<u8> {
+        let path = temp_path(&format!("{}.evtx", name));
+        let path = path.to_str().unwrap().to_string();
+        let mut output = EvtxOutput::new(&path).unwrap();
+        for event in events {
+            output.write_event(event).unwrap();
+        }
+        output.finish().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_records_round_trip() {
+        let events: Vec<EvtEvent> = (0..3).map(|i| event(9000 + i, &format!("user{}", i))).collect();
+        let data = write_file("round_trip", &events);
+        assert_eq!(&data[0..8], b"ElfFile\0");
+        assert_eq!(read_u32(&data, 124), crc32fast::hash(&data[0..120]));
+        assert_eq!(read_u16(&data, 42), 1);
+        assert_eq!(read_u64(&data, 24), 4);
+
+        let chunk = &data[4096..4096 + 65536];
+        let free_offset = read_u32(chunk, 48) as usize;
+        assert_eq!(read_u32(chunk, 52), crc32fast::hash(&chunk[512..free_offset]));
+        let mut records = Vec::new();
+        let mut offset = 512;
+        while offset < free_offset {
+            assert_eq!(read_u32(chunk, offset), 0x2a2a);
+            let size = read_u32(chunk, offset + 4) as usize;
+            assert_eq!(read_u32(chunk, offset + size - 4) as usize, size);
+            let mut decoder = Decoder { chunk: chunk, position: offset + 24 };
+            records.push((read_u64(chunk, offset + 8), decoder.record()));
+            assert_eq!(decoder.position, offset + size - 4);
+            offset += size;
+        }
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].0, 1);
+        assert_eq!(records[2].0, 3);
+        let (_, xml) = &records[1];
+        assert!(xml.starts_with(r#"<Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event"><System><Provider Guid="25968454785494"#));
+        assert!(xml.contains("<EventID>4624</EventID><Version>2</Version>"));
+        assert!(xml.contains("<EventRecordID>2</EventRecordID>"));
+        assert!(xml.contains("<Correlation />"));
+        assert!(xml.contains(r#"<Execution ProcessID="788" ThreadID="6848" />"#));
+        assert!(xml.contains(r#"<Data Name="TargetUserName">user1</Data><Data Name="IpAddress"></Data>"#));
+        // The template is only defined by the first record, so the others are much smaller
+        let first_size = read_u32(chunk, 516);
+        let second_size = read_u32(chunk, 512 + first_size as usize + 4);
+        assert!(second_size < first_size / 2);
+    }
+
+    #[test]
+    fn test_records_span_chunks() {
+        let payload = "x".repeat(5000);
+        let events: Vec<EvtEvent> = (0..20).map(|i| event(i, &payload)).collect();
+        let data = write_file("span_chunks", &events);
+        let chunk_count = read_u16(&data, 42) as usize;
+        assert_eq!(chunk_count, 4);
+        assert_eq!(data.len(), 4096 + chunk_count * 65536);
+        let mut next_record = 1;
+        for index in 0..chunk_count {
+            let chunk = &data[4096 + index * 65536..4096 + (index + 1) * 65536];
+            assert_eq!(&chunk[0..8], b"ElfChnk\0");
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&chunk[0..120]);
+            hasher.update(&chunk[128..512]);
+            assert_eq!(read_u32(chunk, 124), hasher.finalize());
+            assert_eq!(read_u64(chunk, 8), next_record);
+            next_record = read_u64(chunk, 16) + 1;
+            // Every chunk defines its own template in its first record
+            let mut decoder = Decoder { chunk: chunk, position: 512 + 24 };
+            assert!(decoder.record().contains(&payload));
+        }
+        assert_eq!(next_record, 21);
+    }
+
+    #[test]
+    fn test_oversized_record_is_skipped() {
+        let events = vec![event(1, "small"), event(2, &"x".repeat(70000)), event(3, "small")];
+        let data = write_file("oversized", &events);
+        // The big record is left out and the numbering stays gapless
+        assert_eq!(read_u64(&data, 24), 3);
+    }
+
+    // Checks the file with the evtx crate, a parser written against files from Windows
+    #[test]
+    fn test_independent_parser_reads_file() {
+        let payload = "x".repeat(5000);
+        let mut events: Vec<EvtEvent> = (0..20).map(|i| event(i, &payload)).collect();
+        events.push(event(20, "user20"));
+        let path = temp_path("independent.evtx");
+        let mut output = EvtxOutput::new(path.to_str().unwrap()).unwrap();
+        for event in &events {
+            output.write_event(event).unwrap();
+        }
+        output.finish().unwrap();
+
+        let mut parser = evtx::EvtxParser::from_path(&path).unwrap();
+        let records: Vec<evtx::SerializedEvtxRecord<String>> = parser.records().map(|record| record.unwrap()).collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 21);
+        for (index, record) in records.iter().enumerate() {
+            assert_eq!(record.event_record_id, index as u64 + 1);
+        }
+        let last = &records[20].data;
+        assert!(last.contains("<EventID>4624</EventID>"));
+        assert!(last.contains("<EventRecordID>21</EventRecordID>"));
+        assert!(last.contains(r#"<Data Name="TargetUserName">user20</Data>"#));
+        assert!(records[0].data.contains(&payload));
+    }
+}
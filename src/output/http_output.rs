@@ -0,0 +1,426 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::thread;
+use std::time::Duration;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde_json::{json, Value};
+use tokio::runtime::Runtime;
+use crate::events::EvtEvent;
+use crate::output::EvtOutput;
+use crate::output::ecs_output::to_ecs;
+use crate::output::jsonl_output::JsonRecord;
+use crate::time_format::TimeFormat;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpTarget {
+    Elasticsearch,
+    Splunk,
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    pub target: HttpTarget,
+    // Base URL of the cluster or HEC endpoint. The _bulk or /services/collector/event
+    // path is added when it's missing.
+    pub url: String,
+    // Elasticsearch index or data stream. Splunk uses the token's default index when unset.
+    pub index: Option<String>,
+    pub batch_size: usize,
+    // Attempts after the first one before a batch goes to the dead-letter file
+    pub max_retries: u32,
+    // Elasticsearch API key or Splunk HEC token
+    pub token: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub insecure: bool,
+    pub dead_letter_path: String,
+    pub time_format: TimeFormat,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            target: HttpTarget::Elasticsearch,
+            url: "http://localhost:9200".to_string(),
+            index: None,
+            batch_size: 500,
+            max_retries: 5,
+            token: None,
+            username: None,
+            password: None,
+            insecure: false,
+            dead_letter_path: "dead_letter.ndjson".to_string(),
+            time_format: TimeFormat::default(),
+        }
+    }
+}
+
+// What the server did with one batch
+struct BatchResult {
+    accepted: u64,
+    // Sent again after a backoff
+    retry: Vec<Value>,
+    // Refused for good, with the reason from the server
+    rejected: Vec<(Value, String)>,
+}
+
+// 429 and 5xx mean the server is busy or restarting, so the same request can be sent again
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// Batches events and posts them to an Elasticsearch/OpenSearch _bulk endpoint or a
+// Splunk HTTP Event Collector. Events go out as ECS documents to Elasticsearch and
+// as the JSON Lines record to Splunk. Documents the server rejects, or that are
+// still failing after every retry, are written to the dead-letter file with the
+// reason so they can be fixed up and sent again.
+pub struct HttpOutput {
+    options: HttpOptions,
+    url: String,
+    runtime: Runtime,
+    client: Client,
+    batch: Vec<Value>,
+    dead_letter: Option<BufWriter<File>>,
+    sent: u64,
+    rejected: u64,
+}
+
+impl HttpOutput {
+    pub fn new(options: HttpOptions) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let base_url = options.url.trim_end_matches('/');
+        let url = match options.target {
+            HttpTarget::Elasticsearch if !base_url.ends_with("/_bulk") => format!("{}/_bulk", base_url),
+            HttpTarget::Splunk if !base_url.contains("/services/collector") => format!("{}/services/collector/event", base_url),
+            _ => base_url.to_string()
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let client = Client::builder()
+            .danger_accept_invalid_certs(options.insecure)
+            .timeout(Duration::from_secs(60))
+            .build()?;
+        Ok(Self {
+            options: options,
+            url: url,
+            runtime: runtime,
+            client: client,
+            batch: Vec::new(),
+            dead_letter: None,
+            sent: 0,
+            rejected: 0,
+        })
+    }
+
+    fn to_document(&self, event: &EvtEvent) -> Value {
+        match self.options.target {
+            HttpTarget::Elasticsearch => to_ecs(event, &self.options.time_format),
+            HttpTarget::Splunk => {
+                let system = event.get_system();
+                let mut envelope = json!({
+                    "host": system.get_computer(),
+                    "source": system.get_channel(),
+                    "sourcetype": "_json",
+                    "event": JsonRecord::from_event(event, &self.options.time_format),
+                });
+                if let Some(time) = system.get_time_created() {
+                    envelope["time"] = json!(time.timestamp_millis() as f64 / 1000.0);
+                }
+                if let Some(index) = &self.options.index {
+                    envelope["index"] = json!(index);
+                }
+                envelope
+            }
+        }
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match (&self.options.token, &self.options.username) {
+            (Some(token), _) if self.options.target == HttpTarget::Splunk => request.header("Authorization", format!("Splunk {}", token)),
+            (Some(token), _) => request.header("Authorization", format!("ApiKey {}", token)),
+            (None, Some(username)) => request.basic_auth(username, self.options.password.as_ref()),
+            (None, None) => request
+        }
+    }
+
+    async fn post_batch(&self, documents: Vec<Value>) -> std::result::Result<BatchResult, Box<dyn std::error::Error>> {
+        let mut body = String::new();
+        let index = self.options.index.as_deref().unwrap_or("evtrustler");
+        for document in &documents {
+            // create works for both plain indices and data streams
+            if self.options.target == HttpTarget::Elasticsearch {
+                body.push_str(&json!({"create": {"_index": index}}).to_string());
+                body.push('\n');
+            }
+            body.push_str(&document.to_string());
+            body.push('\n');
+        }
+        let content_type = match self.options.target {
+            HttpTarget::Elasticsearch => "application/x-ndjson",
+            HttpTarget::Splunk => "application/json"
+        };
+        let request = self.client.post(&self.url).header("Content-Type", content_type).body(body);
+        let response = match self.authorize(request).send().await {
+            Ok(response) => response,
+            Err(e) => {
+                println!("Failed to post batch to {}: {}", self.url, e);
+                return Ok(BatchResult { accepted: 0, retry: documents, rejected: Vec::new() });
+            }
+        };
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            return Err(format!("{} rejected the credentials ({}): {}", self.url, status, text).into());
+        }
+        if is_retryable(status) {
+            println!("{} answered {}, backing off", self.url, status);
+            return Ok(BatchResult { accepted: 0, retry: documents, rejected: Vec::new() });
+        }
+        if !status.is_success() {
+            let reason = format!("{}: {}", status, text);
+            return Ok(BatchResult { accepted: 0, retry: Vec::new(), rejected: documents.into_iter().map(|document| (document, reason.clone())).collect() });
+        }
+        match self.options.target {
+            // HEC accepts or refuses the whole request
+            HttpTarget::Splunk => Ok(BatchResult { accepted: documents.len() as u64, retry: Vec::new(), rejected: Vec::new() }),
+            // _bulk answers 200 even when some documents failed, so check every item
+            HttpTarget::Elasticsearch => {
+                // A body that didn't arrive whole says nothing about the documents, so
+                // the batch is sent again
+                let reply: Value = match serde_json::from_str(&text) {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        println!("{} answered {} with an unreadable body ({}), retrying", self.url, status, e);
+                        return Ok(BatchResult { accepted: 0, retry: documents, rejected: Vec::new() });
+                    }
+                };
+                if reply["errors"] != json!(true) {
+                    return Ok(BatchResult { accepted: documents.len() as u64, retry: Vec::new(), rejected: Vec::new() });
+                }
+                let items = reply["items"].as_array().cloned().unwrap_or_default();
+                let mut result = BatchResult { accepted: 0, retry: Vec::new(), rejected: Vec::new() };
+                for (position, document) in documents.into_iter().enumerate() {
+                    let item = items.get(position).map(|item| item["create"].clone()).unwrap_or(Value::Null);
+                    let item_status = item["status"].as_u64().and_then(|code| StatusCode::from_u16(code as u16).ok());
+                    match item_status {
+                        Some(code) if code.is_success() => result.accepted += 1,
+                        Some(code) if is_retryable(code) => result.retry.push(document),
+                        _ => result.rejected.push((document, item["error"].to_string()))
+                    }
+                }
+                Ok(result)
+            }
+        }
+    }
+
+    fn write_dead_letter(&mut self, document: Value, reason: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        if self.dead_letter.is_none() {
+            self.dead_letter = Some(BufWriter::new(File::create(&self.options.dead_letter_path)?));
+        }
+        let writer = self.dead_letter.as_mut().unwrap();
+        serde_json::to_writer(&mut *writer, &json!({"reason": reason, "document": document}))?;
+        writer.write_all(b"\n")?;
+        self.rejected += 1;
+        Ok(())
+    }
+
+    fn flush_batch(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut pending = std::mem::take(&mut self.batch);
+        let mut attempt = 0;
+        while !pending.is_empty() {
+            if attempt > self.options.max_retries {
+                for document in std::mem::take(&mut pending) {
+                    self.write_dead_letter(document, "Retries exhausted")?;
+                }
+                break;
+            }
+            if attempt > 0 {
+                // Doubles up to about half a minute between attempts
+                thread::sleep(Duration::from_millis(250 * 2u64.pow((attempt - 1).min(7))));
+            }
+            let result = self.runtime.block_on(self.post_batch(pending))?;
+            self.sent += result.accepted;
+            for (document, reason) in result.rejected {
+                self.write_dead_letter(document, &reason)?;
+            }
+            pending = result.retry;
+            attempt += 1;
+        }
+        Ok(())
+    }
+}
+
+impl EvtOutput for HttpOutput {
+    fn write_event(&mut self, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let document = self.to_document(event);
+        self.batch.push(document);
+        if self.batch.len() >= self.options.batch_size {
+            self.flush_batch()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        self.flush_batch()?;
+        if let Some(writer) = self.dead_letter.as_mut() {
+            writer.flush()?;
+        }
+        println!("Sent {} events to {}", self.sent, self.url);
+        if self.rejected > 0 {
+            println!("{} events were rejected and written to {}", self.rejected, self.options.dead_letter_path);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use crate::events::EvtEvent;
+    use crate::output::EvtOutput;
+    use crate::output::http_output::{HttpOptions, HttpOutput, HttpTarget};
+    use crate::test_support::{temp_path, TestEvent};
+
+    // (headers, body) of every request the mock server received
+    type Requests = Arc<Mutex<Vec<(String, String)>>>;
+
+    // Answers each request with the next scripted (status, body) and closes the connection
+    fn mock_server(responses: Vec<(u16, String)>) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests: Requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut headers = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                    headers.push_str(&line.to_lowercase());
+                }
+                let length = headers.lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map(|value| value.trim().parse::<usize>().unwrap())
+                    .unwrap_or(0);
+                let mut request_body = vec![0u8; length];
+                reader.read_exact(&mut request_body).unwrap();
+                received.lock().unwrap().push((headers, String::from_utf8(request_body).unwrap()));
+                let response = format!("HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn event(record_id: u64) -> EvtEvent {
+        TestEvent::default().level(4).time("2023-05-01T00:00:00.2500000Z").record_id(record_id).build()
+    }
+
+    fn dead_letter_path(name: &str) -> String {
+        temp_path(&format!("{}.ndjson", name)).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_bulk_retry_and_dead_letter() {
+        let bulk_reply = r#"{"errors":true,"items":[{"create":{"status":201}},{"create":{"status":400,"error":{"type":"mapper_parsing_exception"}}},{"create":{"status":429}}]}"#;
+        let (url, requests) = mock_server(vec![
+            (503, "{}".to_string()),
+            (200, bulk_reply.to_string()),
+            (200, r#"{"errors":false,"items":[{"create":{"status":201}}]}"#.to_string()),
+        ]);
+        let dead_letter = dead_letter_path("bulk_dead_letter");
+        let options = HttpOptions {
+            url: url,
+            index: Some("logs-windows".to_string()),
+            batch_size: 3,
+            token: Some("c2VjcmV0".to_string()),
+            dead_letter_path: dead_letter.clone(),
+            ..Default::default()
+        };
+        let mut output = HttpOutput::new(options).unwrap();
+        for record_id in 1..=3 {
+            output.write_event(&event(record_id)).unwrap();
+        }
+        output.finish().unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        let (headers, body) = &requests[0];
+        assert!(headers.starts_with("post /_bulk http/1.1"));
+        assert!(headers.contains("authorization: apikey c2vjcmv0"));
+        assert_eq!(body.lines().count(), 6);
+        assert_eq!(body.lines().next().unwrap(), r#"{"create":{"_index":"logs-windows"}}"#);
+        // Only the throttled document is sent again
+        assert_eq!(requests[2].1.lines().count(), 2);
+        assert!(requests[2].1.contains(r#""record_id":"3""#));
+
+        let rejected = std::fs::read_to_string(&dead_letter).unwrap();
+        std::fs::remove_file(&dead_letter).unwrap();
+        assert_eq!(rejected.lines().count(), 1);
+        let line: serde_json::Value = serde_json::from_str(rejected.lines().next().unwrap()).unwrap();
+        assert_eq!(line["document"]["winlog"]["record_id"], "2");
+        assert!(line["reason"].as_str().unwrap().contains("mapper_parsing_exception"));
+    }
+
+    #[test]
+    fn test_unreadable_bulk_reply_is_retried() {
+        let (url, requests) = mock_server(vec![
+            (200, "{\"errors\":fal".to_string()),
+            (200, r#"{"errors":false,"items":[{"create":{"status":201}}]}"#.to_string()),
+        ]);
+        let dead_letter = dead_letter_path("bulk_unreadable");
+        let options = HttpOptions {
+            url: url,
+            index: Some("logs-windows".to_string()),
+            dead_letter_path: dead_letter.clone(),
+            ..Default::default()
+        };
+        let mut output = HttpOutput::new(options).unwrap();
+        output.write_event(&event(1)).unwrap();
+        output.finish().unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        assert!(!std::path::Path::new(&dead_letter).exists());
+    }
+
+    #[test]
+    fn test_splunk_hec() {
+        let (url, requests) = mock_server(vec![
+            (200, r#"{"text":"Success","code":0}"#.to_string()),
+            (400, r#"{"text":"Invalid data format","code":6}"#.to_string()),
+        ]);
+        let dead_letter = dead_letter_path("hec_dead_letter");
+        let options = HttpOptions {
+            target: HttpTarget::Splunk,
+            url: format!("{}/", url),
+            batch_size: 2,
+            token: Some("token-1".to_string()),
+            dead_letter_path: dead_letter.clone(),
+            ..Default::default()
+        };
+        let mut output = HttpOutput::new(options).unwrap();
+        for record_id in 1..=3 {
+            output.write_event(&event(record_id)).unwrap();
+        }
+        output.finish().unwrap();
+
+        let requests = requests.lock().unwrap();
+        let (headers, body) = &requests[0];
+        assert!(headers.starts_with("post /services/collector/event http/1.1"));
+        assert!(headers.contains("authorization: splunk token-1"));
+        let first: serde_json::Value = serde_json::from_str(body.lines().next().unwrap()).unwrap();
+        assert_eq!(first["time"], 1682899200.25);
+        assert_eq!(first["host"], "HOST");
+        assert_eq!(first["event"]["EventRecordID"], 1);
+
+        let rejected = std::fs::read_to_string(&dead_letter).unwrap();
+        std::fs::remove_file(&dead_letter).unwrap();
+        assert_eq!(rejected.lines().count(), 1);
+        assert!(rejected.contains("Invalid data format"));
+    }
+}
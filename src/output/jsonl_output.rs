@@ -0,0 +1,180 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use serde::Serialize;
+use serde::ser::{SerializeMap, Serializer};
+use crate::events::EvtEvent;
+use crate::output::{group_event_data, EvtOutput};
+use crate::time_format::TimeFormat;
+
+// One line per event. Field names match the CSV columns so both formats can be
+// queried the same way, TimeCreatedUTC included only when it was asked for.
+#[derive(Serialize)]
+pub struct JsonRecord<'a> {
+    #[serde(rename = "TimeCreated")]
+    time_created: String,
+    #[serde(rename = "TimeCreatedUTC", skip_serializing_if = "Option::is_none")]
+    time_created_utc: Option<&'a str>,
+    #[serde(rename = "EventRecordID")]
+    record_id: u64,
+    #[serde(rename = "Computer")]
+    computer: &'a str,
+    #[serde(rename = "Channel")]
+    channel: &'a str,
+    #[serde(rename = "Provider")]
+    provider: &'a str,
+    #[serde(rename = "ProviderGuid")]
+    provider_guid: Option<&'a str>,
+    #[serde(rename = "EventSourceName")]
+    event_source_name: Option<&'a str>,
+    #[serde(rename = "EventID")]
+    event_id: u32,
+    #[serde(rename = "Qualifiers")]
+    qualifiers: Option<u16>,
+    #[serde(rename = "Version")]
+    version: u8,
+    #[serde(rename = "Level")]
+    level: u8,
+    #[serde(rename = "LevelName")]
+    level_name: &'a str,
+    #[serde(rename = "Task")]
+    task: u16,
+    #[serde(rename = "TaskName")]
+    task_name: &'a str,
+    #[serde(rename = "Opcode")]
+    opcode: u8,
+    #[serde(rename = "OpcodeName")]
+    opcode_name: &'a str,
+    #[serde(rename = "Keywords")]
+    keywords: String,
+    #[serde(rename = "KeywordNames")]
+    keyword_names: &'a [String],
+    #[serde(rename = "ProcessID")]
+    process_id: Option<u32>,
+    #[serde(rename = "ThreadID")]
+    thread_id: Option<u32>,
+    #[serde(rename = "ActivityID")]
+    activity_id: Option<&'a str>,
+    #[serde(rename = "RelatedActivityID")]
+    related_activity_id: Option<&'a str>,
+    #[serde(rename = "UserID")]
+    user_id: Option<&'a str>,
+    #[serde(rename = "AttackTechnique")]
+    attack_techniques: &'a [String],
+    #[serde(rename = "AttackTactic")]
+    attack_tactics: &'a [String],
+    #[serde(rename = "Message")]
+    message: String,
+    #[serde(rename = "EventData", serialize_with = "serialize_event_data")]
+    event_data: &'a [(String, String)],
+}
+
+impl<'a> JsonRecord<'a> {
+    pub fn from_event(event: &'a EvtEvent, time_format: &TimeFormat) -> Self {
+        let system = event.get_system();
+        Self {
+            time_created: time_format.time_created(system),
+            time_created_utc: time_format.include_utc.then(|| system.get_system_time()),
+            record_id: system.get_record_id(),
+            computer: system.get_computer(),
+            channel: system.get_channel(),
+            provider: system.get_provider_name(),
+            provider_guid: system.get_provider_guid(),
+            event_source_name: system.get_event_source_name(),
+            event_id: system.get_event_id(),
+            qualifiers: system.get_qualifiers(),
+            version: system.get_version(),
+            level: system.get_level(),
+            level_name: event.get_level_name(),
+            task: system.get_task(),
+            task_name: event.get_task_name(),
+            opcode: system.get_opcode(),
+            opcode_name: event.get_opcode_name(),
+            keywords: format!("0x{:016x}", system.get_keywords()),
+            keyword_names: event.get_keywords(),
+            process_id: system.get_process_id(),
+            thread_id: system.get_thread_id(),
+            activity_id: system.get_activity_id(),
+            related_activity_id: system.get_related_activity_id(),
+            user_id: system.get_user_id(),
+            attack_techniques: event.get_attack_techniques(),
+            attack_tactics: event.get_attack_tactics(),
+            message: event.get_event_message(),
+            event_data: event.get_event_data(),
+        }
+    }
+}
+
+// EventData is written as an object in payload order. Repeated names become an array of values.
+pub fn serialize_event_data<S: Serializer>(event_data: &&[(String, String)], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    let grouped = group_event_data(event_data);
+    let mut map = serializer.serialize_map(Some(grouped.len()))?;
+    for (name, values) in grouped {
+        if values.len() == 1 {
+            map.serialize_entry(name, values[0])?;
+        } else {
+            map.serialize_entry(name, &values)?;
+        }
+    }
+    map.end()
+}
+
+pub struct JsonlOutput {
+    writer: BufWriter<File>,
+    time_format: TimeFormat,
+}
+
+impl JsonlOutput {
+    pub fn new(path: &str, time_format: TimeFormat) -> std::io::Result<Self> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+            time_format: time_format,
+        })
+    }
+}
+
+impl EvtOutput for JsonlOutput {
+    fn write_event(&mut self, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        serde_json::to_writer(&mut self.writer, &JsonRecord::from_event(event, &self.time_format))?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::EvtEvent;
+    use crate::output::EvtOutput;
+    use crate::output::jsonl_output::JsonlOutput;
+    use crate::test_support::temp_path;
+    use crate::time_format::TimeFormat;
+
+    #[test]
+    fn test_one_object_per_line() {
+        let xml = r#"<Event><System><Provider Name="Test-Provider" /><EventID>4688</EventID><Level>0</Level><EventRecordID>12</EventRecordID><Channel>Security</Channel><Computer>HOST</Computer><Execution ProcessID="4" ThreadID="8" /></System><UserData><Root><Item>a</Item><Item>b</Item></Root></UserData><EventData><Data Name="NewProcessName">C:\Windows\cmd.exe</Data></EventData></Event>"#;
+        let path = temp_path("jsonl.jsonl");
+        let path = path.to_str().unwrap().to_string();
+        let event = EvtEvent::from_xml(xml.to_string()).unwrap();
+        let mut output = JsonlOutput::new(&path, TimeFormat::default()).unwrap();
+        output.write_event(&event).unwrap();
+        output.write_event(&event).unwrap();
+        output.finish().unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let value: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(value["EventID"], 4688);
+        assert_eq!(value["EventRecordID"], 12);
+        assert_eq!(value["ProcessID"], 4);
+        assert_eq!(value["Qualifiers"], serde_json::Value::Null);
+        assert!(value.get("TimeCreatedUTC").is_none());
+        assert_eq!(value["EventData"]["NewProcessName"], "C:\\Windows\\cmd.exe");
+        assert_eq!(value["EventData"]["Root.Item"], serde_json::json!(["a", "b"]));
+    }
+}
@@ -0,0 +1,32 @@
+pub mod csv_output;
+pub mod ecs_output;
+pub mod evtx_output;
+pub mod http_output;
+pub mod jsonl_output;
+pub mod ocsf_output;
+pub mod parquet_output;
+pub mod sqlite_output;
+pub mod syslog_output;
+
+use crate::events::EvtEvent;
+
+// Every output format implements this. Events are handed over one at a time in the
+// order they should appear, and finish is called once after the last event so
+// buffered writers can flush.
+pub trait EvtOutput {
+    fn write_event(&mut self, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>>;
+    fn finish(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>>;
+}
+
+// Groups EventData by name in payload order. UserData can repeat a path, and most
+// formats can't repeat a key, so each name comes back once with all of its values.
+pub fn group_event_data(event_data: &[(String, String)]) -> Vec<(&str, Vec<&str>)> {
+    let mut grouped: Vec<(&str, Vec<&str>)> = Vec::new();
+    for (name, value) in event_data {
+        match grouped.iter_mut().find(|(existing, _)| existing == name) {
+            Some((_, values)) => values.push(value),
+            None => grouped.push((name, vec![value]))
+        }
+    }
+    grouped
+}
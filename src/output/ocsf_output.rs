@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use chrono::SecondsFormat;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use crate::events::EvtEvent;
+use crate::output::EvtOutput;
+use crate::time_format::TimeFormat;
+
+const OCSF_VERSION: &str = "1.1.0";
+const DEFAULT_MAPPINGS: &str = include_str!("ocsf_mappings.json");
+
+// Where an OCSF attribute gets its value. A bare string names an EventData field.
+// type can be "integer", or "fingerprints" for Sysmon's "MD5=..,SHA256=.." hash lists.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum AttributeSource {
+    Field(String),
+    Typed { field: String, r#type: String },
+}
+
+// One entry of the mapping file. An event matches when its provider (if given),
+// channel (if given) and event ID all match. Attributes are OCSF dotted paths
+// pointing at the EventData field that fills them. Any other keys, like the "name"
+// the built-in mappings carry, are only there for whoever reads the file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OcsfMapping {
+    pub provider: Option<String>,
+    pub channel: Option<String>,
+    pub event_ids: Vec<u32>,
+    pub class_uid: u32,
+    pub class_name: String,
+    pub category_uid: u32,
+    pub category_name: String,
+    pub activity_id: u32,
+    pub activity_name: String,
+    pub status_id: Option<u32>,
+    #[serde(default)]
+    pub attributes: HashMap<String, AttributeSource>,
+}
+
+impl OcsfMapping {
+    fn matches(&self, event: &EvtEvent) -> bool {
+        let system = event.get_system();
+        self.event_ids.contains(&system.get_event_id())
+            && self.provider.as_ref().map(|provider| provider == system.get_provider_name()).unwrap_or(true)
+            && self.channel.as_ref().map(|channel| channel == system.get_channel()).unwrap_or(true)
+    }
+}
+
+pub struct OcsfMapper {
+    mappings: Vec<OcsfMapping>,
+}
+
+impl OcsfMapper {
+    // Mappings from the file are checked before the built-in ones, so a file can
+    // override how a well-known event is mapped as well as add new events.
+    pub fn new(mapping_path: Option<&str>) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let mut mappings: Vec<OcsfMapping> = Vec::new();
+        if let Some(path) = mapping_path {
+            let reader = BufReader::new(File::open(path)?);
+            let custom: Vec<OcsfMapping> = serde_json::from_reader(reader)?;
+            mappings.extend(custom);
+        }
+        let defaults: Vec<OcsfMapping> = serde_json::from_str(DEFAULT_MAPPINGS)?;
+        mappings.extend(defaults);
+        Ok(Self { mappings: mappings })
+    }
+
+    // time stays epoch milliseconds as OCSF requires. A converted time goes in time_dt
+    // from the Date/Time profile, which is RFC 3339 whatever --time-format says.
+    pub fn to_ocsf(&self, event: &EvtEvent, time_format: &TimeFormat) -> Value {
+        let system = event.get_system();
+        let mut document = Map::new();
+
+        // Unmapped events fall back to the generic Base Event class
+        let mapping = self.mappings.iter().find(|mapping| mapping.matches(event));
+        let (class_uid, class_name, category_uid, category_name, activity_id, activity_name) = match mapping {
+            Some(mapping) => (mapping.class_uid, mapping.class_name.as_str(), mapping.category_uid, mapping.category_name.as_str(), mapping.activity_id, mapping.activity_name.as_str()),
+            None => (0, "Base Event", 0, "Uncategorized", 0, "Unknown")
+        };
+        document.insert("class_uid".to_string(), json!(class_uid));
+        document.insert("class_name".to_string(), json!(class_name));
+        document.insert("category_uid".to_string(), json!(category_uid));
+        document.insert("category_name".to_string(), json!(category_name));
+        document.insert("activity_id".to_string(), json!(activity_id));
+        document.insert("activity_name".to_string(), json!(activity_name));
+        document.insert("type_uid".to_string(), json!(class_uid as u64 * 100 + activity_id as u64));
+        document.insert("type_name".to_string(), json!(format!("{}: {}", class_name, activity_name)));
+
+        let (severity_id, severity) = match system.get_level() {
+            1 => (5, "Critical"),
+            2 => (4, "High"),
+            3 => (3, "Medium"),
+            _ => (1, "Informational")
+        };
+        document.insert("severity_id".to_string(), json!(severity_id));
+        document.insert("severity".to_string(), json!(severity));
+        if let Some(time) = system.get_time_created() {
+            document.insert("time".to_string(), json!(time.timestamp_millis()));
+            if !time_format.is_default() {
+                document.insert("time_dt".to_string(), json!(time_format.rfc3339(time, SecondsFormat::Millis)));
+            }
+        }
+        document.insert("message".to_string(), json!(event.get_event_message()));
+        document.insert("device".to_string(), json!({"hostname": system.get_computer()}));
+        document.insert("metadata".to_string(), json!({
+            "version": OCSF_VERSION,
+            "product": {"name": "EvtRustler", "vendor_name": "EvtRustler"},
+            "uid": system.get_record_id().to_string(),
+            "original_time": system.get_system_time(),
+            "log_name": system.get_channel(),
+            "log_provider": system.get_provider_name(),
+            "event_code": system.get_event_id().to_string(),
+        }));
+
+        // The mapping file doesn't pair tactics with techniques, so every attack
+        // carries all of the event's tactics
+        if !event.get_attack_techniques().is_empty() {
+            let tactics: Vec<Value> = event.get_attack_tactics().iter().map(|tactic| json!({"name": tactic})).collect();
+            let attacks: Vec<Value> = event.get_attack_techniques().iter()
+                .map(|technique| json!({"technique": {"uid": technique}, "tactics": tactics, "version": "14"}))
+                .collect();
+            document.insert("attacks".to_string(), json!(attacks));
+        }
+
+        let mut unmapped = Map::new();
+        let mut used_fields: Vec<&str> = Vec::new();
+        if let Some(mapping) = mapping {
+            if let Some(status_id) = mapping.status_id {
+                let status = match status_id {
+                    1 => "Success",
+                    2 => "Failure",
+                    _ => "Other"
+                };
+                document.insert("status_id".to_string(), json!(status_id));
+                document.insert("status".to_string(), json!(status));
+            }
+            // Sort the paths so the document comes out the same every run
+            let mut paths: Vec<&String> = mapping.attributes.keys().collect();
+            paths.sort();
+            for path in paths {
+                let (field, value_type) = match &mapping.attributes[path] {
+                    AttributeSource::Field(field) => (field.as_str(), "string"),
+                    AttributeSource::Typed { field, r#type } => (field.as_str(), r#type.as_str())
+                };
+                let value = match event.get_event_data().iter().find(|(name, _)| name == field) {
+                    Some((_, value)) => value,
+                    None => continue
+                };
+                // "-" is what Windows writes for an empty field
+                if value.is_empty() || value == "-" {
+                    continue;
+                }
+                let value = match value_type {
+                    "integer" => match parse_integer(value) {
+                        Some(number) => json!(number),
+                        None => continue
+                    },
+                    "fingerprints" => json!(parse_fingerprints(value)),
+                    _ => json!(value)
+                };
+                set_path(&mut document, path, value);
+                used_fields.push(field);
+            }
+        }
+        for (name, value) in event.get_event_data() {
+            if !used_fields.contains(&name.as_str()) {
+                unmapped.insert(name.to_string(), json!(value));
+            }
+        }
+        if !unmapped.is_empty() {
+            document.insert("unmapped".to_string(), Value::Object(unmapped));
+        }
+        Value::Object(document)
+    }
+}
+
+// Security log PIDs are hex ("0x1a2c"), Sysmon's are decimal
+fn parse_integer(value: &str) -> Option<i64> {
+    match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => value.parse::<i64>().ok()
+    }
+}
+
+// Sysmon writes every configured hash in one field: "SHA1=..,MD5=..,IMPHASH=.."
+fn parse_fingerprints(value: &str) -> Vec<Value> {
+    value.split(',')
+        .filter_map(|hash| hash.split_once('='))
+        .map(|(algorithm, hash)| {
+            let algorithm = algorithm.trim();
+            let algorithm_id = match algorithm.to_uppercase().as_str() {
+                "MD5" => 1,
+                "SHA1" => 2,
+                "SHA256" => 3,
+                "SHA512" => 4,
+                _ => 99
+            };
+            json!({"algorithm": algorithm, "algorithm_id": algorithm_id, "value": hash.trim()})
+        })
+        .collect()
+}
+
+fn set_path(document: &mut Map<String, Value>, path: &str, value: Value) {
+    let mut current = document;
+    let mut parts: Vec<&str> = path.split('.').collect();
+    let last = parts.pop().unwrap();
+    for part in parts {
+        let entry = current.entry(part.to_string()).or_insert_with(|| Value::Object(Map::new()));
+        if !entry.is_object() {
+            *entry = Value::Object(Map::new());
+        }
+        current = entry.as_object_mut().unwrap();
+    }
+    current.insert(last.to_string(), value);
+}
+
+pub struct OcsfOutput {
+    writer: BufWriter<File>,
+    mapper: OcsfMapper,
+    time_format: TimeFormat,
+}
+
+impl OcsfOutput {
+    pub fn new(path: &str, mapping_path: Option<&str>, time_format: TimeFormat) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+            mapper: OcsfMapper::new(mapping_path)?,
+            time_format: time_format,
+        })
+    }
+}
+
+impl EvtOutput for OcsfOutput {
+    fn write_event(&mut self, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        serde_json::to_writer(&mut self.writer, &self.mapper.to_ocsf(event, &self.time_format))?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::output::ocsf_output::OcsfMapper;
+    use crate::test_support::TestEvent;
+    use crate::time_format::{parse_timezone, TimeFormat};
+
+    fn event(provider: &str, event_id: u32) -> TestEvent {
+        TestEvent::default().provider(provider).event_id(event_id).level(0).time("2023-05-01T00:00:01.5000000Z").channel("Security").computer("WKS01")
+    }
+
+    #[test]
+    fn test_failed_logon_is_authentication() {
+        let mapper = OcsfMapper::new(None).unwrap();
+        let document = mapper.to_ocsf(&event("Microsoft-Windows-Security-Auditing", 4625).data("TargetUserName", "bob").data("LogonType", "3").data("IpAddress", "10.1.2.3").data("IpPort", "-").data("Extra", "x").build(), &TimeFormat::default());
+        assert_eq!(document["class_uid"], 3002);
+        assert_eq!(document["type_uid"], 300201);
+        assert_eq!(document["status"], "Failure");
+        assert_eq!(document["user"]["name"], "bob");
+        assert_eq!(document["logon_type_id"], 3);
+        assert_eq!(document["src_endpoint"]["ip"], "10.1.2.3");
+        assert!(document["src_endpoint"].get("port").is_none());
+        assert_eq!(document["unmapped"]["Extra"], "x");
+        assert_eq!(document["time"], 1682899201500i64);
+        assert!(document.get("time_dt").is_none());
+    }
+
+    #[test]
+    fn test_converted_time_dt_is_rfc3339() {
+        let mapper = OcsfMapper::new(None).unwrap();
+        let time_format = TimeFormat { zone: Some(parse_timezone("+02:00").unwrap()), pattern: Some("%d/%m/%Y %H:%M".to_string()), include_utc: false };
+        let document = mapper.to_ocsf(&event("Test-Provider", 1).build(), &time_format);
+        assert_eq!(document["time"], 1682899201500i64);
+        assert_eq!(document["time_dt"], "2023-05-01T02:00:01.500+02:00");
+    }
+
+    #[test]
+    fn test_process_creation_hex_pid() {
+        let mapper = OcsfMapper::new(None).unwrap();
+        let document = mapper.to_ocsf(&event("Microsoft-Windows-Security-Auditing", 4688).data("NewProcessId", "0x1a2c").data("NewProcessName", r"C:\Windows\System32\cmd.exe").build(), &TimeFormat::default());
+        assert_eq!(document["class_name"], "Process Activity");
+        assert_eq!(document["process"]["pid"], 6700);
+        assert_eq!(document["process"]["file"]["path"], "C:\\Windows\\System32\\cmd.exe");
+    }
+
+    #[test]
+    fn test_sysmon_hashes_are_fingerprints() {
+        let mapper = OcsfMapper::new(None).unwrap();
+        let document = mapper.to_ocsf(&event("Microsoft-Windows-Sysmon", 1).data("Hashes", "MD5=0123ABCD,SHA256=4567EF01,IMPHASH=89AB").build(), &TimeFormat::default());
+        let hashes = &document["process"]["file"]["hashes"];
+        assert_eq!(hashes[0], serde_json::json!({"algorithm": "MD5", "algorithm_id": 1, "value": "0123ABCD"}));
+        assert_eq!(hashes[1]["algorithm_id"], 3);
+        assert_eq!(hashes[2]["algorithm_id"], 99);
+        assert_eq!(hashes[2]["algorithm"], "IMPHASH");
+        assert!(document.get("unmapped").is_none());
+    }
+
+    #[test]
+    fn test_unmapped_event_is_base_event() {
+        let mapper = OcsfMapper::new(None).unwrap();
+        let document = mapper.to_ocsf(&event("Some-Provider", 4624).data("A", "1").build(), &TimeFormat::default());
+        assert_eq!(document["class_uid"], 0);
+        assert_eq!(document["class_name"], "Base Event");
+        assert_eq!(document["unmapped"]["A"], "1");
+    }
+}
@@ -0,0 +1,465 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_array::builder::{ListBuilder, MapBuilder, StringBuilder, TimestampNanosecondBuilder, UInt16Builder, UInt32Builder, UInt64Builder, UInt8Builder};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use crate::events::EvtEvent;
+use crate::output::{group_event_data, EvtOutput};
+use crate::time_format::TimeFormat;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParquetPartition {
+    None,
+    Channel,
+    Date,
+    ChannelDate,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParquetOptions {
+    pub partition: ParquetPartition,
+    // Rows buffered per partition before a row group is written
+    pub row_group_size: usize,
+    // Partition files kept open at once. The least recently written one is closed to
+    // make room, and a later event for it starts a new file next to the first.
+    pub max_open_partitions: usize,
+    // Bytes of events buffered across all open partitions. Past it the partition
+    // holding the most is written out as a smaller row group.
+    pub memory_budget: usize,
+    pub compression: Compression,
+    // TimeCreated stays a typed instant. Only the zone is used, as the column's
+    // timezone and for date partitions.
+    pub time_format: TimeFormat,
+}
+
+impl Default for ParquetOptions {
+    fn default() -> Self {
+        Self {
+            partition: ParquetPartition::None,
+            row_group_size: 131072,
+            max_open_partitions: 64,
+            memory_budget: 512 * 1024 * 1024,
+            compression: Compression::ZSTD(ZstdLevel::default()),
+            time_format: TimeFormat::default(),
+        }
+    }
+}
+
+impl ParquetOptions {
+    pub fn parse_partition(value: &str) -> Option<ParquetPartition> {
+        match value {
+            "none" => Some(ParquetPartition::None),
+            "channel" => Some(ParquetPartition::Channel),
+            "date" => Some(ParquetPartition::Date),
+            "channel-date" => Some(ParquetPartition::ChannelDate),
+            _ => None
+        }
+    }
+
+    pub fn parse_compression(value: &str) -> Option<Compression> {
+        match value {
+            "zstd" => Some(Compression::ZSTD(ZstdLevel::default())),
+            "snappy" => Some(Compression::SNAPPY),
+            "none" => Some(Compression::UNCOMPRESSED),
+            _ => None
+        }
+    }
+}
+
+// One builder per column. The column names match the CSV and JSON Lines output.
+// EventData is a map column, so DuckDB/Spark can pull fields out with
+// EventData['TargetUserName'].
+struct EventBatchBuilder {
+    time_created: TimestampNanosecondBuilder,
+    record_id: UInt64Builder,
+    computer: StringBuilder,
+    channel: StringBuilder,
+    provider: StringBuilder,
+    provider_guid: StringBuilder,
+    event_id: UInt32Builder,
+    qualifiers: UInt16Builder,
+    version: UInt8Builder,
+    level: UInt8Builder,
+    level_name: StringBuilder,
+    task: UInt16Builder,
+    task_name: StringBuilder,
+    opcode: UInt8Builder,
+    opcode_name: StringBuilder,
+    keywords: UInt64Builder,
+    keyword_names: ListBuilder<StringBuilder>,
+    process_id: UInt32Builder,
+    thread_id: UInt32Builder,
+    activity_id: StringBuilder,
+    related_activity_id: StringBuilder,
+    user_id: StringBuilder,
+    attack_techniques: ListBuilder<StringBuilder>,
+    attack_tactics: ListBuilder<StringBuilder>,
+    message: StringBuilder,
+    event_data: MapBuilder<StringBuilder, StringBuilder>,
+    rows: usize,
+}
+
+impl EventBatchBuilder {
+    fn new(timezone: &str) -> Self {
+        Self {
+            time_created: TimestampNanosecondBuilder::new().with_timezone(timezone),
+            record_id: UInt64Builder::new(),
+            computer: StringBuilder::new(),
+            channel: StringBuilder::new(),
+            provider: StringBuilder::new(),
+            provider_guid: StringBuilder::new(),
+            event_id: UInt32Builder::new(),
+            qualifiers: UInt16Builder::new(),
+            version: UInt8Builder::new(),
+            level: UInt8Builder::new(),
+            level_name: StringBuilder::new(),
+            task: UInt16Builder::new(),
+            task_name: StringBuilder::new(),
+            opcode: UInt8Builder::new(),
+            opcode_name: StringBuilder::new(),
+            keywords: UInt64Builder::new(),
+            keyword_names: ListBuilder::new(StringBuilder::new()),
+            process_id: UInt32Builder::new(),
+            thread_id: UInt32Builder::new(),
+            activity_id: StringBuilder::new(),
+            related_activity_id: StringBuilder::new(),
+            user_id: StringBuilder::new(),
+            attack_techniques: ListBuilder::new(StringBuilder::new()),
+            attack_tactics: ListBuilder::new(StringBuilder::new()),
+            message: StringBuilder::new(),
+            event_data: MapBuilder::new(None, StringBuilder::new(), StringBuilder::new()),
+            rows: 0,
+        }
+    }
+
+    fn append(&mut self, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let system = event.get_system();
+        self.time_created.append_option(system.get_time_created().and_then(|time| time.timestamp_nanos_opt()));
+        self.record_id.append_value(system.get_record_id());
+        self.computer.append_value(system.get_computer());
+        self.channel.append_value(system.get_channel());
+        self.provider.append_value(system.get_provider_name());
+        self.provider_guid.append_option(system.get_provider_guid());
+        self.event_id.append_value(system.get_event_id());
+        self.qualifiers.append_option(system.get_qualifiers());
+        self.version.append_value(system.get_version());
+        self.level.append_value(system.get_level());
+        self.level_name.append_value(event.get_level_name());
+        self.task.append_value(system.get_task());
+        self.task_name.append_value(event.get_task_name());
+        self.opcode.append_value(system.get_opcode());
+        self.opcode_name.append_value(event.get_opcode_name());
+        self.keywords.append_value(system.get_keywords());
+        for keyword in event.get_keywords() {
+            self.keyword_names.values().append_value(keyword);
+        }
+        self.keyword_names.append(true);
+        self.process_id.append_option(system.get_process_id());
+        self.thread_id.append_option(system.get_thread_id());
+        self.activity_id.append_option(system.get_activity_id());
+        self.related_activity_id.append_option(system.get_related_activity_id());
+        self.user_id.append_option(system.get_user_id());
+        for technique in event.get_attack_techniques() {
+            self.attack_techniques.values().append_value(technique);
+        }
+        self.attack_techniques.append(true);
+        for tactic in event.get_attack_tactics() {
+            self.attack_tactics.values().append_value(tactic);
+        }
+        self.attack_tactics.append(true);
+        self.message.append_value(event.get_event_message());
+
+        // Map keys have to be unique, so repeated UserData paths are joined like in the CSV
+        for (name, values) in group_event_data(event.get_event_data()) {
+            self.event_data.keys().append_value(name);
+            self.event_data.values().append_value(values.join(";"));
+        }
+        self.event_data.append(true)?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::result::Result<RecordBatch, Box<dyn std::error::Error>> {
+        self.rows = 0;
+        // Every column is declared nullable so the schema doesn't change between row groups
+        let columns: Vec<(&str, ArrayRef, bool)> = vec![
+            ("TimeCreated", Arc::new(self.time_created.finish()), true),
+            ("EventRecordID", Arc::new(self.record_id.finish()), true),
+            ("Computer", Arc::new(self.computer.finish()), true),
+            ("Channel", Arc::new(self.channel.finish()), true),
+            ("Provider", Arc::new(self.provider.finish()), true),
+            ("ProviderGuid", Arc::new(self.provider_guid.finish()), true),
+            ("EventID", Arc::new(self.event_id.finish()), true),
+            ("Qualifiers", Arc::new(self.qualifiers.finish()), true),
+            ("Version", Arc::new(self.version.finish()), true),
+            ("Level", Arc::new(self.level.finish()), true),
+            ("LevelName", Arc::new(self.level_name.finish()), true),
+            ("Task", Arc::new(self.task.finish()), true),
+            ("TaskName", Arc::new(self.task_name.finish()), true),
+            ("Opcode", Arc::new(self.opcode.finish()), true),
+            ("OpcodeName", Arc::new(self.opcode_name.finish()), true),
+            ("Keywords", Arc::new(self.keywords.finish()), true),
+            ("KeywordNames", Arc::new(self.keyword_names.finish()), true),
+            ("ProcessID", Arc::new(self.process_id.finish()), true),
+            ("ThreadID", Arc::new(self.thread_id.finish()), true),
+            ("ActivityID", Arc::new(self.activity_id.finish()), true),
+            ("RelatedActivityID", Arc::new(self.related_activity_id.finish()), true),
+            ("UserID", Arc::new(self.user_id.finish()), true),
+            ("AttackTechnique", Arc::new(self.attack_techniques.finish()), true),
+            ("AttackTactic", Arc::new(self.attack_tactics.finish()), true),
+            ("Message", Arc::new(self.message.finish()), true),
+            ("EventData", Arc::new(self.event_data.finish()), true),
+        ];
+        Ok(RecordBatch::try_from_iter_with_nullable(columns)?)
+    }
+}
+
+struct PartitionWriter {
+    path: PathBuf,
+    builder: EventBatchBuilder,
+    writer: Option<ArrowWriter<File>>,
+    // Approximate size of the rows in builder
+    buffered: usize,
+    // Event count at the last write, for picking the partition to close
+    last_used: u64,
+}
+
+pub struct ParquetOutput {
+    root: PathBuf,
+    options: ParquetOptions,
+    props: WriterProperties,
+    partitions: HashMap<PathBuf, PartitionWriter>,
+    // Files already closed per partition directory
+    closed_files: HashMap<PathBuf, usize>,
+    buffered: usize,
+    events: u64,
+}
+
+impl ParquetOutput {
+    // Without partitioning, path is the .parquet file. Otherwise it's the root of a
+    // Hive-style directory tree (channel=Security/date=2023-05-01/events.parquet).
+    pub fn new(path: &str, options: ParquetOptions) -> std::io::Result<Self> {
+        let root = PathBuf::from(path);
+        if options.partition != ParquetPartition::None {
+            fs::create_dir_all(&root)?;
+        }
+        let props = WriterProperties::builder()
+            .set_compression(options.compression)
+            .set_max_row_group_size(options.row_group_size)
+            .build();
+        Ok(Self {
+            root: root,
+            options: options,
+            props: props,
+            partitions: HashMap::new(),
+            closed_files: HashMap::new(),
+            buffered: 0,
+            events: 0,
+        })
+    }
+
+    // The directory a partition's files go in, or the output file without partitioning
+    fn partition_dir(&self, event: &EvtEvent) -> PathBuf {
+        let system = event.get_system();
+        let date = match system.get_time_created() {
+            Some(time) => self.options.time_format.format_with(time, "%Y-%m-%d"),
+            None => "unknown".to_string()
+        };
+        let channel = escape_partition_value(system.get_channel());
+        match self.options.partition {
+            ParquetPartition::None => self.root.clone(),
+            ParquetPartition::Channel => self.root.join(format!("channel={}", channel)),
+            ParquetPartition::Date => self.root.join(format!("date={}", date)),
+            ParquetPartition::ChannelDate => self.root.join(format!("channel={}", channel)).join(format!("date={}", date)),
+        }
+    }
+
+    // events.parquet, then events-1.parquet and so on for a partition that was closed
+    // and written to again
+    fn partition_file(&self, dir: &Path) -> PathBuf {
+        if self.options.partition == ParquetPartition::None {
+            return dir.to_path_buf();
+        }
+        match self.closed_files.get(dir) {
+            Some(closed) => dir.join(format!("events-{}.parquet", closed)),
+            None => dir.join("events.parquet")
+        }
+    }
+
+    fn close_partition(&mut self, dir: &Path) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        if let Some(mut partition) = self.partitions.remove(dir) {
+            self.buffered -= partition.buffered;
+            Self::flush_partition(&mut partition, &self.props)?;
+            if let Some(writer) = partition.writer.take() {
+                writer.close()?;
+            }
+            *self.closed_files.entry(dir.to_path_buf()).or_insert(0) += 1;
+        }
+        Ok(())
+    }
+
+    fn flush_partition(partition: &mut PartitionWriter, props: &WriterProperties) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        if partition.builder.rows == 0 {
+            return Ok(());
+        }
+        let batch = partition.builder.finish()?;
+        partition.buffered = 0;
+        if partition.writer.is_none() {
+            if let Some(parent) = partition.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = File::create(&partition.path)?;
+            partition.writer = Some(ArrowWriter::try_new(file, batch.schema(), Some(props.clone()))?);
+        }
+        // ArrowWriter would otherwise keep the rows until it has a full row group
+        let writer = partition.writer.as_mut().unwrap();
+        writer.write(&batch)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl EvtOutput for ParquetOutput {
+    fn write_event(&mut self, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = self.partition_dir(event);
+        if !self.partitions.contains_key(&dir) && self.partitions.len() >= self.options.max_open_partitions.max(1) {
+            let oldest = self.partitions.iter().min_by_key(|(_, partition)| partition.last_used).map(|(dir, _)| dir.clone()).unwrap();
+            self.close_partition(&oldest)?;
+        }
+        if !self.partitions.contains_key(&dir) {
+            let partition = PartitionWriter {
+                path: self.partition_file(&dir),
+                builder: EventBatchBuilder::new(&self.options.time_format.zone_name()),
+                writer: None,
+                buffered: 0,
+                last_used: 0,
+            };
+            self.partitions.insert(dir.clone(), partition);
+        }
+
+        self.events += 1;
+        let size = event.approximate_size();
+        let partition = self.partitions.get_mut(&dir).unwrap();
+        partition.builder.append(event)?;
+        partition.buffered += size;
+        partition.last_used = self.events;
+        self.buffered += size;
+        if partition.builder.rows >= self.options.row_group_size {
+            self.buffered -= partition.buffered;
+            Self::flush_partition(partition, &self.props)?;
+        }
+        while self.buffered > self.options.memory_budget {
+            let largest = self.partitions.values_mut().max_by_key(|partition| partition.buffered).unwrap();
+            if largest.buffered == 0 {
+                break;
+            }
+            self.buffered -= largest.buffered;
+            Self::flush_partition(largest, &self.props)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dirs: Vec<PathBuf> = self.partitions.keys().cloned().collect();
+        for dir in dirs {
+            self.close_partition(&dir)?;
+        }
+        Ok(())
+    }
+}
+
+// Channel names contain '/' and other characters that can't go in a directory name.
+// Escape them the way Hive does so query engines decode the partition value correctly.
+fn escape_partition_value(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' || c == ' ' {
+            escaped.push(c);
+        } else {
+            let mut buffer = [0; 4];
+            for byte in c.encode_utf8(&mut buffer).bytes() {
+                escaped.push_str(&format!("%{:02X}", byte));
+            }
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use crate::events::EvtEvent;
+    use crate::output::EvtOutput;
+    use crate::output::parquet_output::{escape_partition_value, ParquetOptions, ParquetOutput, ParquetPartition};
+    use crate::test_support::{temp_path, TestEvent};
+
+    fn event(record_id: u64, channel: &str, time: &str) -> EvtEvent {
+        TestEvent::default().time(time).record_id(record_id).channel(channel).data("A", "1").build()
+    }
+
+    #[test]
+    fn test_partitioned_row_groups() {
+        let root = temp_path("parquet");
+        let options = ParquetOptions { partition: ParquetPartition::ChannelDate, row_group_size: 2, ..ParquetOptions::default() };
+        let mut output = ParquetOutput::new(root.to_str().unwrap(), options).unwrap();
+        for record_id in 0..5 {
+            output.write_event(&event(record_id, "Microsoft-Windows-Sysmon/Operational", "2023-05-01T01:00:00.0000000Z")).unwrap();
+        }
+        output.write_event(&event(9, "Security", "2023-05-02T01:00:00.0000000Z")).unwrap();
+        output.finish().unwrap();
+
+        let sysmon = root.join("channel=Microsoft-Windows-Sysmon%2FOperational").join("date=2023-05-01").join("events.parquet");
+        let reader = SerializedFileReader::new(File::open(&sysmon).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 5);
+        assert_eq!(reader.metadata().num_row_groups(), 3);
+        assert!(root.join("channel=Security").join("date=2023-05-02").join("events.parquet").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_open_partitions_are_capped() {
+        let root = temp_path("parquet_lru");
+        let options = ParquetOptions { partition: ParquetPartition::Channel, max_open_partitions: 1, ..ParquetOptions::default() };
+        let mut output = ParquetOutput::new(root.to_str().unwrap(), options).unwrap();
+        // Security is closed when System opens, and gets a second file when it comes back
+        output.write_event(&event(1, "Security", "2023-05-01T01:00:00.0000000Z")).unwrap();
+        output.write_event(&event(2, "System", "2023-05-01T01:00:00.0000000Z")).unwrap();
+        output.write_event(&event(3, "Security", "2023-05-01T01:00:00.0000000Z")).unwrap();
+        output.write_event(&event(4, "Security", "2023-05-01T01:00:00.0000000Z")).unwrap();
+        assert_eq!(output.partitions.len(), 1);
+        output.finish().unwrap();
+
+        let rows = |path: std::path::PathBuf| SerializedFileReader::new(File::open(path).unwrap()).unwrap().metadata().file_metadata().num_rows();
+        assert_eq!(rows(root.join("channel=Security").join("events.parquet")), 1);
+        assert_eq!(rows(root.join("channel=Security").join("events-1.parquet")), 2);
+        assert_eq!(rows(root.join("channel=System").join("events.parquet")), 1);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_memory_budget_flushes_row_groups() {
+        let root = temp_path("parquet_budget.parquet");
+        // Every event goes over the budget, so each one is its own row group
+        let options = ParquetOptions { memory_budget: 1, ..ParquetOptions::default() };
+        let mut output = ParquetOutput::new(root.to_str().unwrap(), options).unwrap();
+        for record_id in 0..3 {
+            output.write_event(&event(record_id, "Security", "2023-05-01T01:00:00.0000000Z")).unwrap();
+        }
+        assert_eq!(output.buffered, 0);
+        output.finish().unwrap();
+        let reader = SerializedFileReader::new(File::open(&root).unwrap()).unwrap();
+        assert_eq!(reader.metadata().num_row_groups(), 3);
+        std::fs::remove_file(&root).unwrap();
+    }
+
+    #[test]
+    fn test_escape_partition_value() {
+        assert_eq!(escape_partition_value("Windows PowerShell"), "Windows PowerShell");
+        assert_eq!(escape_partition_value("a/b=c"), "a%2Fb%3Dc");
+    }
+}
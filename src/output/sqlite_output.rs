@@ -0,0 +1,313 @@
+use chrono::SecondsFormat;
+use rusqlite::{params, Connection};
+use crate::events::EvtEvent;
+use crate::metadata_cache::EvtCache;
+use crate::output::EvtOutput;
+use crate::time_format::TimeFormat;
+
+// Events are committed in batches. One transaction per event is far too slow once a
+// case gets into the millions of records.
+const EVENTS_PER_TRANSACTION: usize = 10000;
+
+// events holds the System fields, event_data the payload as one row per field, and
+// providers a copy of the metadata cache. provider_metadata has one row per level,
+// task, opcode, keyword and channel a provider declares, so names can be joined onto
+// events, e.g. ON m.provider = e.provider AND m.kind = 'task' AND m.value = e.task.
+// task is 0 except for opcodes that belong to one task. Keyword
+// values are masks stored like events.keywords. (computer, channel, record_id) is
+// unique, so running against the same database again only adds records it hasn't seen.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS events (
+        id INTEGER PRIMARY KEY,
+        time_created TEXT,
+        time_created_local TEXT,
+        record_id INTEGER NOT NULL,
+        computer TEXT NOT NULL,
+        channel TEXT NOT NULL,
+        provider TEXT NOT NULL,
+        provider_guid TEXT,
+        event_source_name TEXT,
+        event_id INTEGER NOT NULL,
+        qualifiers INTEGER,
+        version INTEGER NOT NULL,
+        level INTEGER NOT NULL,
+        level_name TEXT,
+        task INTEGER NOT NULL,
+        task_name TEXT,
+        opcode INTEGER NOT NULL,
+        opcode_name TEXT,
+        keywords INTEGER NOT NULL,
+        keyword_names TEXT,
+        process_id INTEGER,
+        thread_id INTEGER,
+        activity_id TEXT,
+        related_activity_id TEXT,
+        user_id TEXT,
+        attack_techniques TEXT,
+        attack_tactics TEXT,
+        message TEXT,
+        xml TEXT,
+        UNIQUE (computer, channel, record_id)
+    );
+    CREATE TABLE IF NOT EXISTS event_data (
+        event_rowid INTEGER NOT NULL REFERENCES events(id),
+        position INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        value TEXT,
+        PRIMARY KEY (event_rowid, position)
+    );
+    CREATE TABLE IF NOT EXISTS providers (
+        name TEXT PRIMARY KEY,
+        metadata TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS provider_metadata (
+        provider TEXT NOT NULL REFERENCES providers(name),
+        kind TEXT NOT NULL,
+        value INTEGER NOT NULL,
+        task INTEGER NOT NULL,
+        name TEXT,
+        message TEXT,
+        PRIMARY KEY (provider, kind, value, task)
+    );
+    CREATE INDEX IF NOT EXISTS events_time_created ON events(time_created);
+    CREATE INDEX IF NOT EXISTS events_event_id ON events(event_id);
+    CREATE INDEX IF NOT EXISTS events_provider ON events(provider);
+    CREATE INDEX IF NOT EXISTS events_computer ON events(computer);
+    CREATE INDEX IF NOT EXISTS event_data_name_value ON event_data(name, value);
+";
+
+pub struct SqliteOutput {
+    connection: Connection,
+    time_format: TimeFormat,
+    pending: usize,
+    inserted: usize,
+    skipped: usize,
+}
+
+impl SqliteOutput {
+    pub fn new(path: &str, config: &EvtCache, time_format: TimeFormat) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        let mut output = Self {
+            connection: connection,
+            time_format: time_format,
+            pending: 0,
+            inserted: 0,
+            skipped: 0,
+        };
+        output.save_providers(config)?;
+        output.connection.execute_batch("BEGIN")?;
+        Ok(output)
+    }
+
+    fn save_providers(&mut self, config: &EvtCache) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare("INSERT OR REPLACE INTO providers (name, metadata) VALUES (?1, ?2)")?;
+            let mut metadata_statement = transaction.prepare("
+                INSERT OR REPLACE INTO provider_metadata (provider, kind, value, task, name, message) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ")?;
+            for provider in config.get_data().values() {
+                statement.execute(params![provider.get_name(), provider.to_json()?])?;
+                let kinds = [
+                    ("level", "Level", provider.get_levels()),
+                    ("task", "Task", provider.get_tasks()),
+                    ("opcode", "Opcode", provider.get_opcodes()),
+                    ("keyword", "Keyword", provider.get_keywords()),
+                    ("channel", "Channel", provider.get_channels()),
+                ];
+                for (kind, prefix, entries) in kinds {
+                    for (&key, properties) in entries {
+                        // Opcode keys hold the opcode in the high word and its task in the low word
+                        let (value, task) = if kind == "opcode" { (key >> 16, key & 0xffff) } else { (key, 0) };
+                        metadata_statement.execute(params![
+                            provider.get_name(),
+                            kind,
+                            value as i64,
+                            task as i64,
+                            properties.get(&format!("{} Name", prefix)),
+                            properties.get(&format!("{} Message", prefix)).map(|message| message.trim_end()),
+                        ])?;
+                    }
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+impl EvtOutput for SqliteOutput {
+    fn write_event(&mut self, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let system = event.get_system();
+        // Fixed-width nanoseconds so the text sorts in time order. Times converted with
+        // --timezone or --time-format go in their own column, time_created stays UTC.
+        let time_created = system.get_time_created().map(|time| time.to_rfc3339_opts(SecondsFormat::Nanos, true));
+        let time_created_local = match system.get_time_created() {
+            Some(time) if !self.time_format.is_default() => Some(self.time_format.format(time)),
+            _ => None
+        };
+        let inserted = self.connection.prepare_cached("
+            INSERT OR IGNORE INTO events (
+                time_created, time_created_local, record_id, computer, channel, provider, provider_guid, event_source_name,
+                event_id, qualifiers, version, level, level_name, task, task_name, opcode, opcode_name,
+                keywords, keyword_names, process_id, thread_id, activity_id, related_activity_id, user_id,
+                attack_techniques, attack_tactics, message, xml
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28)
+        ")?.execute(params![
+            time_created,
+            time_created_local,
+            system.get_record_id() as i64,
+            system.get_computer(),
+            system.get_channel(),
+            system.get_provider_name(),
+            system.get_provider_guid(),
+            system.get_event_source_name(),
+            system.get_event_id(),
+            system.get_qualifiers(),
+            system.get_version(),
+            system.get_level(),
+            event.get_level_name(),
+            system.get_task(),
+            event.get_task_name(),
+            system.get_opcode(),
+            event.get_opcode_name(),
+            // SQLite integers are signed. Keep the bit pattern so band-style queries still work.
+            system.get_keywords() as i64,
+            event.get_keywords().join(";"),
+            system.get_process_id(),
+            system.get_thread_id(),
+            system.get_activity_id(),
+            system.get_related_activity_id(),
+            system.get_user_id(),
+            event.get_attack_techniques().join(";"),
+            event.get_attack_tactics().join(";"),
+            event.get_event_message(),
+            event.get_xml(),
+        ])?;
+
+        if inserted == 0 {
+            // Already in the database from an earlier run or an overlapping file
+            self.skipped += 1;
+            return Ok(());
+        }
+        let event_rowid = self.connection.last_insert_rowid();
+        let mut statement = self.connection.prepare_cached("INSERT INTO event_data (event_rowid, position, name, value) VALUES (?1, ?2, ?3, ?4)")?;
+        for (position, (name, value)) in event.get_event_data().iter().enumerate() {
+            statement.execute(params![event_rowid, position as i64, name, value])?;
+        }
+        drop(statement);
+
+        self.inserted += 1;
+        self.pending += 1;
+        if self.pending >= EVENTS_PER_TRANSACTION {
+            self.connection.execute_batch("COMMIT; BEGIN")?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        if !self.connection.is_autocommit() {
+            self.connection.execute_batch("COMMIT")?;
+        }
+        println!("SQLite: inserted {} events, skipped {} already in the database", self.inserted, self.skipped);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use crate::events::EvtEvent;
+    use crate::metadata_cache::EvtCache;
+    use crate::output::EvtOutput;
+    use crate::output::sqlite_output::SqliteOutput;
+    use crate::test_support::{temp_path, TestEvent};
+    use crate::time_format::{parse_timezone, TimeFormat};
+
+    fn event(record_id: u64) -> EvtEvent {
+        TestEvent::default().event_id(4625).time("2023-05-01T01:00:00.1234567Z").record_id(record_id).channel("Security")
+            .data("TargetUserName", "bob").data("IpAddress", "10.0.0.5").build()
+    }
+
+    #[test]
+    fn test_provider_metadata_rows() {
+        let dir = temp_path("sqlite_metadata");
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache_path = dir.join("config.cfg");
+        let cache = r#"{"Test-Provider": {"name": "Test-Provider", "hostname": "HOST",
+            "channels": {"0": {"Channel Name": "Security", "Channel Index": "0"}},
+            "levels": {"4": {"Level Name": "win:Informational", "Level Message": "Information "}},
+            "tasks": {"12544": {"Task Name": "Logon", "Task Message": "Logon"}},
+            "opcodes": {"655365": {"Opcode Name": "TaskOpcode"}},
+            "keywords": {"9007199254740992": {"Keyword Name": "Audit Success"}}, "events": []}}"#;
+        std::fs::write(&cache_path, cache).unwrap();
+        let config = EvtCache::new(cache_path.to_str().unwrap()).unwrap();
+        let db_path = dir.join("case.db");
+        let mut output = SqliteOutput::new(db_path.to_str().unwrap(), &config, TimeFormat::default()).unwrap();
+        output.finish().unwrap();
+        drop(output);
+        // Opening the database again replaces the rows rather than adding to them
+        let mut output = SqliteOutput::new(db_path.to_str().unwrap(), &config, TimeFormat::default()).unwrap();
+        output.finish().unwrap();
+        drop(output);
+
+        let connection = Connection::open(&db_path).unwrap();
+        let rows: i64 = connection.query_row("SELECT COUNT(*) FROM provider_metadata", [], |row| row.get(0)).unwrap();
+        let lookup = |kind: &str, value: i64, task: i64| -> (Option<String>, Option<String>) {
+            connection.query_row("SELECT name, message FROM provider_metadata WHERE provider = 'Test-Provider' AND kind = ?1 AND value = ?2 AND task = ?3",
+                rusqlite::params![kind, value, task], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+        };
+        assert_eq!(rows, 5);
+        assert_eq!(lookup("level", 4, 0), (Some("win:Informational".to_string()), Some("Information".to_string())));
+        assert_eq!(lookup("task", 12544, 0).0.unwrap(), "Logon");
+        assert_eq!(lookup("opcode", 10, 5).0.unwrap(), "TaskOpcode");
+        assert_eq!(lookup("keyword", 9007199254740992, 0).0.unwrap(), "Audit Success");
+        assert_eq!(lookup("channel", 0, 0).0.unwrap(), "Security");
+        drop(connection);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_append_without_duplicates() {
+        let dir = temp_path("sqlite");
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("case.db");
+        let config = EvtCache::new(dir.join("config.cfg").to_str().unwrap()).unwrap();
+
+        let mut output = SqliteOutput::new(db_path.to_str().unwrap(), &config, TimeFormat::default()).unwrap();
+        output.write_event(&event(1)).unwrap();
+        output.write_event(&event(2)).unwrap();
+        output.finish().unwrap();
+        drop(output);
+
+        // Second run overlaps the first and converts times
+        let time_format = TimeFormat { zone: Some(parse_timezone("Europe/Berlin").unwrap()), pattern: None, include_utc: false };
+        let mut output = SqliteOutput::new(db_path.to_str().unwrap(), &config, time_format).unwrap();
+        output.write_event(&event(2)).unwrap();
+        output.write_event(&event(3)).unwrap();
+        output.finish().unwrap();
+        drop(output);
+
+        let connection = Connection::open(&db_path).unwrap();
+        let events: i64 = connection.query_row("SELECT COUNT(*) FROM events", [], |row| row.get(0)).unwrap();
+        let fields: i64 = connection.query_row("SELECT COUNT(*) FROM event_data", [], |row| row.get(0)).unwrap();
+        let user: String = connection.query_row(
+            "SELECT d.value FROM events e JOIN event_data d ON d.event_rowid = e.id WHERE e.record_id = 3 AND d.name = 'TargetUserName'",
+            [], |row| row.get(0)).unwrap();
+        let time: String = connection.query_row("SELECT time_created FROM events WHERE record_id = 1", [], |row| row.get(0)).unwrap();
+        let (converted_utc, converted_local): (String, String) = connection.query_row(
+            "SELECT time_created, time_created_local FROM events WHERE record_id = 3", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        let unconverted_local: Option<String> = connection.query_row("SELECT time_created_local FROM events WHERE record_id = 1", [], |row| row.get(0)).unwrap();
+        assert_eq!(events, 3);
+        assert_eq!(fields, 6);
+        assert_eq!(user, "bob");
+        assert_eq!(time, "2023-05-01T01:00:00.123456700Z");
+        assert_eq!(converted_utc, "2023-05-01T01:00:00.123456700Z");
+        assert_eq!(converted_local, "2023-05-01T03:00:00.123456700+02:00");
+        assert_eq!(unconverted_local, None);
+        drop(connection);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
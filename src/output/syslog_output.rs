@@ -0,0 +1,484 @@
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::Duration;
+use chrono::SecondsFormat;
+use native_tls::{TlsConnector, TlsStream};
+use crate::events::EvtEvent;
+use crate::output::EvtOutput;
+use crate::time_format::TimeFormat;
+
+// Private enterprise number used in the structured data IDs. 32473 is the number
+// RFC 5612 sets aside for documentation and examples.
+const SD_ENTERPRISE: &str = "32473";
+const RECONNECT_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyslogTransport {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyslogFormat {
+    Rfc5424,
+    Cef,
+    Leef,
+}
+
+#[derive(Debug, Clone)]
+pub struct SyslogOptions {
+    // Collector address as host:port
+    pub target: String,
+    pub transport: SyslogTransport,
+    pub format: SyslogFormat,
+    pub facility: u8,
+    // Accept self-signed or mismatched certificates on the collector
+    pub tls_insecure: bool,
+    // Only the zone applies to the RFC 5424 timestamp. CEF and LEEF send epoch milliseconds.
+    pub time_format: TimeFormat,
+}
+
+impl Default for SyslogOptions {
+    fn default() -> Self {
+        Self {
+            target: "127.0.0.1:514".to_string(),
+            transport: SyslogTransport::Udp,
+            format: SyslogFormat::Rfc5424,
+            facility: 16,
+            tls_insecure: false,
+            time_format: TimeFormat::default(),
+        }
+    }
+}
+
+impl SyslogOptions {
+    pub fn parse_transport(value: &str) -> Option<SyslogTransport> {
+        match value {
+            "udp" => Some(SyslogTransport::Udp),
+            "tcp" => Some(SyslogTransport::Tcp),
+            "tls" => Some(SyslogTransport::Tls),
+            _ => None
+        }
+    }
+
+    pub fn parse_format(value: &str) -> Option<SyslogFormat> {
+        match value {
+            "rfc5424" => Some(SyslogFormat::Rfc5424),
+            "cef" => Some(SyslogFormat::Cef),
+            "leef" => Some(SyslogFormat::Leef),
+            _ => None
+        }
+    }
+}
+
+// Syslog severity for an event level. LogAlways (0) is what the Security log uses,
+// so it's treated as informational like Event Viewer does.
+pub fn syslog_severity(level: u8) -> u8 {
+    match level {
+        1 => 2,
+        2 => 3,
+        3 => 4,
+        5 => 7,
+        _ => 6
+    }
+}
+
+// CEF and LEEF both want a 0-10 severity
+fn scaled_severity(level: u8) -> u8 {
+    match level {
+        1 => 10,
+        2 => 8,
+        3 => 5,
+        5 => 1,
+        _ => 3
+    }
+}
+
+// RFC 5424 header fields are printable ASCII without spaces, with a length limit each
+fn header_field(value: &str, max_len: usize) -> String {
+    let field: String = value.chars()
+        .map(|c| if c.is_ascii_graphic() { c } else { '_' })
+        .take(max_len)
+        .collect();
+    if field.is_empty() { "-".to_string() } else { field }
+}
+
+// SD-NAMEs can't contain '=', ' ', ']' or '"' and are limited to 32 characters
+fn sd_name(value: &str) -> String {
+    value.chars()
+        .map(|c| if c.is_ascii_graphic() && !matches!(c, '=' | ']' | '"') { c } else { '_' })
+        .take(32)
+        .collect()
+}
+
+fn sd_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn cef_header_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|").replace(['\r', '\n'], " ")
+}
+
+fn cef_extension_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('=', "\\=").replace("\r\n", "\\n").replace(['\r', '\n'], "\\n")
+}
+
+// LEEF has no escaping for the delimiter, so tabs and line breaks become spaces
+fn leef_value(value: &str) -> String {
+    value.replace("\r\n", " ").replace(['\t', '\r', '\n'], " ")
+}
+
+// CEF and LEEF keys are plain identifiers
+fn extension_key(value: &str) -> String {
+    value.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
+
+// Builds the full syslog message for an event. CEF and LEEF lines ride in the same
+// RFC 5424 header as the native format so every collector sees the same PRI and host.
+pub fn format_event(event: &EvtEvent, options: &SyslogOptions) -> String {
+    let system = event.get_system();
+    let priority = options.facility as u32 * 8 + syslog_severity(system.get_level()) as u32;
+    // RFC 5424 allows at most microseconds
+    let timestamp = match system.get_time_created() {
+        Some(time) => options.time_format.rfc3339(time, SecondsFormat::Micros),
+        None => "-".to_string()
+    };
+    let process_id = match system.get_process_id() {
+        Some(pid) => pid.to_string(),
+        None => "-".to_string()
+    };
+    let header = format!("<{}>1 {} {} {} {} {}",
+        priority,
+        timestamp,
+        header_field(system.get_computer(), 255),
+        header_field(system.get_provider_name(), 48),
+        process_id,
+        system.get_event_id(),
+    );
+
+    match options.format {
+        SyslogFormat::Rfc5424 => {
+            let mut structured = format!("[win@{} Channel=\"{}\" RecordID=\"{}\" Level=\"{}\" Task=\"{}\" Keywords=\"0x{:016x}\"",
+                SD_ENTERPRISE,
+                sd_value(system.get_channel()),
+                system.get_record_id(),
+                sd_value(event.get_level_name()),
+                sd_value(event.get_task_name()),
+                system.get_keywords(),
+            );
+            if options.time_format.include_utc {
+                structured.push_str(&format!(" TimeCreatedUTC=\"{}\"", sd_value(system.get_system_time())));
+            }
+            structured.push(']');
+            if !event.get_event_data().is_empty() {
+                structured.push_str(&format!("[event_data@{}", SD_ENTERPRISE));
+                for (name, value) in event.get_event_data() {
+                    structured.push_str(&format!(" {}=\"{}\"", sd_name(name), sd_value(value)));
+                }
+                structured.push(']');
+            }
+            // SD parameters can repeat, so each tag gets its own
+            if !event.get_attack_techniques().is_empty() {
+                structured.push_str(&format!("[attack@{}", SD_ENTERPRISE));
+                for technique in event.get_attack_techniques() {
+                    structured.push_str(&format!(" Technique=\"{}\"", sd_value(technique)));
+                }
+                for tactic in event.get_attack_tactics() {
+                    structured.push_str(&format!(" Tactic=\"{}\"", sd_value(tactic)));
+                }
+                structured.push(']');
+            }
+            let message = event.get_event_message();
+            if message.is_empty() {
+                format!("{} {}", header, structured)
+            } else {
+                format!("{} {} {}", header, structured, message)
+            }
+        },
+        SyslogFormat::Cef => {
+            let name = match event.get_task_name() {
+                "" | "None" => system.get_event_id().to_string(),
+                task_name => task_name.to_string()
+            };
+            let mut extension = vec![
+                format!("dvchost={}", cef_extension_value(system.get_computer())),
+                format!("externalId={}", system.get_record_id()),
+                format!("cat={}", cef_extension_value(system.get_channel())),
+            ];
+            if let Some(time) = system.get_time_created() {
+                extension.push(format!("rt={}", time.timestamp_millis()));
+            }
+            if let Some(pid) = system.get_process_id() {
+                extension.push(format!("dvcpid={}", pid));
+            }
+            // ArcSight keeps vendor specific fields under ad.*
+            for (name, value) in event.get_event_data() {
+                extension.push(format!("ad.{}={}", extension_key(name), cef_extension_value(value)));
+            }
+            extension.push(format!("msg={}", cef_extension_value(&event.get_event_message())));
+            format!("{} - CEF:0|Microsoft|{}|{}|{}|{}|{}|{}",
+                header,
+                cef_header_value(system.get_provider_name()),
+                system.get_version(),
+                system.get_event_id(),
+                cef_header_value(&name),
+                scaled_severity(system.get_level()),
+                extension.join(" "),
+            )
+        },
+        SyslogFormat::Leef => {
+            let mut attributes = vec![
+                format!("sev={}", scaled_severity(system.get_level())),
+                format!("identHostName={}", leef_value(system.get_computer())),
+                format!("externalId={}", system.get_record_id()),
+                format!("cat={}", leef_value(system.get_channel())),
+            ];
+            // QRadar reads devTime as epoch milliseconds when no devTimeFormat is given
+            if let Some(time) = system.get_time_created() {
+                attributes.push(format!("devTime={}", time.timestamp_millis()));
+            }
+            for (name, value) in event.get_event_data() {
+                attributes.push(format!("{}={}", extension_key(name), leef_value(value)));
+            }
+            attributes.push(format!("msg={}", leef_value(&event.get_event_message())));
+            format!("{} - LEEF:1.0|Microsoft|{}|{}|{}|{}",
+                header,
+                leef_value(system.get_provider_name()).replace('|', " "),
+                system.get_version(),
+                system.get_event_id(),
+                attributes.join("\t"),
+            )
+        }
+    }
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+// Streams events to a syslog collector. TCP and TLS connections are opened lazily and
+// reopened with backoff when a write fails, so a collector restart doesn't end the run.
+// Messages written into a connection the peer has already dropped can still be lost,
+// as with any plain TCP syslog sender.
+pub struct SyslogOutput {
+    options: SyslogOptions,
+    connection: Option<Connection>,
+    sent: u64,
+}
+
+impl SyslogOutput {
+    pub fn new(options: SyslogOptions) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let mut output = Self {
+            options: options,
+            connection: None,
+            sent: 0,
+        };
+        // Connect up front so a bad target fails before any parsing happens
+        output.connection = Some(output.connect()?);
+        Ok(output)
+    }
+
+    fn connect(&self) -> std::result::Result<Connection, Box<dyn std::error::Error>> {
+        match self.options.transport {
+            SyslogTransport::Udp => {
+                // The local socket has to be of the same family as the collector
+                let target = self.options.target.to_socket_addrs()?.next()
+                    .ok_or_else(|| format!("{} doesn't resolve to an address", self.options.target))?;
+                let socket = UdpSocket::bind(if target.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" })?;
+                socket.connect(target)?;
+                Ok(Connection::Udp(socket))
+            },
+            SyslogTransport::Tcp => Ok(Connection::Tcp(TcpStream::connect(&self.options.target)?)),
+            SyslogTransport::Tls => {
+                let connector = TlsConnector::builder()
+                    .danger_accept_invalid_certs(self.options.tls_insecure)
+                    .danger_accept_invalid_hostnames(self.options.tls_insecure)
+                    .build()?;
+                let domain = match self.options.target.rsplit_once(':') {
+                    Some((host, _port)) => host.trim_start_matches('[').trim_end_matches(']'),
+                    None => self.options.target.as_str()
+                };
+                let stream = TcpStream::connect(&self.options.target)?;
+                Ok(Connection::Tls(connector.connect(domain, stream)?))
+            }
+        }
+    }
+
+    // Stream transports need framing. RFC 5424 messages use octet counting (RFC 6587)
+    // so multi-line messages survive, CEF and LEEF are newline delimited.
+    fn frame(&self, message: &str) -> Vec<u8> {
+        match (self.options.transport, self.options.format) {
+            (SyslogTransport::Udp, _) => message.as_bytes().to_vec(),
+            (_, SyslogFormat::Rfc5424) => format!("{} {}", message.len(), message).into_bytes(),
+            (_, _) => format!("{}\n", message).into_bytes()
+        }
+    }
+
+    fn send(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        match self.connection.as_mut() {
+            Some(Connection::Udp(socket)) => socket.send(frame).map(|_| ()),
+            Some(Connection::Tcp(stream)) => stream.write_all(frame),
+            Some(Connection::Tls(stream)) => stream.write_all(frame),
+            None => Err(std::io::Error::new(std::io::ErrorKind::NotConnected, "Not connected"))
+        }
+    }
+}
+
+impl EvtOutput for SyslogOutput {
+    fn write_event(&mut self, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let frame = self.frame(&format_event(event, &self.options));
+        let mut attempt = 0;
+        loop {
+            let error = match self.send(&frame) {
+                Ok(()) => {
+                    self.sent += 1;
+                    return Ok(());
+                },
+                Err(e) => e
+            };
+            self.connection = None;
+            attempt += 1;
+            if attempt > RECONNECT_ATTEMPTS {
+                return Err(Box::new(error));
+            }
+            println!("Lost connection to {} ({}), reconnecting", self.options.target, error);
+            thread::sleep(Duration::from_millis(250 * 2u64.pow(attempt - 1)));
+            match self.connect() {
+                Ok(connection) => self.connection = Some(connection),
+                Err(e) => println!("Failed to reconnect to {}: {}", self.options.target, e)
+            }
+        }
+    }
+
+    fn finish(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        match self.connection.as_mut() {
+            Some(Connection::Tcp(stream)) => stream.flush()?,
+            Some(Connection::Tls(stream)) => {
+                stream.flush()?;
+                stream.shutdown()?;
+            },
+            _ => ()
+        }
+        println!("Sent {} events to {}", self.sent, self.options.target);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::{TcpListener, UdpSocket};
+    use std::thread;
+    use crate::events::EvtEvent;
+    use crate::output::EvtOutput;
+    use crate::output::syslog_output::{format_event, SyslogFormat, SyslogOptions, SyslogOutput, SyslogTransport};
+    use crate::test_support::TestEvent;
+
+    fn event(record_id: u64, level: u8) -> EvtEvent {
+        TestEvent::default().provider("Microsoft-Windows-Security-Auditing").event_id(4625).level(level).time("2023-05-01T12:00:00.1234567Z")
+            .record_id(record_id).execution(788, 1).channel("Security").computer("WKS01")
+            .data("TargetUserName", r#"bob "the" admin"#).data("IpAddress", "10.0.0.5").build()
+    }
+
+    #[test]
+    fn test_message_formats() {
+        let mut options = SyslogOptions::default();
+        let message = format_event(&event(7, 2), &options);
+        // local0 (16) * 8 + err (3)
+        assert!(message.starts_with("<131>1 2023-05-01T12:00:00.123456Z WKS01 Microsoft-Windows-Security-Auditing 788 4625 [win@32473 Channel=\"Security\" RecordID=\"7\""));
+        assert!(message.contains("[event_data@32473 TargetUserName=\"bob \\\"the\\\" admin\" IpAddress=\"10.0.0.5\"]"));
+
+        options.format = SyslogFormat::Cef;
+        let message = format_event(&event(7, 0), &options);
+        assert!(message.starts_with("<134>1 "));
+        assert!(message.contains(" - CEF:0|Microsoft|Microsoft-Windows-Security-Auditing|0|4625|4625|3|dvchost=WKS01 externalId=7 cat=Security rt=1682942400123 dvcpid=788 ad.TargetUserName=bob \"the\" admin"));
+
+        options.format = SyslogFormat::Leef;
+        let message = format_event(&event(7, 0), &options);
+        assert!(message.contains(" - LEEF:1.0|Microsoft|Microsoft-Windows-Security-Auditing|0|4625|sev=3\tidentHostName=WKS01\t"));
+        assert!(message.contains("\tIpAddress=10.0.0.5\t"));
+    }
+
+    #[test]
+    fn test_udp_delivery() {
+        for address in ["127.0.0.1:0", "[::1]:0"] {
+            let listener = UdpSocket::bind(address).unwrap();
+            let options = SyslogOptions {
+                target: listener.local_addr().unwrap().to_string(),
+                ..Default::default()
+            };
+            let mut output = SyslogOutput::new(options).unwrap();
+            output.write_event(&event(1, 4)).unwrap();
+            output.finish().unwrap();
+
+            let mut buffer = [0u8; 4096];
+            let length = listener.recv(&mut buffer).unwrap();
+            let message = String::from_utf8_lossy(&buffer[..length]);
+            assert!(message.starts_with("<134>1 "), "{}", address);
+        }
+    }
+
+    #[test]
+    fn test_tcp_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = SyslogOptions {
+            target: listener.local_addr().unwrap().to_string(),
+            transport: SyslogTransport::Tcp,
+            format: SyslogFormat::Cef,
+            ..Default::default()
+        };
+        let server = thread::spawn(move || {
+            // Drop the first connection straight away, then read from the second one
+            let (first, _) = listener.accept().unwrap();
+            drop(first);
+            let (second, _) = listener.accept().unwrap();
+            let mut lines = BufReader::new(second).lines();
+            lines.next().unwrap().unwrap()
+        });
+
+        let mut output = SyslogOutput::new(options).unwrap();
+        let mut record_id = 0;
+        while !server.is_finished() && record_id < 50 {
+            record_id += 1;
+            output.write_event(&event(record_id, 0)).unwrap();
+            thread::sleep(std::time::Duration::from_millis(20));
+        }
+        let line = server.join().unwrap();
+        assert!(line.contains("CEF:0|Microsoft|"));
+    }
+
+    #[test]
+    fn test_octet_counting() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = SyslogOptions {
+            target: listener.local_addr().unwrap().to_string(),
+            transport: SyslogTransport::Tcp,
+            ..Default::default()
+        };
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut received = String::new();
+            BufReader::new(stream).read_to_string(&mut received).unwrap();
+            received
+        });
+        let mut output = SyslogOutput::new(options).unwrap();
+        output.write_event(&event(1, 0)).unwrap();
+        output.finish().unwrap();
+        drop(output);
+
+        let received = server.join().unwrap();
+        let (length, message) = received.split_once(' ').unwrap();
+        assert_eq!(length.parse::<usize>().unwrap(), message.len());
+    }
+}
@@ -592,7 +592,10 @@ impl EvtProvider {
 }
 impl Drop for EvtProvider {
     fn drop(&mut self) {
-        unsafe { EvtClose(self.handle) };
+        // Providers loaded from the cache file never had a handle opened
+        if self.handle.0 != 0 {
+            unsafe { EvtClose(self.handle) };
+        }
     }
 }
 impl PartialEq for EvtProvider {
@@ -0,0 +1,192 @@
+use std::collections::HashSet;
+use chrono::{DateTime, Utc};
+use xmltree::{Element, XMLNode};
+use crate::xpath::{XPathError, XPathQuery};
+
+#[derive(Debug)]
+pub enum QueryListError {
+    IoError(std::io::Error),
+    XmlParseError(xmltree::ParseError),
+    QueryError(String, XPathError),
+    InvalidQueryList(String),
+}
+
+impl From<std::io::Error> for QueryListError {
+    fn from(err: std::io::Error) -> QueryListError {
+        QueryListError::IoError(err)
+    }
+}
+
+impl From<xmltree::ParseError> for QueryListError {
+    fn from(err: xmltree::ParseError) -> QueryListError {
+        QueryListError::XmlParseError(err)
+    }
+}
+
+impl std::error::Error for QueryListError {}
+
+impl std::fmt::Display for QueryListError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryListError::IoError(err) => write!(f, "Couldn't read query file: {}", err),
+            QueryListError::XmlParseError(err) => write!(f, "Query file isn't valid XML: {}", err),
+            QueryListError::QueryError(query, err) => write!(f, "Invalid query '{}': {}", query, err),
+            QueryListError::InvalidQueryList(message) => write!(f, "Invalid QueryList: {}", message),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    // Lowercased channel name or file path, without the file:// prefix
+    source: String,
+    is_file: bool,
+    // The path as written in the file, for opening .evtx files
+    original_path: String,
+    query: XPathQuery,
+}
+
+#[derive(Debug, Clone)]
+struct Query {
+    selects: Vec<Rule>,
+    suppresses: Vec<Rule>,
+}
+
+// A QueryList as saved by Event Viewer's custom views. An event is kept when, within
+// any one Query, a Select for its channel or file matches it and no Suppress for the
+// same source does.
+#[derive(Debug, Clone)]
+pub struct QueryList {
+    queries: Vec<Query>,
+}
+
+fn source_key(path: &str) -> String {
+    path.trim().to_lowercase()
+}
+
+impl QueryList {
+    pub fn from_file(path: &str) -> std::result::Result<Self, QueryListError> {
+        let xml = std::fs::read_to_string(path)?;
+        Self::parse(&xml)
+    }
+
+    pub fn parse(xml: &str) -> std::result::Result<Self, QueryListError> {
+        let root = Element::parse(xml.as_bytes())?;
+        if root.name != "QueryList" {
+            return Err(QueryListError::InvalidQueryList(format!("Root element is <{}>, not <QueryList>", root.name)));
+        }
+        let mut queries = Vec::new();
+        for query_element in Self::children(&root, "Query") {
+            let default_path = query_element.attributes.get("Path").cloned();
+            let mut query = Query { selects: Vec::new(), suppresses: Vec::new() };
+            for (kind, rules) in [("Select", &mut query.selects), ("Suppress", &mut query.suppresses)] {
+                for element in Self::children(query_element, kind) {
+                    let path = element.attributes.get("Path").cloned().or(default_path.clone())
+                        .ok_or(QueryListError::InvalidQueryList(format!("<{}> has no Path and neither does its <Query>", kind)))?;
+                    let text = element.get_text().unwrap_or_default().trim().to_string();
+                    let xpath = XPathQuery::parse(&text).map_err(|e| QueryListError::QueryError(text.clone(), e))?;
+                    let (original_path, is_file) = match path.strip_prefix("file://") {
+                        Some(file_path) => (file_path.to_string(), true),
+                        None => (path.clone(), false)
+                    };
+                    rules.push(Rule { source: source_key(&original_path), is_file: is_file, original_path: original_path, query: xpath });
+                }
+            }
+            if query.selects.is_empty() {
+                return Err(QueryListError::InvalidQueryList("A <Query> needs at least one <Select>".to_string()));
+            }
+            queries.push(query);
+        }
+        if queries.is_empty() {
+            return Err(QueryListError::InvalidQueryList("No <Query> elements".to_string()));
+        }
+        Ok(Self { queries: queries })
+    }
+
+    fn children<'a>(element: &'a Element, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        element.children.iter().filter_map(move |node| match node {
+            XMLNode::Element(child) if child.name == name => Some(child),
+            _ => None
+        })
+    }
+
+    fn selects(&self) -> impl Iterator<Item = &Rule> {
+        self.queries.iter().flat_map(|query| query.selects.iter())
+    }
+
+    // Live channels the Select rules read from, lowercased
+    pub fn channels(&self) -> HashSet<String> {
+        self.selects().filter(|rule| !rule.is_file).map(|rule| rule.source.clone()).collect()
+    }
+
+    // .evtx files the Select rules read from, as written in the file
+    pub fn files(&self) -> HashSet<String> {
+        self.selects().filter(|rule| rule.is_file).map(|rule| rule.original_path.clone()).collect()
+    }
+
+    // source is the channel name or file path the event was read from
+    pub fn matches(&self, source: &str, root: &Element, now: DateTime<Utc>) -> bool {
+        let source = source_key(source);
+        self.queries.iter().any(|query| {
+            let selected = query.selects.iter().any(|rule| rule.source == source && rule.query.matches(root, now));
+            selected && !query.suppresses.iter().any(|rule| rule.source == source && rule.query.matches(root, now))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use xmltree::Element;
+    use crate::query_list::QueryList;
+    use crate::test_support::TestEvent;
+
+    const QUERY_LIST: &str = r#"<QueryList>
+  <Query Id="0" Path="Security">
+    <Select Path="Security">*[System[(EventID=4624 or EventID=4625)]]</Select>
+    <Select Path="System">*[System[Level&lt;=3]]</Select>
+    <Suppress Path="Security">*[EventData[Data[@Name='LogonType']=5]]</Suppress>
+  </Query>
+  <Query Id="1" Path="file://C:\Cases\Archive-Security.evtx">
+    <Select>*[System[EventID=4688]]</Select>
+  </Query>
+</QueryList>"#;
+
+    fn event(event_id: u32, level: u8, logon_type: u8) -> Element {
+        let xml = TestEvent::default().event_id(event_id).level(level).data("LogonType", &logon_type.to_string()).xml();
+        Element::parse(xml.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_select_and_suppress() {
+        let query_list = QueryList::parse(QUERY_LIST).unwrap();
+        let now = Utc::now();
+        assert!(query_list.matches("Security", &event(4624, 0, 2), now));
+        assert!(query_list.matches("security", &event(4625, 0, 3), now));
+        // Service logons are suppressed in Security
+        assert!(!query_list.matches("Security", &event(4624, 0, 5), now));
+        assert!(!query_list.matches("Security", &event(4688, 0, 0), now));
+        assert!(query_list.matches("System", &event(7000, 2, 5), now));
+        assert!(!query_list.matches("System", &event(7036, 4, 0), now));
+        // The file query inherits its Path from the Query element
+        assert!(query_list.matches("C:\\Cases\\Archive-Security.evtx", &event(4688, 0, 0), now));
+        assert!(!query_list.matches("Application", &event(4624, 0, 2), now));
+    }
+
+    #[test]
+    fn test_sources() {
+        let query_list = QueryList::parse(QUERY_LIST).unwrap();
+        let mut channels: Vec<String> = query_list.channels().into_iter().collect();
+        channels.sort();
+        assert_eq!(channels, vec!["security", "system"]);
+        assert_eq!(query_list.files().into_iter().collect::<Vec<String>>(), vec!["C:\\Cases\\Archive-Security.evtx"]);
+    }
+
+    #[test]
+    fn test_invalid_query_lists() {
+        assert!(QueryList::parse("<Query />").is_err());
+        assert!(QueryList::parse("<QueryList><Query><Select>*</Select></Query></QueryList>").is_err());
+        assert!(QueryList::parse("<QueryList><Query Path='System'><Select>*[System[</Select></Query></QueryList>").is_err());
+        assert!(QueryList::parse("<QueryList><Query Path='System'><Suppress>*</Suppress></Query></QueryList>").is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use crate::glob::glob_matches;
+use crate::sigma::SigmaError;
+
+// A parsed Sigma condition. "1 of" and "all of" are resolved against the rule's
+// selection names when the condition is parsed, so evaluation never sees a pattern.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Selection(String),
+    Not(Box<Condition>),
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+    OneOf(Vec<String>),
+    AllOf(Vec<String>),
+}
+
+impl Condition {
+    // selected says whether the named selection matched the event
+    pub fn evaluate(&self, selected: &dyn Fn(&str) -> bool) -> bool {
+        match self {
+            Condition::Selection(name) => selected(name),
+            Condition::Not(inner) => !inner.evaluate(selected),
+            Condition::And(parts) => parts.iter().all(|part| part.evaluate(selected)),
+            Condition::Or(parts) => parts.iter().any(|part| part.evaluate(selected)),
+            Condition::OneOf(names) => names.iter().any(|name| selected(name)),
+            Condition::AllOf(names) => names.iter().all(|name| selected(name)),
+        }
+    }
+
+    // Grammar, loosest first:
+    //   or_expr   := and_expr ("or" and_expr)*
+    //   and_expr  := not_expr ("and" not_expr)*
+    //   not_expr  := "not" not_expr | primary
+    //   primary   := "(" or_expr ")" | ("1" | "any" | "all") "of" (pattern | "them") | name
+    pub fn parse(text: &str, selection_names: &[String]) -> std::result::Result<Self, SigmaError> {
+        if text.contains('|') {
+            return Err(SigmaError::new(format!("Aggregations in conditions aren't supported: '{}'", text)));
+        }
+        let spaced = text.replace('(', " ( ").replace(')', " ) ");
+        let tokens: Vec<&str> = spaced.split_whitespace().collect();
+        let mut parser = ConditionParser { tokens: tokens, position: 0, selection_names: selection_names };
+        let condition = parser.or_expr()?;
+        if let Some(token) = parser.peek() {
+            return Err(SigmaError::new(format!("Unexpected '{}' in condition '{}'", token, text)));
+        }
+        Ok(condition)
+    }
+}
+
+struct ConditionParser<'a> {
+    tokens: Vec<&'a str>,
+    position: usize,
+    selection_names: &'a [String],
+}
+
+impl<'a> ConditionParser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> std::result::Result<&'a str, SigmaError> {
+        let token = self.peek().ok_or(SigmaError::new("Condition ends early".to_string()))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn is_keyword(token: Option<&str>, keyword: &str) -> bool {
+        token.map(|token| token.eq_ignore_ascii_case(keyword)).unwrap_or(false)
+    }
+
+    fn or_expr(&mut self) -> std::result::Result<Condition, SigmaError> {
+        let mut parts = vec![self.and_expr()?];
+        while Self::is_keyword(self.peek(), "or") {
+            self.position += 1;
+            parts.push(self.and_expr()?);
+        }
+        Ok(if parts.len() == 1 { parts.remove(0) } else { Condition::Or(parts) })
+    }
+
+    fn and_expr(&mut self) -> std::result::Result<Condition, SigmaError> {
+        let mut parts = vec![self.not_expr()?];
+        while Self::is_keyword(self.peek(), "and") {
+            self.position += 1;
+            parts.push(self.not_expr()?);
+        }
+        Ok(if parts.len() == 1 { parts.remove(0) } else { Condition::And(parts) })
+    }
+
+    fn not_expr(&mut self) -> std::result::Result<Condition, SigmaError> {
+        if Self::is_keyword(self.peek(), "not") {
+            self.position += 1;
+            return Ok(Condition::Not(Box::new(self.not_expr()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> std::result::Result<Condition, SigmaError> {
+        let token = self.next()?;
+        if token == "(" {
+            let inner = self.or_expr()?;
+            if self.next()? != ")" {
+                return Err(SigmaError::new("Missing ')' in condition".to_string()));
+            }
+            return Ok(inner);
+        }
+        let quantifier = token.to_lowercase();
+        if (quantifier == "1" || quantifier == "any" || quantifier == "all") && Self::is_keyword(self.peek(), "of") {
+            self.position += 1;
+            let pattern = self.next()?;
+            let names = self.resolve_pattern(pattern)?;
+            return Ok(if quantifier == "all" { Condition::AllOf(names) } else { Condition::OneOf(names) });
+        }
+        if ["and", "or", "of", ")"].contains(&quantifier.as_str()) {
+            return Err(SigmaError::new(format!("Unexpected '{}' in condition", token)));
+        }
+        if !self.selection_names.iter().any(|name| name == token) {
+            return Err(SigmaError::new(format!("Condition refers to unknown selection '{}'", token)));
+        }
+        Ok(Condition::Selection(token.to_string()))
+    }
+
+    // "them" is every selection not starting with an underscore, anything else is a
+    // name where * matches any run of characters
+    fn resolve_pattern(&self, pattern: &str) -> std::result::Result<Vec<String>, SigmaError> {
+        let names: Vec<String> = if pattern.eq_ignore_ascii_case("them") {
+            self.selection_names.iter().filter(|name| !name.starts_with('_')).cloned().collect()
+        } else {
+            self.selection_names.iter().filter(|name| glob_matches(pattern, name)).cloned().collect()
+        };
+        if names.is_empty() {
+            return Err(SigmaError::new(format!("No selection matches '{}'", pattern)));
+        }
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sigma::condition::Condition;
+
+    fn names() -> Vec<String> {
+        ["selection_img", "selection_cli", "filter_main", "_helper"].iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_and_evaluate() {
+        let condition = Condition::parse("1 of selection_* and not filter_main", &names()).unwrap();
+        assert_eq!(condition, Condition::And(vec![
+            Condition::OneOf(vec!["selection_img".to_string(), "selection_cli".to_string()]),
+            Condition::Not(Box::new(Condition::Selection("filter_main".to_string()))),
+        ]));
+        assert!(condition.evaluate(&|name| name == "selection_cli"));
+        assert!(!condition.evaluate(&|name| name == "selection_cli" || name == "filter_main"));
+
+        // not binds tighter than and, and tighter than or
+        let condition = Condition::parse("selection_img or selection_cli and not (filter_main)", &names()).unwrap();
+        assert!(condition.evaluate(&|name| name == "selection_img" || name == "filter_main"));
+        assert!(!condition.evaluate(&|name| name == "selection_cli" || name == "filter_main"));
+
+        // them leaves out selections starting with an underscore
+        let condition = Condition::parse("all of them", &names()).unwrap();
+        assert!(condition.evaluate(&|name| name != "_helper"));
+    }
+
+    #[test]
+    fn test_invalid_conditions() {
+        assert!(Condition::parse("selection_nope", &names()).is_err());
+        assert!(Condition::parse("1 of nothing_*", &names()).is_err());
+        assert!(Condition::parse("(selection_img", &names()).is_err());
+        assert!(Condition::parse("selection_img and", &names()).is_err());
+        assert!(Condition::parse("selection_img | count() > 5", &names()).is_err());
+    }
+}
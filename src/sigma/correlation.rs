@@ -0,0 +1,366 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use chrono::{DateTime, Duration, Utc};
+use serde_yaml::Value;
+use crate::events::EvtEvent;
+use crate::sigma::rule::EventFields;
+use crate::sigma::{EventRef, SigmaConfig, SigmaError};
+use crate::time_filter::parse_duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CorrelationType {
+    // At least N hits in the window
+    EventCount,
+    // At least N distinct values of a field in the window
+    ValueCount,
+    // Every referenced rule hit in the window, in any order
+    Temporal,
+    // Every referenced rule hit in the window, in the order they're listed
+    TemporalOrdered,
+}
+
+impl CorrelationType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CorrelationType::EventCount => "event_count",
+            CorrelationType::ValueCount => "value_count",
+            CorrelationType::Temporal => "temporal",
+            CorrelationType::TemporalOrdered => "temporal_ordered",
+        }
+    }
+}
+
+// One hit of a referenced rule, already reduced to what this correlation groups on.
+// Hits are held until evaluate(), at a few hundred bytes each with the EventRef.
+#[derive(Debug, Clone)]
+struct Hit {
+    time: DateTime<Utc>,
+    // The entry of rules it came from
+    rule: String,
+    group: Vec<String>,
+    value: Option<String>,
+    events: Vec<EventRef>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CorrelationAlert {
+    pub group: Vec<(String, String)>,
+    pub first_time: DateTime<Utc>,
+    pub last_time: DateTime<Utc>,
+    pub events: Vec<EventRef>,
+}
+
+// A Sigma correlation rule. Hits of the rules it references are collected while the
+// events go by, and windowed once everything has been read, so the events don't have
+// to arrive in time order. A correlation can reference another correlation, whose
+// alerts then count as hits at the time of their last event.
+#[derive(Debug, Clone)]
+pub struct CorrelationRule {
+    pub id: String,
+    pub name: String,
+    pub title: String,
+    pub level: String,
+    pub tags: Vec<String>,
+    pub path: String,
+    pub correlation_type: CorrelationType,
+    // Whether the referenced rules still report their own hits
+    pub generate: bool,
+    pub rules: Vec<String>,
+    group_by: Vec<String>,
+    // Group field -> rule reference -> the field it's called in that rule's events
+    aliases: HashMap<String, HashMap<String, String>>,
+    timespan: Duration,
+    // Minimum count, and whether it has to be passed rather than reached
+    threshold: Option<(usize, bool)>,
+    value_field: Option<String>,
+    hits: Vec<Hit>,
+}
+
+fn string_list(value: Option<&Value>) -> Vec<String> {
+    match value {
+        Some(Value::Sequence(items)) => items.iter().filter_map(|item| item.as_str()).map(|item| item.to_string()).collect(),
+        Some(Value::String(item)) => vec![item.to_string()],
+        _ => Vec::new()
+    }
+}
+
+impl CorrelationRule {
+    pub fn from_yaml(document: &Value, path: &str) -> std::result::Result<Self, SigmaError> {
+        let text = |key: &str| document.get(key).and_then(|value| value.as_str()).unwrap_or_default().to_string();
+        let correlation = document.get("correlation").ok_or(SigmaError::new("Missing correlation".to_string()))?;
+        let correlation_type = match correlation.get("type").and_then(|value| value.as_str()) {
+            Some("event_count") => CorrelationType::EventCount,
+            Some("value_count") => CorrelationType::ValueCount,
+            Some("temporal") => CorrelationType::Temporal,
+            Some("temporal_ordered") => CorrelationType::TemporalOrdered,
+            other => return Err(SigmaError::new(format!("Unknown correlation type {:?}", other)))
+        };
+        let rules = string_list(correlation.get("rules"));
+        if rules.is_empty() {
+            return Err(SigmaError::new("Correlation has no rules".to_string()));
+        }
+        let timespan = correlation.get("timespan").and_then(|value| value.as_str())
+            .ok_or(SigmaError::new("Correlation has no timespan".to_string()))
+            .and_then(|value| parse_duration(value).map_err(SigmaError::new))?;
+
+        let mut aliases = HashMap::new();
+        if let Some(Value::Mapping(alias_map)) = correlation.get("aliases") {
+            for (alias, per_rule) in alias_map {
+                let per_rule: HashMap<String, String> = serde_yaml::from_value(per_rule.clone())
+                    .map_err(|e| SigmaError::new(format!("Bad alias {:?}: {}", alias, e)))?;
+                aliases.insert(alias.as_str().unwrap_or_default().to_string(), per_rule);
+            }
+        }
+
+        let condition = correlation.get("condition");
+        let number = |key: &str| condition.and_then(|condition| condition.get(key)).and_then(|value| value.as_u64()).map(|value| value as usize);
+        let threshold = match (number("gte"), number("gt")) {
+            (Some(count), _) => Some((count, false)),
+            (None, Some(count)) => Some((count, true)),
+            (None, None) => None
+        };
+        let value_field = condition.and_then(|condition| condition.get("field")).and_then(|value| value.as_str()).map(|value| value.to_string());
+        match correlation_type {
+            CorrelationType::EventCount | CorrelationType::ValueCount if threshold.is_none() => {
+                return Err(SigmaError::new("Count correlations need a gte or gt condition, other comparisons aren't supported".to_string()));
+            },
+            CorrelationType::ValueCount if value_field.is_none() => {
+                return Err(SigmaError::new("value_count needs a field in its condition".to_string()));
+            },
+            _ => ()
+        }
+
+        Ok(Self {
+            id: text("id"),
+            name: text("name"),
+            title: text("title"),
+            level: text("level"),
+            tags: string_list(document.get("tags")),
+            path: path.to_string(),
+            correlation_type: correlation_type,
+            generate: correlation.get("generate").and_then(|value| value.as_bool()).unwrap_or(false),
+            rules: rules,
+            group_by: string_list(correlation.get("group-by")),
+            aliases: aliases,
+            timespan: timespan,
+            threshold: threshold,
+            value_field: value_field,
+            hits: Vec::new(),
+        })
+    }
+
+    // The names other correlations can reference this one by
+    pub fn references(&self) -> Vec<&str> {
+        [self.name.as_str(), self.id.as_str()].into_iter().filter(|reference| !reference.is_empty()).collect()
+    }
+
+    // The entry of rules that points at a rule known by any of these references
+    pub fn matching_reference(&self, references: &[&str]) -> Option<String> {
+        self.rules.iter().find(|rule| references.contains(&rule.as_str())).cloned()
+    }
+
+    fn field_name<'a>(&'a self, field: &'a str, rule: &str) -> &'a str {
+        self.aliases.get(field).and_then(|per_rule| per_rule.get(rule)).map(|name| name.as_str()).unwrap_or(field)
+    }
+
+    pub fn record_event(&mut self, references: &[&str], event: &EvtEvent, fields: &EventFields, config: &SigmaConfig) {
+        let (Some(rule), Some(time)) = (self.matching_reference(references), event.get_system().get_time_created()) else { return };
+        let lookup = |field: &str| {
+            let names: Vec<String> = config.mapped_fields(self.field_name(field, &rule)).iter().map(|name| name.to_lowercase()).collect();
+            fields.first_value(&names).unwrap_or_default().to_string()
+        };
+        let group = self.group_by.iter().map(|field| lookup(field)).collect();
+        let value = self.value_field.as_ref().map(|field| lookup(field));
+        self.hits.push(Hit { time: time, rule: rule, group: group, value: value, events: vec![EventRef::from_event(event)] });
+    }
+
+    // An alert of another correlation, its group fields stand in for event fields
+    pub fn record_alert(&mut self, references: &[&str], alert: &CorrelationAlert) {
+        let Some(rule) = self.matching_reference(references) else { return };
+        let lookup = |field: &str| {
+            let name = self.field_name(field, &rule);
+            alert.group.iter().find(|(group_field, _)| group_field.eq_ignore_ascii_case(name)).map(|(_, value)| value.to_string()).unwrap_or_default()
+        };
+        let group = self.group_by.iter().map(|field| lookup(field)).collect();
+        let value = self.value_field.as_ref().map(|field| lookup(field));
+        self.hits.push(Hit { time: alert.last_time, rule: rule, group: group, value: value, events: alert.events.clone() });
+    }
+
+    fn reached(&self, count: usize) -> bool {
+        match self.threshold {
+            Some((threshold, true)) => count > threshold,
+            Some((threshold, false)) => count >= threshold,
+            None => false
+        }
+    }
+
+    // The hits that complete the pattern once the newest hit is in the window, if any
+    fn completed(&self, window: &VecDeque<Hit>) -> Option<Vec<usize>> {
+        match self.correlation_type {
+            CorrelationType::EventCount => self.reached(window.len()).then(|| (0..window.len()).collect()),
+            CorrelationType::ValueCount => {
+                let distinct: HashSet<&str> = window.iter().filter_map(|hit| hit.value.as_deref()).filter(|value| !value.is_empty()).collect();
+                self.reached(distinct.len()).then(|| (0..window.len()).collect())
+            },
+            CorrelationType::Temporal => {
+                let all_seen = self.rules.iter().all(|rule| window.iter().any(|hit| &hit.rule == rule));
+                all_seen.then(|| (0..window.len()).collect())
+            },
+            CorrelationType::TemporalOrdered => {
+                // Earliest hit of each rule after the previous one, and the newest hit has to finish it
+                let mut matched = Vec::new();
+                let mut next = 0;
+                for rule in &self.rules {
+                    let found = (next..window.len()).find(|&index| &window[index].rule == rule)?;
+                    matched.push(found);
+                    next = found + 1;
+                }
+                (matched.last() == Some(&(window.len() - 1))).then_some(matched)
+            },
+        }
+    }
+
+    // Windows the collected hits per group. Once a window completes it's emptied, so
+    // the same hits never make up two alerts.
+    pub fn evaluate(&mut self) -> Vec<CorrelationAlert> {
+        let mut hits = std::mem::take(&mut self.hits);
+        hits.sort_by_key(|hit| hit.time);
+        let mut groups: HashMap<Vec<String>, Vec<Hit>> = HashMap::new();
+        let mut group_order = Vec::new();
+        for hit in hits {
+            if !groups.contains_key(&hit.group) {
+                group_order.push(hit.group.clone());
+            }
+            groups.entry(hit.group.clone()).or_default().push(hit);
+        }
+
+        let mut alerts = Vec::new();
+        for group in group_order {
+            let mut window: VecDeque<Hit> = VecDeque::new();
+            for hit in groups.remove(&group).unwrap_or_default() {
+                let start = hit.time - self.timespan;
+                window.push_back(hit);
+                while window.front().map(|oldest| oldest.time < start).unwrap_or(false) {
+                    window.pop_front();
+                }
+                if let Some(indexes) = self.completed(&window) {
+                    let contributing: Vec<&Hit> = indexes.iter().map(|&index| &window[index]).collect();
+                    alerts.push(CorrelationAlert {
+                        group: self.group_by.iter().cloned().zip(group.iter().cloned()).collect(),
+                        first_time: contributing.first().map(|hit| hit.time).unwrap_or_default(),
+                        last_time: contributing.last().map(|hit| hit.time).unwrap_or_default(),
+                        events: contributing.iter().flat_map(|hit| hit.events.iter().cloned()).collect(),
+                    });
+                    window.clear();
+                }
+            }
+        }
+        alerts.sort_by_key(|alert| alert.last_time);
+        alerts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::EvtEvent;
+    use crate::sigma::{SigmaConfig, SigmaEngine};
+    use crate::test_support::{temp_path, TestEvent};
+
+    const RULES: &str = r#"title: Failed logon
+name: failed_logon
+logsource:
+  product: windows
+  service: security
+detection:
+  selection:
+    EventID: 4625
+  condition: selection
+---
+title: Successful logon
+name: successful_logon
+logsource:
+  product: windows
+  service: security
+detection:
+  selection:
+    EventID: 4624
+  condition: selection
+---
+title: Repeated failed logons for one account
+name: many_failures
+correlation:
+  type: event_count
+  rules: failed_logon
+  group-by: [TargetUserName]
+  timespan: 5m
+  condition:
+    gte: 3
+---
+title: Password spray from one address
+correlation:
+  type: value_count
+  rules: [failed_logon]
+  group-by: [IpAddress]
+  timespan: 10m
+  condition:
+    field: TargetUserName
+    gte: 3
+---
+title: Brute force followed by a successful logon
+level: critical
+correlation:
+  type: temporal_ordered
+  rules: [many_failures, successful_logon]
+  group-by: [user]
+  aliases:
+    user:
+      many_failures: TargetUserName
+      successful_logon: TargetUserName
+  timespan: 10m
+"#;
+
+    fn logon(event_id: u32, record_id: u64, second: u32, user: &str, address: &str) -> EvtEvent {
+        TestEvent::default().provider("Microsoft-Windows-Security-Auditing").event_id(event_id)
+            .time(&format!("2023-05-01T12:{:02}:{:02}.0000000Z", second / 60, second % 60)).record_id(record_id).channel("Security").computer("DC01")
+            .data("TargetUserName", user).data("IpAddress", address).build()
+    }
+
+    #[test]
+    fn test_correlations() {
+        let dir = temp_path("correlation");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("rules.yml"), RULES).unwrap();
+        let mut engine = SigmaEngine::load(dir.to_str().unwrap(), &SigmaConfig::load(None).unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Out of time order on purpose: alice fails three times in a minute then logs
+        // on, bob fails twice, and 10.0.0.9 tries three different accounts
+        let events = vec![
+            logon(4624, 6, 90, "alice", "10.0.0.5"),
+            logon(4625, 3, 40, "alice", "10.0.0.5"),
+            logon(4625, 1, 0, "alice", "10.0.0.5"),
+            logon(4625, 2, 20, "alice", "10.0.0.5"),
+            logon(4625, 4, 30, "bob", "10.0.0.5"),
+            logon(4625, 5, 50, "bob", "10.0.0.5"),
+            logon(4625, 7, 100, "carol", "10.0.0.9"),
+            logon(4625, 8, 110, "dave", "10.0.0.9"),
+            logon(4625, 9, 120, "erin", "10.0.0.9"),
+        ];
+        for event in &events {
+            // Both logon rules are only referenced by correlations, so they don't report on their own
+            assert!(engine.evaluate(event).is_empty());
+        }
+        let alerts = engine.correlate();
+        let summary: Vec<(&str, Vec<u64>)> = alerts.iter()
+            .map(|(rule, alert)| (rule.title.as_str(), alert.events.iter().map(|event| event.record_id).collect()))
+            .collect();
+        // many_failures feeds the temporal_ordered rule, so it isn't reported either.
+        // 10.0.0.5 only tried two accounts.
+        assert_eq!(summary, vec![
+            ("Brute force followed by a successful logon", vec![1, 2, 3, 6]),
+            ("Password spray from one address", vec![7, 8, 9]),
+        ]);
+        let brute_force = &alerts[0].1;
+        assert_eq!(brute_force.group, vec![("user".to_string(), "alice".to_string())]);
+        assert_eq!(brute_force.first_time.to_rfc3339(), "2023-05-01T12:00:40+00:00");
+    }
+}
@@ -0,0 +1,463 @@
+pub mod condition;
+pub mod correlation;
+pub mod rule;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use crate::events::EvtEvent;
+use crate::sigma::correlation::{CorrelationAlert, CorrelationRule};
+use crate::sigma::rule::{EventFields, Selection, SigmaRule};
+
+const DEFAULT_CONFIG: &str = include_str!("sigma_config.yml");
+
+#[derive(Debug)]
+pub struct SigmaError {
+    message: String,
+}
+
+impl SigmaError {
+    pub fn new(message: String) -> Self {
+        Self { message: message }
+    }
+}
+
+impl std::error::Error for SigmaError {}
+
+impl std::fmt::Display for SigmaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum FieldMapping {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct LogsourceMapping {
+    category: Option<String>,
+    service: Option<String>,
+    conditions: Value,
+}
+
+impl LogsourceMapping {
+    // Every part the mapping names has to match the rule's logsource
+    fn covers(&self, category: &Option<String>, service: &Option<String>) -> bool {
+        let part_matches = |mapped: &Option<String>, wanted: &Option<String>| match mapped {
+            Some(mapped) => wanted.as_ref().map(|wanted| mapped.eq_ignore_ascii_case(wanted)).unwrap_or(false),
+            None => true
+        };
+        (self.category.is_some() || self.service.is_some()) && part_matches(&self.category, category) && part_matches(&self.service, service)
+    }
+}
+
+// Field name and logsource mappings. See sigma_config.yml for the layout.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SigmaConfig {
+    #[serde(default)]
+    fieldmappings: HashMap<String, FieldMapping>,
+    #[serde(default)]
+    logsources: Vec<LogsourceMapping>,
+}
+
+impl SigmaConfig {
+    pub fn load(config_path: Option<&str>) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let mut config: SigmaConfig = serde_yaml::from_str(DEFAULT_CONFIG)?;
+        if let Some(path) = config_path {
+            let custom: SigmaConfig = serde_yaml::from_str(&fs::read_to_string(path)?)?;
+            config.fieldmappings.extend(custom.fieldmappings);
+            config.logsources.retain(|mapping| !custom.logsources.iter().any(|replacement| {
+                replacement.category == mapping.category && replacement.service == mapping.service
+            }));
+            config.logsources.extend(custom.logsources);
+        }
+        Ok(config)
+    }
+
+    pub fn mapped_fields(&self, field: &str) -> Vec<String> {
+        match self.fieldmappings.get(field) {
+            Some(FieldMapping::One(name)) => vec![name.to_string()],
+            Some(FieldMapping::Many(names)) => names.clone(),
+            None => vec![field.to_string()]
+        }
+    }
+
+    // The events a logsource covers, or None when it names neither a category nor a
+    // service and every event is looked at. A category and a service each narrow the
+    // events, so a rule naming both only sees events both map to, unless a mapping
+    // names the pair itself. A part nothing maps is an error, the rule would
+    // otherwise run on every event.
+    pub fn logsource_selection(&self, category: Option<String>, service: Option<String>) -> std::result::Result<Option<Selection>, SigmaError> {
+        if category.is_none() && service.is_none() {
+            return Ok(None);
+        }
+        // Mapping conditions name event fields directly, so they skip the field mappings
+        let unmapped = SigmaConfig::default();
+        let compile = |mappings: Vec<&LogsourceMapping>| -> std::result::Result<Selection, SigmaError> {
+            let selections = mappings.iter()
+                .map(|mapping| Selection::compile(&mapping.conditions, &unmapped))
+                .collect::<std::result::Result<Vec<Selection>, SigmaError>>()?;
+            Ok(Selection::AnyOf(selections))
+        };
+        let pair: Vec<&LogsourceMapping> = self.logsources.iter()
+            .filter(|mapping| mapping.category.is_some() && mapping.service.is_some() && mapping.covers(&category, &service))
+            .collect();
+        if !pair.is_empty() {
+            return compile(pair).map(Some);
+        }
+        let mut parts = Vec::new();
+        if let Some(name) = &category {
+            let mappings: Vec<&LogsourceMapping> = self.logsources.iter()
+                .filter(|mapping| mapping.service.is_none() && mapping.covers(&category, &None))
+                .collect();
+            if mappings.is_empty() {
+                return Err(SigmaError::new(format!("Logsource category '{}' isn't mapped", name)));
+            }
+            parts.push(compile(mappings)?);
+        }
+        if let Some(name) = &service {
+            let mappings: Vec<&LogsourceMapping> = self.logsources.iter()
+                .filter(|mapping| mapping.category.is_none() && mapping.covers(&None, &service))
+                .collect();
+            if mappings.is_empty() {
+                return Err(SigmaError::new(format!("Logsource service '{}' isn't mapped", name)));
+            }
+            parts.push(compile(mappings)?);
+        }
+        if parts.len() == 1 {
+            return Ok(parts.pop());
+        }
+        Ok(Some(Selection::AllOf(parts)))
+    }
+}
+
+pub struct SigmaEngine {
+    rules: Vec<SigmaRule>,
+    correlations: Vec<CorrelationRule>,
+    config: SigmaConfig,
+}
+
+impl SigmaEngine {
+    // Loads every .yml/.yaml file under rules_path, which can also be a single file.
+    // Rules that can't be used are reported and skipped rather than failing the run.
+    pub fn load(rules_path: &str, config: &SigmaConfig) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let mut files = Vec::new();
+        Self::collect_rule_files(Path::new(rules_path), &mut files)?;
+        files.sort();
+        let mut rules = Vec::new();
+        let mut correlations = Vec::new();
+        for file in files {
+            let path = file.to_string_lossy().to_string();
+            let text = fs::read_to_string(&file)?;
+            for document in serde_yaml::Deserializer::from_str(&text) {
+                let parsed = Value::deserialize(document).map_err(|e| SigmaError::new(e.to_string())).and_then(|document| {
+                    if document.get("correlation").is_some() {
+                        CorrelationRule::from_yaml(&document, &path).map(|correlation| correlations.push(correlation))
+                    } else {
+                        SigmaRule::from_yaml(&document, &path, config).map(|rule| rules.push(rule))
+                    }
+                });
+                if let Err(e) = parsed {
+                    println!("Skipping Sigma rule in {}: {}", path, e);
+                }
+            }
+        }
+        let correlations = Self::order_correlations(&rules, correlations);
+        Ok(Self { rules: rules, correlations: correlations, config: config.clone() })
+    }
+
+    // Puts every correlation after the correlations it references, so their alerts
+    // are ready as hits by the time it's evaluated. Correlations referencing a rule
+    // that wasn't loaded, or stuck in a cycle, are reported and dropped.
+    fn order_correlations(rules: &[SigmaRule], mut pending: Vec<CorrelationRule>) -> Vec<CorrelationRule> {
+        let mut ordered: Vec<CorrelationRule> = Vec::new();
+        loop {
+            let ready = pending.iter().position(|correlation| correlation.rules.iter().all(|reference| {
+                rules.iter().any(|rule| rule.references().contains(&reference.as_str()))
+                    || ordered.iter().any(|done| done.references().contains(&reference.as_str()))
+            }));
+            match ready {
+                Some(index) => ordered.push(pending.remove(index)),
+                None => break
+            }
+        }
+        for correlation in pending {
+            println!("Skipping Sigma correlation '{}' in {}: it references rules that weren't loaded or refer back to it", correlation.title, correlation.path);
+        }
+        ordered
+    }
+
+    fn collect_rule_files(path: &Path, files: &mut Vec<std::path::PathBuf>) -> std::io::Result<()> {
+        if path.is_dir() {
+            for entry in fs::read_dir(path)? {
+                Self::collect_rule_files(&entry?.path(), files)?;
+            }
+        } else if path.extension().map(|extension| extension == "yml" || extension == "yaml").unwrap_or(false) {
+            files.push(path.to_path_buf());
+        }
+        Ok(())
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules.len() + self.correlations.len()
+    }
+
+    // Rules only used by correlations stay quiet unless one of those asks for them
+    fn generates(&self, references: &[&str]) -> bool {
+        let referencing: Vec<&CorrelationRule> = self.correlations.iter()
+            .filter(|correlation| correlation.matching_reference(references).is_some())
+            .collect();
+        referencing.is_empty() || referencing.iter().any(|correlation| correlation.generate)
+    }
+
+    // Returns the rules that matched and should be reported. Hits of rules that
+    // correlations reference are kept for correlate().
+    pub fn evaluate(&mut self, event: &EvtEvent) -> Vec<&SigmaRule> {
+        let fields = EventFields::from_event(event);
+        let mut reported = Vec::new();
+        for rule in &self.rules {
+            if !rule.matches(&fields) {
+                continue;
+            }
+            let references = rule.references();
+            for correlation in self.correlations.iter_mut() {
+                correlation.record_event(&references, event, &fields, &self.config);
+            }
+            if self.generates(&references) {
+                reported.push(rule);
+            }
+        }
+        reported
+    }
+
+    // Runs the correlations over everything evaluate() collected
+    pub fn correlate(&mut self) -> Vec<(&CorrelationRule, CorrelationAlert)> {
+        let mut alerts: Vec<(usize, CorrelationAlert)> = Vec::new();
+        for index in 0..self.correlations.len() {
+            let new_alerts = self.correlations[index].evaluate();
+            let references: Vec<String> = self.correlations[index].references().iter().map(|reference| reference.to_string()).collect();
+            let references: Vec<&str> = references.iter().map(|reference| reference.as_str()).collect();
+            for later in index + 1..self.correlations.len() {
+                for alert in &new_alerts {
+                    self.correlations[later].record_alert(&references, alert);
+                }
+            }
+            if self.generates(&references) {
+                alerts.extend(new_alerts.into_iter().map(|alert| (index, alert)));
+            }
+        }
+        alerts.sort_by_key(|(_, alert)| alert.last_time);
+        alerts.into_iter().map(|(index, alert)| (&self.correlations[index], alert)).collect()
+    }
+}
+
+// Where an event that made up a detection came from
+#[derive(Debug, Clone, Serialize)]
+pub struct EventRef {
+    pub record_id: u64,
+    pub source: String,
+    pub time_created: String,
+    pub computer: String,
+    pub channel: String,
+    pub provider: String,
+    pub event_id: u32,
+}
+
+impl EventRef {
+    pub fn from_event(event: &EvtEvent) -> Self {
+        let system = event.get_system();
+        Self {
+            record_id: event.get_record_id(),
+            source: event.get_source().to_string(),
+            time_created: system.get_system_time().to_string(),
+            computer: system.get_computer().to_string(),
+            channel: system.get_channel().to_string(),
+            provider: system.get_provider_name().to_string(),
+            event_id: system.get_event_id(),
+        }
+    }
+}
+
+// One line of the detections report
+#[derive(Serialize)]
+struct Detection<'a> {
+    rule_id: &'a str,
+    rule_title: &'a str,
+    rule_level: &'a str,
+    rule_tags: &'a [String],
+    rule_path: &'a str,
+    #[serde(flatten)]
+    event: EventRef,
+}
+
+// A correlation alert in the same report, listing every event that made it up
+#[derive(Serialize)]
+struct CorrelationDetection<'a> {
+    rule_id: &'a str,
+    rule_title: &'a str,
+    rule_level: &'a str,
+    rule_tags: &'a [String],
+    rule_path: &'a str,
+    correlation_type: &'a str,
+    group: serde_json::Map<String, serde_json::Value>,
+    first_time: String,
+    last_time: String,
+    event_count: usize,
+    events: &'a [EventRef],
+}
+
+// Rule hits as JSON lines, kept apart from the event output. Each line points back at
+// the event by record ID and the channel or file it was read from.
+pub struct DetectionReport {
+    writer: BufWriter<File>,
+    count: usize,
+}
+
+impl DetectionReport {
+    pub fn new(path: &str) -> std::io::Result<Self> {
+        Ok(Self { writer: BufWriter::new(File::create(path)?), count: 0 })
+    }
+
+    pub fn write(&mut self, rule: &SigmaRule, event: &EvtEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let detection = Detection {
+            rule_id: &rule.id,
+            rule_title: &rule.title,
+            rule_level: &rule.level,
+            rule_tags: &rule.tags,
+            rule_path: &rule.path,
+            event: EventRef::from_event(event),
+        };
+        self.write_line(&detection)
+    }
+
+    pub fn write_alert(&mut self, rule: &CorrelationRule, alert: &CorrelationAlert) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let detection = CorrelationDetection {
+            rule_id: &rule.id,
+            rule_title: &rule.title,
+            rule_level: &rule.level,
+            rule_tags: &rule.tags,
+            rule_path: &rule.path,
+            correlation_type: rule.correlation_type.name(),
+            group: alert.group.iter().map(|(field, value)| (field.to_string(), serde_json::Value::from(value.as_str()))).collect(),
+            first_time: alert.first_time.to_rfc3339(),
+            last_time: alert.last_time.to_rfc3339(),
+            event_count: alert.events.len(),
+            events: &alert.events,
+        };
+        self.write_line(&detection)
+    }
+
+    fn write_line<T: Serialize>(&mut self, line: &T) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        serde_json::to_writer(&mut self.writer, line)?;
+        self.writer.write_all(b"\n")?;
+        self.count += 1;
+        Ok(())
+    }
+
+    // Returns how many detections were written
+    pub fn finish(&mut self) -> std::io::Result<usize> {
+        self.writer.flush()?;
+        Ok(self.count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::EvtEvent;
+    use crate::sigma::{DetectionReport, SigmaConfig, SigmaEngine};
+    use crate::test_support::{temp_path, TestEvent};
+
+    const RULES: &str = r#"title: Encoded PowerShell
+id: 5b6e7a10-0000-4000-8000-000000000001
+level: high
+tags:
+  - attack.execution
+  - attack.t1059.001
+logsource:
+  category: process_creation
+  product: windows
+detection:
+  selection_img:
+    Image|endswith: '\powershell.exe'
+  selection_cli:
+    CommandLine|contains: [' -enc ', ' -encodedcommand ']
+  filter_admin:
+    User: 'CORP\svc_admin'
+  condition: all of selection_* and not filter_admin
+---
+title: Linux only
+logsource:
+  product: linux
+detection:
+  selection:
+    Image: '/bin/bash'
+  condition: selection
+---
+title: Unmapped logsource
+logsource:
+  product: windows
+  category: registry_tampering
+detection:
+  selection:
+    Image|endswith: '\powershell.exe'
+  condition: selection
+---
+title: PowerShell started from Sysmon
+logsource:
+  product: windows
+  category: process_creation
+  service: sysmon
+detection:
+  selection:
+    Image|endswith: '\powershell.exe'
+  condition: selection
+"#;
+
+    fn event(channel: &str, image_field: &str, command_line: &str, user: &str) -> EvtEvent {
+        TestEvent::default().provider("Test").event_id(if channel == "Security" { 4688 } else { 1 }).time("2023-05-01T12:00:00.0000000Z")
+            .record_id(42).channel(channel).computer("WS01")
+            .data(image_field, r"C:\Windows\System32\WindowsPowerShell\v1.0\powershell.exe").data("CommandLine", command_line).data("SubjectUserName", user)
+            .source(r"C:\Cases\host.evtx").build()
+    }
+
+    #[test]
+    fn test_engine_and_report() {
+        let dir = temp_path("sigma");
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("nested").join("rules.yml"), RULES).unwrap();
+        let mut engine = SigmaEngine::load(dir.to_str().unwrap(), &SigmaConfig::load(None).unwrap()).unwrap();
+        // The linux rule and the one with an unmapped logsource are skipped
+        assert_eq!(engine.rule_count(), 2);
+
+        let sysmon = event("Microsoft-Windows-Sysmon/Operational", "Image", "powershell -enc SQBFAFgA", "alice");
+        // Security 4688 names the image NewProcessName, the field mapping covers it
+        let security = event("Security", "NewProcessName", "powershell -EncodedCommand SQBFAFgA", "bob");
+        let filtered = event("Security", "NewProcessName", "powershell -enc SQBFAFgA", "CORP\\svc_admin");
+        let wrong_channel = event("Application", "Image", "powershell -enc SQBFAFgA", "alice");
+        // process_creation and sysmon together only cover Sysmon event 1
+        assert_eq!(engine.evaluate(&sysmon).len(), 2);
+        assert_eq!(engine.evaluate(&security).len(), 1);
+        assert!(engine.evaluate(&filtered).is_empty());
+        assert!(engine.evaluate(&wrong_channel).is_empty());
+
+        let report_path = dir.join("detections.jsonl");
+        let mut report = DetectionReport::new(report_path.to_str().unwrap()).unwrap();
+        for rule in engine.evaluate(&sysmon) {
+            report.write(rule, &sysmon).unwrap();
+        }
+        assert_eq!(report.finish().unwrap(), 2);
+        let line: serde_json::Value = serde_json::from_str(std::fs::read_to_string(&report_path).unwrap().lines().next().unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(line["rule_title"], "Encoded PowerShell");
+        assert_eq!(line["record_id"], 42);
+        assert_eq!(line["source"], "C:\\Cases\\host.evtx");
+        assert_eq!(line["rule_tags"][1], "attack.t1059.001");
+    }
+}
@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ipnet::IpNet;
+use regex::{Regex, RegexBuilder};
+use serde_yaml::{Mapping, Value};
+use crate::events::EvtEvent;
+use crate::sigma::condition::Condition;
+use crate::sigma::{SigmaConfig, SigmaError};
+
+// Everything a rule can look at in one event, keyed by lowercased field name. System
+// fields use the names Sigma's Windows rules expect, EventData and UserData keep theirs.
+pub struct EventFields {
+    fields: HashMap<String, Vec<String>>,
+}
+
+impl EventFields {
+    pub fn from_event(event: &EvtEvent) -> Self {
+        let system = event.get_system();
+        let mut fields: HashMap<String, Vec<String>> = HashMap::new();
+        let mut add = |name: &str, value: String| fields.entry(name.to_lowercase()).or_default().push(value);
+        add("EventID", system.get_event_id().to_string());
+        add("Provider_Name", system.get_provider_name().to_string());
+        add("Channel", system.get_channel().to_string());
+        add("Computer", system.get_computer().to_string());
+        add("Level", system.get_level().to_string());
+        add("Task", system.get_task().to_string());
+        add("Opcode", system.get_opcode().to_string());
+        add("Keywords", format!("0x{:x}", system.get_keywords()));
+        add("Version", system.get_version().to_string());
+        add("EventRecordID", system.get_record_id().to_string());
+        add("TimeCreated", system.get_system_time().to_string());
+        if let Some(guid) = system.get_provider_guid() {
+            add("Provider_Guid", guid.to_string());
+        }
+        if let Some(process_id) = system.get_process_id() {
+            add("ProcessID", process_id.to_string());
+        }
+        if let Some(thread_id) = system.get_thread_id() {
+            add("ThreadID", thread_id.to_string());
+        }
+        if let Some(user_id) = system.get_user_id() {
+            add("UserID", user_id.to_string());
+        }
+        for (name, value) in event.get_event_data() {
+            add(name, value.to_string());
+        }
+        Self { fields: fields }
+    }
+
+    fn get(&self, name: &str) -> Option<&Vec<String>> {
+        self.fields.get(name)
+    }
+
+    // First value of the first of these lowercased field names the event has
+    pub fn first_value(&self, names: &[String]) -> Option<&str> {
+        names.iter().filter_map(|name| self.get(name)).flatten().next().map(|value| value.as_str())
+    }
+
+    fn values(&self) -> impl Iterator<Item = &String> {
+        self.fields.values().flatten()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ValueMatcher {
+    // null in the rule, the field has to be missing or empty
+    Null,
+    // No wildcards left, compared case-insensitively
+    Exact(String),
+    Wildcard(Regex),
+    Regex(Regex),
+    Cidr(IpNet),
+}
+
+impl ValueMatcher {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            ValueMatcher::Null => value.is_empty(),
+            ValueMatcher::Exact(expected) => value.to_lowercase() == *expected,
+            ValueMatcher::Wildcard(regex) | ValueMatcher::Regex(regex) => regex.is_match(value),
+            ValueMatcher::Cidr(network) => value.trim().parse::<IpAddr>().map(|address| network.contains(&address)).unwrap_or(false),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Literal(char),
+    AnyRun,
+    AnyOne,
+}
+
+// Sigma wildcards: * and ? unless escaped with a backslash, \\ for a literal backslash.
+// Any other backslash is just a backslash, so Windows paths don't need escaping.
+fn wildcard_pieces(value: &str) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                Some(&next) if next == '*' || next == '?' || next == '\\' => {
+                    pieces.push(Piece::Literal(next));
+                    chars.next();
+                },
+                _ => pieces.push(Piece::Literal('\\'))
+            },
+            '*' => pieces.push(Piece::AnyRun),
+            '?' => pieces.push(Piece::AnyOne),
+            other => pieces.push(Piece::Literal(other))
+        }
+    }
+    pieces
+}
+
+fn literal_pieces(value: &str) -> Vec<Piece> {
+    value.chars().map(Piece::Literal).collect()
+}
+
+fn compile_pieces(pieces: Vec<Piece>) -> std::result::Result<ValueMatcher, SigmaError> {
+    if pieces.iter().all(|piece| matches!(piece, Piece::Literal(_))) {
+        let text: String = pieces.iter().map(|piece| match piece { Piece::Literal(c) => *c, _ => ' ' }).collect();
+        return Ok(ValueMatcher::Exact(text.to_lowercase()));
+    }
+    let mut pattern = String::from("^");
+    for piece in pieces {
+        match piece {
+            Piece::Literal(c) => pattern.push_str(&regex::escape(&c.to_string())),
+            Piece::AnyRun => pattern.push_str(".*"),
+            Piece::AnyOne => pattern.push('.'),
+        }
+    }
+    pattern.push('$');
+    let regex = RegexBuilder::new(&pattern).case_insensitive(true).dot_matches_new_line(true).build()
+        .map_err(|e| SigmaError::new(e.to_string()))?;
+    Ok(ValueMatcher::Wildcard(regex))
+}
+
+// The three ways a value can show up inside a longer base64 string, depending on
+// where it falls relative to the 3 byte groups. Same offsets as the Sigma reference.
+fn base64_offsets(value: &str) -> Vec<String> {
+    let start_offsets = [0, 2, 3];
+    let end_trims = [0, 3, 2];
+    (0..3).map(|i| {
+        let mut bytes = vec![b' '; i];
+        bytes.extend_from_slice(value.as_bytes());
+        let encoded = BASE64.encode(&bytes);
+        let end = encoded.len() - end_trims[(value.len() + i) % 3];
+        encoded[start_offsets[i]..end].to_string()
+    }).collect()
+}
+
+fn scalar_text(value: &Value) -> std::result::Result<Option<String>, SigmaError> {
+    match value {
+        Value::Null => Ok(None),
+        Value::String(text) => Ok(Some(text.to_string())),
+        Value::Number(number) => Ok(Some(number.to_string())),
+        Value::Bool(flag) => Ok(Some(flag.to_string())),
+        other => Err(SigmaError::new(format!("Expected a plain value, got {:?}", other)))
+    }
+}
+
+// The values under one "Field|modifier|..." key
+#[derive(Debug, Clone)]
+pub struct FieldMatcher {
+    // Event fields to look in, several when the field mapping gives alternatives
+    fields: Vec<String>,
+    matchers: Vec<ValueMatcher>,
+    all: bool,
+}
+
+impl FieldMatcher {
+    fn compile(key: &str, values: &Value, config: &SigmaConfig) -> std::result::Result<Self, SigmaError> {
+        let mut parts = key.split('|');
+        let field = parts.next().unwrap_or_default();
+        let modifiers: Vec<&str> = parts.collect();
+        let known = ["contains", "startswith", "endswith", "re", "i", "m", "s", "base64", "base64offset", "cidr", "all"];
+        if let Some(unknown) = modifiers.iter().find(|modifier| !known.contains(modifier)) {
+            return Err(SigmaError::new(format!("Unsupported modifier '{}' on field '{}'", unknown, field)));
+        }
+        let has = |name: &str| modifiers.contains(&name);
+
+        let raw_values: Vec<Option<String>> = match values {
+            Value::Sequence(items) => items.iter().map(scalar_text).collect::<std::result::Result<_, _>>()?,
+            other => vec![scalar_text(other)?]
+        };
+        let mut matchers = Vec::new();
+        for raw in raw_values {
+            let Some(text) = raw else {
+                matchers.push(ValueMatcher::Null);
+                continue;
+            };
+            if has("re") {
+                let regex = RegexBuilder::new(&text)
+                    .case_insensitive(has("i"))
+                    .multi_line(has("m"))
+                    .dot_matches_new_line(has("s"))
+                    .build()
+                    .map_err(|e| SigmaError::new(format!("Bad regex on field '{}': {}", field, e)))?;
+                matchers.push(ValueMatcher::Regex(regex));
+            } else if has("cidr") {
+                let network = text.parse::<IpNet>()
+                    .or_else(|_| text.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| SigmaError::new(format!("Bad CIDR '{}' on field '{}'", text, field)))?;
+                matchers.push(ValueMatcher::Cidr(network));
+            } else {
+                // Encoded values are taken literally, the encoding has no wildcards
+                let variants: Vec<Vec<Piece>> = if has("base64offset") {
+                    base64_offsets(&text).iter().map(|variant| literal_pieces(variant)).collect()
+                } else if has("base64") {
+                    vec![literal_pieces(&BASE64.encode(text.as_bytes()))]
+                } else {
+                    vec![wildcard_pieces(&text)]
+                };
+                for mut pieces in variants {
+                    if has("contains") || has("endswith") {
+                        pieces.insert(0, Piece::AnyRun);
+                    }
+                    if has("contains") || has("startswith") {
+                        pieces.push(Piece::AnyRun);
+                    }
+                    matchers.push(compile_pieces(pieces)?);
+                }
+            }
+        }
+        // base64offset expands one value into three alternatives, so all can't apply to it
+        if has("all") && has("base64offset") {
+            return Err(SigmaError::new(format!("'all' can't be combined with 'base64offset' on field '{}'", field)));
+        }
+        let fields = config.mapped_fields(field).iter().map(|name| name.to_lowercase()).collect();
+        Ok(Self { fields: fields, matchers: matchers, all: has("all") })
+    }
+
+    fn matches(&self, event: &EventFields) -> bool {
+        let values: Vec<&String> = self.fields.iter().filter_map(|field| event.get(field)).flatten().collect();
+        let check = |matcher: &ValueMatcher| {
+            if values.is_empty() {
+                matches!(matcher, ValueMatcher::Null)
+            } else {
+                values.iter().any(|value| matcher.matches(value))
+            }
+        };
+        if self.all {
+            self.matchers.iter().all(check)
+        } else {
+            self.matchers.iter().any(check)
+        }
+    }
+}
+
+// One named entry under detection
+#[derive(Debug, Clone)]
+pub enum Selection {
+    // A map: every field has to match
+    Fields(Vec<FieldMatcher>),
+    // A list of maps: any of them
+    AnyOf(Vec<Selection>),
+    // Every one of them, for a logsource naming both a category and a service
+    AllOf(Vec<Selection>),
+    // A list of plain values searched for in every field
+    Keywords(Vec<ValueMatcher>),
+}
+
+impl Selection {
+    pub fn compile(value: &Value, config: &SigmaConfig) -> std::result::Result<Self, SigmaError> {
+        match value {
+            Value::Mapping(map) => Self::compile_map(map, config),
+            Value::Sequence(items) if items.iter().all(|item| item.is_mapping()) => {
+                let selections = items.iter().map(|item| Self::compile(item, config)).collect::<std::result::Result<_, _>>()?;
+                Ok(Selection::AnyOf(selections))
+            },
+            Value::Sequence(_) | Value::String(_) | Value::Number(_) => {
+                let matcher = FieldMatcher::compile("keywords|contains", value, config)?;
+                Ok(Selection::Keywords(matcher.matchers))
+            },
+            other => Err(SigmaError::new(format!("Can't read selection {:?}", other)))
+        }
+    }
+
+    fn compile_map(map: &Mapping, config: &SigmaConfig) -> std::result::Result<Self, SigmaError> {
+        let mut matchers = Vec::new();
+        for (key, values) in map {
+            let key = key.as_str().ok_or(SigmaError::new(format!("Field names have to be strings, got {:?}", key)))?;
+            matchers.push(FieldMatcher::compile(key, values, config)?);
+        }
+        Ok(Selection::Fields(matchers))
+    }
+
+    pub fn matches(&self, event: &EventFields) -> bool {
+        match self {
+            Selection::Fields(matchers) => matchers.iter().all(|matcher| matcher.matches(event)),
+            Selection::AnyOf(selections) => selections.iter().any(|selection| selection.matches(event)),
+            Selection::AllOf(selections) => selections.iter().all(|selection| selection.matches(event)),
+            Selection::Keywords(matchers) => event.values().any(|value| matchers.iter().any(|matcher| matcher.matches(value))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SigmaRule {
+    pub id: String,
+    pub name: String,
+    pub title: String,
+    pub level: String,
+    pub tags: Vec<String>,
+    pub path: String,
+    // The channels/event IDs the logsource maps to, None when it names neither a category nor a service
+    logsource: Option<Selection>,
+    selections: HashMap<String, Selection>,
+    // Several conditions in a list are alternatives
+    conditions: Vec<Condition>,
+}
+
+impl SigmaRule {
+    pub fn from_yaml(document: &Value, path: &str, config: &SigmaConfig) -> std::result::Result<Self, SigmaError> {
+        let text = |key: &str| document.get(key).and_then(|value| value.as_str()).unwrap_or_default().to_string();
+        let title = text("title");
+        let logsource = document.get("logsource").ok_or(SigmaError::new("Missing logsource".to_string()))?;
+        let logsource_field = |key: &str| logsource.get(key).and_then(|value| value.as_str()).map(|value| value.to_lowercase());
+        if let Some(product) = logsource_field("product") {
+            if product != "windows" {
+                return Err(SigmaError::new(format!("Logsource product '{}' isn't windows", product)));
+            }
+        }
+        let logsource = config.logsource_selection(logsource_field("category"), logsource_field("service"))?;
+
+        let detection = document.get("detection").and_then(|value| value.as_mapping())
+            .ok_or(SigmaError::new("Missing detection".to_string()))?;
+        let mut selections = HashMap::new();
+        let mut condition_values = Vec::new();
+        for (key, value) in detection {
+            match key.as_str() {
+                Some("condition") => match value {
+                    Value::Sequence(items) => condition_values.extend(items.iter().filter_map(|item| item.as_str()).map(|item| item.to_string())),
+                    other => condition_values.push(other.as_str().unwrap_or_default().to_string())
+                },
+                // Only correlation needs the timeframe
+                Some("timeframe") => (),
+                Some(name) => {
+                    let selection = Selection::compile(value, config).map_err(|e| SigmaError::new(format!("Selection '{}': {}", name, e)))?;
+                    selections.insert(name.to_string(), selection);
+                },
+                None => return Err(SigmaError::new(format!("Selection names have to be strings, got {:?}", key)))
+            }
+        }
+        if condition_values.is_empty() {
+            return Err(SigmaError::new("Missing condition".to_string()));
+        }
+        let names: Vec<String> = {
+            // Keep the order of the rule file so "1 of" lists come out the way they were written
+            detection.keys().filter_map(|key| key.as_str()).filter(|key| selections.contains_key(*key)).map(|key| key.to_string()).collect()
+        };
+        let conditions = condition_values.iter().map(|condition| Condition::parse(condition, &names)).collect::<std::result::Result<_, _>>()?;
+
+        let tags = document.get("tags").and_then(|value| value.as_sequence())
+            .map(|items| items.iter().filter_map(|item| item.as_str()).map(|item| item.to_string()).collect())
+            .unwrap_or_default();
+        Ok(Self {
+            id: text("id"),
+            name: text("name"),
+            title: title,
+            level: text("level"),
+            tags: tags,
+            path: path.to_string(),
+            logsource: logsource,
+            selections: selections,
+            conditions: conditions,
+        })
+    }
+
+    // The names correlations can reference this rule by
+    pub fn references(&self) -> Vec<&str> {
+        [self.name.as_str(), self.id.as_str()].into_iter().filter(|reference| !reference.is_empty()).collect()
+    }
+
+    pub fn matches(&self, event: &EventFields) -> bool {
+        if let Some(logsource) = &self.logsource {
+            if !logsource.matches(event) {
+                return false;
+            }
+        }
+        let selected = |name: &str| self.selections.get(name).map(|selection| selection.matches(event)).unwrap_or(false);
+        self.conditions.iter().any(|condition| condition.evaluate(&selected))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+    use crate::sigma::SigmaConfig;
+    use crate::sigma::rule::{base64_offsets, EventFields, Selection};
+    use crate::test_support::TestEvent;
+
+    fn fields() -> EventFields {
+        let event = TestEvent::default().provider("Microsoft-Windows-Sysmon").level(4).keywords("0x8000000000000000").time("2023-05-01T12:00:00.0000000Z")
+            .record_id(77).channel("Microsoft-Windows-Sysmon/Operational").computer("WS01")
+            .data("Image", r"C:\Windows\System32\WindowsPowerShell\v1.0\powershell.exe").data("CommandLine", "powershell -enc SQBFAFgAIAAoAE4AZQB3AC0A")
+            .data("DestinationIp", "10.1.2.3").data("ParentUser", "").build();
+        EventFields::from_event(&event)
+    }
+
+    fn selection(yaml: &str) -> Selection {
+        let value: Value = serde_yaml::from_str(yaml).unwrap();
+        Selection::compile(&value, &SigmaConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn test_modifiers() {
+        let event = fields();
+        assert!(selection(r"Image|endswith: '\powershell.exe'").matches(&event));
+        assert!(selection(r"Image: 'c:\windows\system32\windowspowershell\v1.0\POWERSHELL.EXE'").matches(&event));
+        assert!(selection(r"Image: '*\WindowsPowerShell\\*'").matches(&event));
+        // \* is a literal star, not a wildcard
+        assert!(!selection(r"Image: '*\WindowsPowerShell\*'").matches(&event));
+        assert!(!selection(r"Image|startswith: 'D:\'").matches(&event));
+        assert!(selection("CommandLine|contains|all: ['-enc', 'powershell']").matches(&event));
+        assert!(!selection("CommandLine|contains|all: ['-enc', 'bypass']").matches(&event));
+        assert!(selection("CommandLine|re: '-[Ee]nc\\s+[A-Za-z0-9+/=]+$'").matches(&event));
+        assert!(selection("DestinationIp|cidr: ['192.168.0.0/16', '10.0.0.0/8']").matches(&event));
+        assert!(!selection("DestinationIp|cidr: '172.16.0.0/12'").matches(&event));
+        assert!(selection("EventID: [1, 3]").matches(&event));
+        assert!(selection("ParentUser: null").matches(&event));
+        assert!(selection("MissingField: null").matches(&event));
+        assert!(!selection("Image: null").matches(&event));
+        // Keyword lists look through every field
+        assert!(selection("['SQBFAFgA']").matches(&event));
+        // A list of maps is an or of the maps
+        assert!(selection("[{EventID: 3}, {Computer: ws01}]").matches(&event));
+    }
+
+    #[test]
+    fn test_base64_offsets() {
+        // Reference values from the Sigma specification
+        assert_eq!(base64_offsets("/bin/bash"), vec!["L2Jpbi9iYXNo", "9iaW4vYmFza", "vYmluL2Jhc2"]);
+        let event = EventFields::from_event(&TestEvent::default().data("CommandLine", "x aHR0cDovL2V4YW1wbGU=").build());
+        // "http://example" encoded, found through the offset-0 variant of "http://"
+        assert!(selection("CommandLine|base64offset|contains: 'http://'").matches(&event));
+        assert!(!selection("CommandLine|base64offset|contains: 'ftp://'").matches(&event));
+    }
+}
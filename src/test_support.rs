@@ -0,0 +1,220 @@
+// Fixtures shared by the unit tests
+use std::path::PathBuf;
+use crate::events::EvtEvent;
+
+// A path in the temp directory that is unique to this test process, keeping the extension of name
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("evtrustler_{}_{}", std::process::id(), name))
+}
+
+// Builds event XML with only the System elements a test asks for
+pub struct TestEvent {
+    namespace: bool,
+    provider: String,
+    guid: Option<String>,
+    event_id: u32,
+    version: Option<u8>,
+    level: Option<u8>,
+    task: Option<u16>,
+    opcode: Option<u8>,
+    keywords: Option<String>,
+    time: String,
+    record_id: u64,
+    correlation: bool,
+    execution: Option<(u32, u32)>,
+    channel: String,
+    computer: String,
+    user_id: Option<String>,
+    data: Vec<(String, String)>,
+    message: Option<String>,
+    source: Option<String>
+}
+
+impl Default for TestEvent {
+    fn default() -> Self {
+        TestEvent {
+            namespace: false,
+            provider: "Test-Provider".to_string(),
+            guid: None,
+            event_id: 1,
+            version: None,
+            level: None,
+            task: None,
+            opcode: None,
+            keywords: None,
+            time: "2023-05-01T00:00:00.0000000Z".to_string(),
+            record_id: 1,
+            correlation: false,
+            execution: None,
+            channel: "Application".to_string(),
+            computer: "HOST".to_string(),
+            user_id: None,
+            data: Vec::new(),
+            message: None,
+            source: None
+        }
+    }
+}
+
+impl TestEvent {
+    // The Windows event schema namespace on the root element
+    pub fn namespace(mut self) -> Self {
+        self.namespace = true;
+        self
+    }
+
+    pub fn provider(mut self, provider: &str) -> Self {
+        self.provider = provider.to_string();
+        self
+    }
+
+    pub fn guid(mut self, guid: &str) -> Self {
+        self.guid = Some(guid.to_string());
+        self
+    }
+
+    pub fn event_id(mut self, event_id: u32) -> Self {
+        self.event_id = event_id;
+        self
+    }
+
+    pub fn version(mut self, version: u8) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    pub fn level(mut self, level: u8) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    pub fn task(mut self, task: u16) -> Self {
+        self.task = Some(task);
+        self
+    }
+
+    pub fn opcode(mut self, opcode: u8) -> Self {
+        self.opcode = Some(opcode);
+        self
+    }
+
+    pub fn keywords(mut self, keywords: &str) -> Self {
+        self.keywords = Some(keywords.to_string());
+        self
+    }
+
+    pub fn time(mut self, time: &str) -> Self {
+        self.time = time.to_string();
+        self
+    }
+
+    pub fn record_id(mut self, record_id: u64) -> Self {
+        self.record_id = record_id;
+        self
+    }
+
+    // An empty Correlation element
+    pub fn correlation(mut self) -> Self {
+        self.correlation = true;
+        self
+    }
+
+    pub fn execution(mut self, process_id: u32, thread_id: u32) -> Self {
+        self.execution = Some((process_id, thread_id));
+        self
+    }
+
+    pub fn channel(mut self, channel: &str) -> Self {
+        self.channel = channel.to_string();
+        self
+    }
+
+    pub fn computer(mut self, computer: &str) -> Self {
+        self.computer = computer.to_string();
+        self
+    }
+
+    pub fn user_id(mut self, user_id: &str) -> Self {
+        self.user_id = Some(user_id.to_string());
+        self
+    }
+
+    // The value goes into the XML as is, so it must already be escaped
+    pub fn data(mut self, name: &str, value: &str) -> Self {
+        self.data.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    // A rendered message in RenderingInfo
+    pub fn message(mut self, message: &str) -> Self {
+        self.message = Some(message.to_string());
+        self
+    }
+
+    pub fn source(mut self, source: &str) -> Self {
+        self.source = Some(source.to_string());
+        self
+    }
+
+    pub fn xml(&self) -> String {
+        let mut xml = String::new();
+        if self.namespace {
+            xml.push_str(r#"<Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event"><System>"#);
+        } else {
+            xml.push_str("<Event><System>");
+        }
+        match &self.guid {
+            Some(guid) => xml.push_str(&format!(r#"<Provider Name="{}" Guid="{}" />"#, self.provider, guid)),
+            None => xml.push_str(&format!(r#"<Provider Name="{}" />"#, self.provider))
+        }
+        xml.push_str(&format!("<EventID>{}</EventID>", self.event_id));
+        if let Some(version) = self.version {
+            xml.push_str(&format!("<Version>{}</Version>", version));
+        }
+        if let Some(level) = self.level {
+            xml.push_str(&format!("<Level>{}</Level>", level));
+        }
+        if let Some(task) = self.task {
+            xml.push_str(&format!("<Task>{}</Task>", task));
+        }
+        if let Some(opcode) = self.opcode {
+            xml.push_str(&format!("<Opcode>{}</Opcode>", opcode));
+        }
+        if let Some(keywords) = &self.keywords {
+            xml.push_str(&format!("<Keywords>{}</Keywords>", keywords));
+        }
+        xml.push_str(&format!(r#"<TimeCreated SystemTime="{}" />"#, self.time));
+        xml.push_str(&format!("<EventRecordID>{}</EventRecordID>", self.record_id));
+        if self.correlation {
+            xml.push_str("<Correlation />");
+        }
+        if let Some((process_id, thread_id)) = self.execution {
+            xml.push_str(&format!(r#"<Execution ProcessID="{}" ThreadID="{}" />"#, process_id, thread_id));
+        }
+        xml.push_str(&format!("<Channel>{}</Channel><Computer>{}</Computer>", self.channel, self.computer));
+        if let Some(user_id) = &self.user_id {
+            xml.push_str(&format!(r#"<Security UserID="{}" />"#, user_id));
+        }
+        xml.push_str("</System>");
+        if !self.data.is_empty() {
+            xml.push_str("<EventData>");
+            for (name, value) in &self.data {
+                xml.push_str(&format!(r#"<Data Name="{}">{}</Data>"#, name, value));
+            }
+            xml.push_str("</EventData>");
+        }
+        if let Some(message) = &self.message {
+            xml.push_str(&format!(r#"<RenderingInfo Culture="en-US"><Message>{}</Message></RenderingInfo>"#, message));
+        }
+        xml.push_str("</Event>");
+        xml
+    }
+
+    pub fn build(&self) -> EvtEvent {
+        let mut event = EvtEvent::from_xml(self.xml()).unwrap();
+        if let Some(source) = &self.source {
+            event.set_source(source.clone());
+        }
+        event
+    }
+}
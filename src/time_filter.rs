@@ -0,0 +1,148 @@
+use chrono::{DateTime, Duration, NaiveDate, SecondsFormat, TimeZone, Utc};
+
+// Parses a --since/--until value. Accepts RFC 3339 timestamps, plain dates (midnight
+// UTC), relative durations counted back from now ("90s", "15m", "24h", "7d", "2w")
+// and epoch values in seconds, or milliseconds when there are more than 11 digits.
+pub fn parse_time(value: &str, now: DateTime<Utc>) -> std::result::Result<DateTime<Utc>, String> {
+    let value = value.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()));
+    }
+    if value.chars().last().map(|c| c.is_ascii_alphabetic()).unwrap_or(false) {
+        let duration = parse_duration(value)?;
+        return now.checked_sub_signed(duration).ok_or(format!("Relative time '{}' is out of range", value));
+    }
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if !whole.is_empty() && whole.chars().all(|c| c.is_ascii_digit()) && fraction.chars().all(|c| c.is_ascii_digit()) {
+        let number = whole.parse::<i64>().map_err(|e| e.to_string())?;
+        let time = if whole.len() > 11 {
+            Utc.timestamp_millis_opt(number).single()
+        } else {
+            // Pad or cut the fraction to nanoseconds
+            let nanos = format!("{:0<9}", fraction.chars().take(9).collect::<String>()).parse::<u32>().unwrap_or(0);
+            Utc.timestamp_opt(number, nanos).single()
+        };
+        return time.ok_or(format!("Epoch value '{}' is out of range", value));
+    }
+    Err(format!("Couldn't read '{}' as an RFC 3339 timestamp, relative duration or epoch value", value))
+}
+
+// Durations like "90s", "15m", "24h", "7d" or "2w"
+pub fn parse_duration(value: &str) -> std::result::Result<Duration, String> {
+    let value = value.trim();
+    let unit = value.chars().last().ok_or("Empty duration".to_string())?;
+    let amount = value[..value.len() - unit.len_utf8()].parse::<i64>().map_err(|_| format!("Invalid duration '{}'", value))?;
+    let duration = match unit {
+        's' => Duration::try_seconds(amount),
+        'm' => Duration::try_minutes(amount),
+        'h' => Duration::try_hours(amount),
+        'd' => Duration::try_days(amount),
+        'w' => Duration::try_weeks(amount),
+        _ => return Err(format!("Unknown unit '{}' in '{}', use s, m, h, d or w", unit, value))
+    };
+    duration.ok_or(format!("Duration '{}' is out of range", value))
+}
+
+// clap value parser for --since and --until
+pub fn parse_time_arg(value: &str) -> std::result::Result<DateTime<Utc>, String> {
+    parse_time(value, Utc::now())
+}
+
+// Inclusive window on TimeCreated. Either end can be left open.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimeRange {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl TimeRange {
+    pub fn new(since: Option<DateTime<Utc>>, until: Option<DateTime<Utc>>) -> std::result::Result<Self, String> {
+        if let (Some(since), Some(until)) = (since, until) {
+            if since > until {
+                return Err(format!("--since ({}) is after --until ({})", since, until));
+            }
+        }
+        Ok(Self { since: since, until: until })
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        self.since.is_none() && self.until.is_none()
+    }
+
+    // Events without a readable TimeCreated only pass when there's no window at all
+    pub fn contains(&self, time: Option<DateTime<Utc>>) -> bool {
+        if self.is_unbounded() {
+            return true;
+        }
+        match time {
+            Some(time) => self.since.map(|since| time >= since).unwrap_or(true) && self.until.map(|until| time <= until).unwrap_or(true),
+            None => false
+        }
+    }
+
+    // Predicate for the System element of an EvtQuery XPath. The event log service
+    // compares SystemTime at millisecond precision, so the window is widened to whole
+    // milliseconds here and contains() trims it back exactly.
+    pub fn xpath_condition(&self) -> Option<String> {
+        let mut conditions = Vec::new();
+        if let Some(since) = self.since {
+            let since = since - Duration::nanoseconds(since.timestamp_subsec_nanos() as i64 % 1_000_000);
+            conditions.push(format!("@SystemTime>='{}'", since.to_rfc3339_opts(SecondsFormat::Millis, true)));
+        }
+        if let Some(until) = self.until {
+            let remainder = until.timestamp_subsec_nanos() as i64 % 1_000_000;
+            let until = if remainder == 0 { until } else { until + Duration::nanoseconds(1_000_000 - remainder) };
+            conditions.push(format!("@SystemTime<='{}'", until.to_rfc3339_opts(SecondsFormat::Millis, true)));
+        }
+        if conditions.is_empty() {
+            return None;
+        }
+        Some(format!("TimeCreated[{}]", conditions.join(" and ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeZone, Utc};
+    use crate::time_filter::{parse_time, TimeRange};
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 5, 2, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_parse_time_formats() {
+        let expected = Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap();
+        assert_eq!(parse_time("2023-05-01T12:00:00Z", now()).unwrap(), expected);
+        assert_eq!(parse_time("2023-05-01T14:00:00+02:00", now()).unwrap(), expected);
+        assert_eq!(parse_time("24h", now()).unwrap(), expected);
+        assert_eq!(parse_time("1d", now()).unwrap(), expected);
+        assert_eq!(parse_time("1440m", now()).unwrap(), expected);
+        assert_eq!(parse_time("1682942400", now()).unwrap(), expected);
+        assert_eq!(parse_time("1682942400000", now()).unwrap(), expected);
+        assert_eq!(parse_time("1682942400.25", now()).unwrap().timestamp_subsec_millis(), 250);
+        assert_eq!(parse_time("2023-05-01", now()).unwrap(), Utc.with_ymd_and_hms(2023, 5, 1, 0, 0, 0).unwrap());
+        assert!(parse_time("3y", now()).is_err());
+        assert!(parse_time("yesterday", now()).is_err());
+    }
+
+    #[test]
+    fn test_range() {
+        let since = parse_time("2023-05-01T00:00:00.0000005Z", now()).unwrap();
+        let until = parse_time("2023-05-01T23:59:59.9991Z", now()).unwrap();
+        let range = TimeRange::new(Some(since), Some(until)).unwrap();
+        // Widened out to whole milliseconds on both ends
+        assert_eq!(range.xpath_condition().unwrap(), "TimeCreated[@SystemTime>='2023-05-01T00:00:00.000Z' and @SystemTime<='2023-05-02T00:00:00.000Z']");
+        assert!(range.contains(Some(since)));
+        assert!(!range.contains(Some(parse_time("2023-05-01T00:00:00Z", now()).unwrap())));
+        assert!(range.contains(Some(until)));
+        assert!(!range.contains(Some(parse_time("2023-05-01T23:59:59.9992Z", now()).unwrap())));
+        assert!(!range.contains(None));
+        assert!(TimeRange::default().contains(None));
+        assert_eq!(TimeRange::default().xpath_condition(), None);
+        assert!(TimeRange::new(Some(until), Some(since)).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use chrono::{DateTime, FixedOffset, SecondsFormat, Utc};
+use chrono::format::{Item, StrftimeItems};
+use chrono_tz::Tz;
+use crate::event_system::EvtSystem;
+
+// Zone timestamps are written in. Named zones follow their DST rules, so two events
+// a few hours apart can come out with different offsets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputZone {
+    Named(Tz),
+    Fixed(FixedOffset),
+}
+
+// --timezone value: an IANA name like "Europe/Berlin", "UTC", or a fixed offset like
+// "+02:00", "-0530", "+2" or "UTC+2"
+pub fn parse_timezone(value: &str) -> std::result::Result<OutputZone, String> {
+    let value = value.trim();
+    if let Ok(tz) = value.parse::<Tz>() {
+        return Ok(OutputZone::Named(tz));
+    }
+    let offset = value.strip_prefix("UTC").or(value.strip_prefix("GMT")).unwrap_or(value);
+    let invalid = || format!("Unknown timezone '{}', use an IANA name like Europe/Berlin or an offset like +02:00", value);
+    let (sign, digits) = match offset.chars().next() {
+        Some('+') => (1, &offset[1..]),
+        Some('-') => (-1, &offset[1..]),
+        _ => return Err(invalid())
+    };
+    if !digits.chars().all(|c| c.is_ascii_digit() || c == ':') {
+        return Err(invalid());
+    }
+    let (hours, minutes) = match digits.split_once(':') {
+        Some((hours, minutes)) => (hours, minutes),
+        None if digits.len() > 2 => digits.split_at(digits.len() - 2),
+        None => (digits, "0")
+    };
+    let hours = hours.parse::<i32>().map_err(|_| invalid())?;
+    let minutes = minutes.parse::<i32>().map_err(|_| invalid())?;
+    if hours > 23 || minutes > 59 {
+        return Err(invalid());
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).map(OutputZone::Fixed).ok_or_else(invalid)
+}
+
+// --time-format value, checked here so a typo fails before anything is read
+pub fn parse_time_format(value: &str) -> std::result::Result<String, String> {
+    if StrftimeItems::new(value).any(|item| item == Item::Error) {
+        return Err(format!("Invalid strftime pattern '{}'", value));
+    }
+    Ok(value.to_string())
+}
+
+// How writers turn TimeCreated into text. Left at the default, text columns keep
+// SystemTime exactly as the log has it.
+#[derive(Debug, Clone, Default)]
+pub struct TimeFormat {
+    // None keeps UTC
+    pub zone: Option<OutputZone>,
+    // strftime pattern. None is RFC 3339 with nanoseconds and the zone's offset.
+    pub pattern: Option<String>,
+    // Also write the original UTC SystemTime next to the converted one
+    pub include_utc: bool,
+}
+
+impl TimeFormat {
+    pub fn is_default(&self) -> bool {
+        self.zone.is_none() && self.pattern.is_none()
+    }
+
+    // Text for a TimeCreated column. SystemTime is passed through untouched when
+    // nothing was asked for, or when it couldn't be parsed.
+    pub fn time_created(&self, system: &EvtSystem) -> String {
+        match system.get_time_created() {
+            Some(time) if !self.is_default() => self.format(time),
+            _ => system.get_system_time().to_string()
+        }
+    }
+
+    pub fn format(&self, time: DateTime<Utc>) -> String {
+        match &self.pattern {
+            Some(pattern) => self.format_with(time, pattern),
+            None => self.rfc3339(time, SecondsFormat::Nanos)
+        }
+    }
+
+    // time in the output zone with a fixed strftime pattern, e.g. for partition dates
+    pub fn format_with(&self, time: DateTime<Utc>, pattern: &str) -> String {
+        match self.zone {
+            Some(OutputZone::Named(tz)) => time.with_timezone(&tz).format(pattern).to_string(),
+            Some(OutputZone::Fixed(offset)) => time.with_timezone(&offset).format(pattern).to_string(),
+            None => time.format(pattern).to_string()
+        }
+    }
+
+    // For fields whose schema requires RFC 3339 (ECS @timestamp, the RFC 5424 header).
+    // The zone applies, the pattern doesn't.
+    pub fn rfc3339(&self, time: DateTime<Utc>, precision: SecondsFormat) -> String {
+        match self.zone {
+            Some(OutputZone::Named(tz)) => time.with_timezone(&tz).to_rfc3339_opts(precision, true),
+            Some(OutputZone::Fixed(offset)) => time.with_timezone(&offset).to_rfc3339_opts(precision, true),
+            None => time.to_rfc3339_opts(precision, true)
+        }
+    }
+
+    // Timezone for typed timestamp columns, which hold the instant and only carry the
+    // zone as metadata for readers to display it in
+    pub fn zone_name(&self) -> String {
+        match self.zone {
+            Some(OutputZone::Named(tz)) => tz.name().to_string(),
+            Some(OutputZone::Fixed(offset)) => offset.to_string(),
+            None => "UTC".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{SecondsFormat, TimeZone, Utc};
+    use xmltree::Element;
+    use crate::event_system::EvtSystem;
+    use crate::time_format::{parse_time_format, parse_timezone, OutputZone, TimeFormat};
+
+    #[test]
+    fn test_parse_timezone() {
+        assert_eq!(parse_timezone("Europe/Berlin"), Ok(OutputZone::Named(chrono_tz::Europe::Berlin)));
+        for (value, seconds) in [("+02:00", 7200), ("-0530", -19800), ("+2", 7200), ("UTC-3", -10800), ("GMT+05:45", 20700)] {
+            match parse_timezone(value) {
+                Ok(OutputZone::Fixed(offset)) => assert_eq!(offset.local_minus_utc(), seconds, "{}", value),
+                other => panic!("{} parsed as {:?}", value, other)
+            }
+        }
+        assert!(parse_timezone("Mars/Olympus").is_err());
+        assert!(parse_timezone("+25:00").is_err());
+        assert!(parse_timezone("+1€").is_err());
+        assert!(parse_timezone("+€€").is_err());
+        assert!(parse_time_format("%Y-%m-%d %H:%M:%S").is_ok());
+        assert!(parse_time_format("%Y-%Q").is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        let xml = r#"<System><EventID>1</EventID><TimeCreated SystemTime="2023-07-01T12:34:56.1234567Z" /><EventRecordID>1</EventRecordID></System>"#;
+        let system = EvtSystem::from_element(&Element::parse(xml.as_bytes()).unwrap()).unwrap();
+        assert_eq!(TimeFormat::default().time_created(&system), "2023-07-01T12:34:56.1234567Z");
+
+        // Named zones follow DST, New York is on EDT in July and EST in January
+        let new_york = TimeFormat { zone: Some(parse_timezone("America/New_York").unwrap()), pattern: None, include_utc: true };
+        assert_eq!(new_york.time_created(&system), "2023-07-01T08:34:56.123456700-04:00");
+        let january = Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap();
+        assert_eq!(new_york.rfc3339(january, SecondsFormat::Secs), "2023-01-01T07:00:00-05:00");
+
+        let custom = TimeFormat { zone: Some(parse_timezone("+05:30").unwrap()), pattern: Some("%d/%m/%Y %H:%M:%S%.3f".to_string()), include_utc: false };
+        assert_eq!(custom.time_created(&system), "01/07/2023 18:04:56.123");
+        assert_eq!(custom.zone_name(), "+05:30");
+    }
+}
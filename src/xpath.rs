@@ -0,0 +1,568 @@
+use chrono::{DateTime, Utc};
+use xmltree::{Element, XMLNode};
+
+// Parser and evaluator for the XPath subset the event log service accepts in
+// EvtQuery: a path from the Event element with nested predicates, and/or/not,
+// comparisons, band(), timediff() and attribute tests like Data[@Name='x'].
+// Evaluating it here means the same query works against any parsed event.
+
+#[derive(Debug)]
+pub struct XPathError {
+    message: String,
+    position: usize,
+}
+
+impl std::error::Error for XPathError {}
+
+impl std::fmt::Display for XPathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Literal(String),
+    Number(i128),
+    Operator(CompareOp),
+    Star,
+    At,
+    Slash,
+    OpenBracket,
+    CloseBracket,
+    OpenParen,
+    CloseParen,
+    Comma,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl CompareOp {
+    fn holds(&self, ordering: std::cmp::Ordering) -> bool {
+        match self {
+            CompareOp::Equal => ordering.is_eq(),
+            CompareOp::NotEqual => ordering.is_ne(),
+            CompareOp::Less => ordering.is_lt(),
+            CompareOp::LessEqual => ordering.is_le(),
+            CompareOp::Greater => ordering.is_gt(),
+            CompareOp::GreaterEqual => ordering.is_ge(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Step {
+    // "*" matches any element
+    name: String,
+    predicates: Vec<Expr>,
+}
+
+#[derive(Debug, Clone)]
+struct Path {
+    steps: Vec<Step>,
+    attribute: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Band,
+    TimeDiff,
+    Not,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Compare(Box<Expr>, CompareOp, Box<Expr>),
+    Path(Path),
+    Literal(String),
+    Number(i128),
+    Call(Function, Vec<Expr>),
+}
+
+// Result of evaluating an expression. Node-sets only ever need their string values.
+enum Value {
+    Nodes(Vec<String>),
+    Text(String),
+    Number(i128),
+    Boolean(bool),
+}
+
+impl Value {
+    fn truthy(&self) -> bool {
+        match self {
+            Value::Nodes(nodes) => !nodes.is_empty(),
+            Value::Text(text) => !text.is_empty(),
+            Value::Number(number) => *number != 0,
+            Value::Boolean(value) => *value,
+        }
+    }
+
+    // The string values to compare, one per node for node-sets
+    fn strings(&self) -> Vec<String> {
+        match self {
+            Value::Nodes(nodes) => nodes.clone(),
+            Value::Text(text) => vec![text.clone()],
+            Value::Number(number) => vec![number.to_string()],
+            Value::Boolean(value) => vec![value.to_string()],
+        }
+    }
+}
+
+// Numbers in event XML are decimal, except Keywords which are 0x hex
+fn parse_number(text: &str) -> Option<i128> {
+    let text = text.trim();
+    match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        Some(hex) => i128::from_str_radix(hex, 16).ok(),
+        None => text.parse::<i128>().ok()
+    }
+}
+
+fn parse_time_ms(text: &str) -> Option<i128> {
+    DateTime::parse_from_rfc3339(text.trim()).ok().map(|time| time.timestamp_millis() as i128)
+}
+
+fn compare_strings(left: &str, op: CompareOp, right: &str) -> bool {
+    match op {
+        CompareOp::Equal => left == right,
+        CompareOp::NotEqual => left != right,
+        // Ordering comparisons are numeric in XPath, and SystemTime compares as a time
+        _ => match (parse_number(left), parse_number(right)) {
+            (Some(left), Some(right)) => op.holds(left.cmp(&right)),
+            _ => match (parse_time_ms(left), parse_time_ms(right)) {
+                (Some(left), Some(right)) => op.holds(left.cmp(&right)),
+                _ => false
+            }
+        }
+    }
+}
+
+fn compare(left: &Value, op: CompareOp, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Boolean(_), _) | (_, Value::Boolean(_)) => op.holds(left.truthy().cmp(&right.truthy())),
+        // Against a number every node is converted to a number first
+        (Value::Number(number), other) | (other, Value::Number(number)) => {
+            let flipped = matches!(left, Value::Number(_));
+            other.strings().iter().filter_map(|text| parse_number(text)).any(|value| {
+                if flipped { op.holds(number.cmp(&value)) } else { op.holds(value.cmp(number)) }
+            })
+        },
+        // Node-set comparisons hold when any pair of values does
+        _ => {
+            let right_strings = right.strings();
+            left.strings().iter().any(|left| right_strings.iter().any(|right| compare_strings(left, op, right)))
+        }
+    }
+}
+
+fn element_text(element: &Element) -> String {
+    let mut text = String::new();
+    for node in &element.children {
+        match node {
+            XMLNode::Text(value) | XMLNode::CData(value) => text.push_str(value),
+            XMLNode::Element(child) => text.push_str(&element_text(child)),
+            _ => ()
+        }
+    }
+    text.trim().to_string()
+}
+
+fn child_elements(element: &Element) -> impl Iterator<Item = &Element> {
+    element.children.iter().filter_map(|node| match node {
+        XMLNode::Element(child) => Some(child),
+        _ => None
+    })
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn tokenize(query: &str) -> std::result::Result<Vec<(Token, usize)>, XPathError> {
+        let chars: Vec<char> = query.chars().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let start = i;
+            let token = match c {
+                c if c.is_whitespace() => {
+                    i += 1;
+                    continue;
+                },
+                '*' => Token::Star,
+                '@' => Token::At,
+                '/' => Token::Slash,
+                '[' => Token::OpenBracket,
+                ']' => Token::CloseBracket,
+                '(' => Token::OpenParen,
+                ')' => Token::CloseParen,
+                ',' => Token::Comma,
+                '=' => Token::Operator(CompareOp::Equal),
+                '!' if chars.get(i + 1) == Some(&'=') => {
+                    i += 1;
+                    Token::Operator(CompareOp::NotEqual)
+                },
+                '<' | '>' => {
+                    let or_equal = chars.get(i + 1) == Some(&'=');
+                    if or_equal {
+                        i += 1;
+                    }
+                    match (c, or_equal) {
+                        ('<', false) => Token::Operator(CompareOp::Less),
+                        ('<', true) => Token::Operator(CompareOp::LessEqual),
+                        ('>', false) => Token::Operator(CompareOp::Greater),
+                        _ => Token::Operator(CompareOp::GreaterEqual)
+                    }
+                },
+                '\'' | '"' => {
+                    let end = chars[i + 1..].iter().position(|&other| other == c)
+                        .ok_or(XPathError { message: "Unterminated string".to_string(), position: start })?;
+                    let literal: String = chars[i + 1..i + 1 + end].iter().collect();
+                    i += end + 1;
+                    Token::Literal(literal)
+                },
+                c if c.is_ascii_digit() => {
+                    let mut end = i;
+                    while end < chars.len() && chars[end].is_ascii_alphanumeric() {
+                        end += 1;
+                    }
+                    let text: String = chars[i..end].iter().collect();
+                    let number = parse_number(&text).ok_or(XPathError { message: format!("Invalid number '{}'", text), position: start })?;
+                    i = end - 1;
+                    Token::Number(number)
+                },
+                c if c.is_alphabetic() || c == '_' => {
+                    let mut end = i;
+                    while end < chars.len() && (chars[end].is_alphanumeric() || matches!(chars[end], '_' | '-' | '.' | ':')) {
+                        end += 1;
+                    }
+                    let name: String = chars[i..end].iter().collect();
+                    i = end - 1;
+                    Token::Name(name)
+                },
+                other => return Err(XPathError { message: format!("Unexpected character '{}'", other), position: start })
+            };
+            tokens.push((token, start));
+            i += 1;
+        }
+        Ok(tokens)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn error(&self, message: &str) -> XPathError {
+        let position = self.tokens.get(self.position).map(|(_, position)| *position).unwrap_or(self.end);
+        XPathError { message: message.to_string(), position: position }
+    }
+
+    fn expect(&mut self, expected: Token) -> std::result::Result<(), XPathError> {
+        if self.peek() == Some(&expected) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("Expected {:?}", expected)))
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Name(name)) if name == keyword)
+    }
+
+    fn parse_or(&mut self) -> std::result::Result<Expr, XPathError> {
+        let mut expr = self.parse_and()?;
+        while self.is_keyword("or") {
+            self.position += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> std::result::Result<Expr, XPathError> {
+        let mut expr = self.parse_comparison()?;
+        while self.is_keyword("and") {
+            self.position += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_comparison()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_comparison(&mut self) -> std::result::Result<Expr, XPathError> {
+        let left = self.parse_primary()?;
+        if let Some(Token::Operator(op)) = self.peek().cloned() {
+            self.position += 1;
+            let right = self.parse_primary()?;
+            return Ok(Expr::Compare(Box::new(left), op, Box::new(right)));
+        }
+        Ok(left)
+    }
+
+    fn parse_primary(&mut self) -> std::result::Result<Expr, XPathError> {
+        match self.peek().cloned() {
+            Some(Token::OpenParen) => {
+                self.position += 1;
+                let expr = self.parse_or()?;
+                self.expect(Token::CloseParen)?;
+                Ok(expr)
+            },
+            Some(Token::Literal(literal)) => {
+                self.position += 1;
+                Ok(Expr::Literal(literal))
+            },
+            Some(Token::Number(number)) => {
+                self.position += 1;
+                Ok(Expr::Number(number))
+            },
+            Some(Token::Name(name)) if self.tokens.get(self.position + 1).map(|(token, _)| token) == Some(&Token::OpenParen) => {
+                let function = match name.as_str() {
+                    "band" => Function::Band,
+                    "timediff" => Function::TimeDiff,
+                    "not" => Function::Not,
+                    _ => return Err(self.error(&format!("Unsupported function '{}'", name)))
+                };
+                self.position += 2;
+                let mut arguments = Vec::new();
+                if self.peek() != Some(&Token::CloseParen) {
+                    arguments.push(self.parse_or()?);
+                    while self.peek() == Some(&Token::Comma) {
+                        self.position += 1;
+                        arguments.push(self.parse_or()?);
+                    }
+                }
+                self.expect(Token::CloseParen)?;
+                let arity_ok = match function {
+                    Function::Band => arguments.len() == 2,
+                    Function::TimeDiff => arguments.len() == 1 || arguments.len() == 2,
+                    Function::Not => arguments.len() == 1
+                };
+                if !arity_ok {
+                    return Err(self.error(&format!("Wrong number of arguments to {}()", name)));
+                }
+                Ok(Expr::Call(function, arguments))
+            },
+            Some(Token::Name(_)) | Some(Token::Star) | Some(Token::At) | Some(Token::Slash) => Ok(Expr::Path(self.parse_path()?)),
+            _ => Err(self.error("Expected an expression"))
+        }
+    }
+
+    fn parse_path(&mut self) -> std::result::Result<Path, XPathError> {
+        let mut path = Path { steps: Vec::new(), attribute: None };
+        // A leading slash only anchors the path to the document, which is where it starts anyway
+        if self.peek() == Some(&Token::Slash) {
+            self.position += 1;
+        }
+        loop {
+            match self.peek().cloned() {
+                Some(Token::At) => {
+                    self.position += 1;
+                    match self.peek().cloned() {
+                        Some(Token::Name(name)) => {
+                            self.position += 1;
+                            path.attribute = Some(name);
+                        },
+                        _ => return Err(self.error("Expected an attribute name"))
+                    }
+                    return Ok(path);
+                },
+                Some(Token::Name(name)) => {
+                    self.position += 1;
+                    path.steps.push(Step { name: name, predicates: self.parse_predicates()? });
+                },
+                Some(Token::Star) => {
+                    self.position += 1;
+                    path.steps.push(Step { name: "*".to_string(), predicates: self.parse_predicates()? });
+                },
+                _ => return Err(self.error("Expected an element name"))
+            }
+            if self.peek() != Some(&Token::Slash) {
+                return Ok(path);
+            }
+            self.position += 1;
+        }
+    }
+
+    fn parse_predicates(&mut self) -> std::result::Result<Vec<Expr>, XPathError> {
+        let mut predicates = Vec::new();
+        while self.peek() == Some(&Token::OpenBracket) {
+            self.position += 1;
+            predicates.push(self.parse_or()?);
+            self.expect(Token::CloseBracket)?;
+        }
+        Ok(predicates)
+    }
+}
+
+// --query value. Queries copied out of a QueryList still have their XML escapes, so
+// they're decoded here. Text read from a query file has been decoded by the XML parser.
+pub fn parse_query_arg(value: &str) -> std::result::Result<XPathQuery, String> {
+    let query = value.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&");
+    XPathQuery::parse(&query).map_err(|e| e.to_string())
+}
+
+#[derive(Debug, Clone)]
+pub struct XPathQuery {
+    path: Path,
+}
+
+impl XPathQuery {
+    pub fn parse(query: &str) -> std::result::Result<Self, XPathError> {
+        let mut parser = Parser { tokens: Parser::tokenize(&query)?, position: 0, end: query.len() };
+        let path = parser.parse_path()?;
+        if path.attribute.is_some() {
+            return Err(XPathError { message: "A query has to select events, not an attribute".to_string(), position: 0 });
+        }
+        if parser.position != parser.tokens.len() {
+            return Err(parser.error("Unexpected text after the query"));
+        }
+        Ok(Self { path: path })
+    }
+
+    // The first step is matched against the Event element itself
+    pub fn matches(&self, root: &Element, now: DateTime<Utc>) -> bool {
+        let evaluator = Evaluator { now_ms: now.timestamp_millis() as i128 };
+        !evaluator.select(&self.path, vec![root]).is_empty()
+    }
+}
+
+struct Evaluator {
+    now_ms: i128,
+}
+
+impl Evaluator {
+    // Walks the steps from the given starting elements. The first step tests the
+    // starting elements themselves, later steps their children.
+    fn select<'a>(&self, path: &Path, start: Vec<&'a Element>) -> Vec<String> {
+        let mut current = start;
+        for (index, step) in path.steps.iter().enumerate() {
+            let candidates: Vec<&Element> = if index == 0 {
+                current
+            } else {
+                current.into_iter().flat_map(|element| child_elements(element)).collect()
+            };
+            current = candidates.into_iter()
+                .filter(|element| step.name == "*" || element.name == step.name)
+                .filter(|element| step.predicates.iter().all(|predicate| self.evaluate(predicate, element).truthy()))
+                .collect();
+        }
+        match &path.attribute {
+            Some(attribute) => current.iter().filter_map(|element| element.attributes.get(attribute).cloned()).collect(),
+            None => current.iter().map(|element| element_text(element)).collect()
+        }
+    }
+
+    fn evaluate(&self, expr: &Expr, context: &Element) -> Value {
+        match expr {
+            Expr::Or(left, right) => Value::Boolean(self.evaluate(left, context).truthy() || self.evaluate(right, context).truthy()),
+            Expr::And(left, right) => Value::Boolean(self.evaluate(left, context).truthy() && self.evaluate(right, context).truthy()),
+            Expr::Compare(left, op, right) => Value::Boolean(compare(&self.evaluate(left, context), *op, &self.evaluate(right, context))),
+            Expr::Literal(literal) => Value::Text(literal.clone()),
+            Expr::Number(number) => Value::Number(*number),
+            // Relative paths inside a predicate start from the context's children
+            Expr::Path(path) if path.steps.is_empty() => Value::Nodes(context.attributes.get(path.attribute.as_ref().unwrap()).cloned().into_iter().collect()),
+            Expr::Path(path) => Value::Nodes(self.select(path, child_elements(context).collect())),
+            Expr::Call(Function::Not, arguments) => Value::Boolean(!self.evaluate(&arguments[0], context).truthy()),
+            Expr::Call(Function::Band, arguments) => {
+                let values: Vec<Option<i128>> = arguments.iter().map(|argument| self.number(argument, context)).collect();
+                match (values[0], values[1]) {
+                    (Some(left), Some(right)) => Value::Number(left & right),
+                    _ => Value::Number(0)
+                }
+            },
+            Expr::Call(Function::TimeDiff, arguments) => {
+                let first = self.time(&arguments[0], context);
+                let second = match arguments.get(1) {
+                    Some(argument) => self.time(argument, context),
+                    None => Some(self.now_ms)
+                };
+                match (first, second) {
+                    (Some(first), Some(second)) => Value::Number(second - first),
+                    // Nothing to compare against, so no comparison can hold
+                    _ => Value::Nodes(Vec::new())
+                }
+            }
+        }
+    }
+
+    fn number(&self, expr: &Expr, context: &Element) -> Option<i128> {
+        match self.evaluate(expr, context) {
+            Value::Number(number) => Some(number),
+            other => other.strings().first().and_then(|text| parse_number(text))
+        }
+    }
+
+    fn time(&self, expr: &Expr, context: &Element) -> Option<i128> {
+        self.evaluate(expr, context).strings().first().and_then(|text| parse_time_ms(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use xmltree::Element;
+    use crate::xpath::{parse_query_arg, XPathQuery};
+
+    const EVENT: &str = r#"<Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event"><System><Provider Name="Microsoft-Windows-Security-Auditing" /><EventID>4625</EventID><Level>0</Level><Task>12544</Task><Keywords>0x8010000000000000</Keywords><TimeCreated SystemTime="2023-05-01T12:00:00.0000000Z" /><EventRecordID>77</EventRecordID><Channel>Security</Channel><Computer>WKS01</Computer></System><EventData><Data Name="TargetUserName">bob</Data><Data Name="LogonType">3</Data><Data Name="IpAddress">10.0.0.5</Data></EventData></Event>"#;
+
+    fn matches(query: &str) -> bool {
+        let root = Element::parse(EVENT.as_bytes()).unwrap();
+        let now = Utc.with_ymd_and_hms(2023, 5, 1, 13, 0, 0).unwrap();
+        XPathQuery::parse(query).unwrap().matches(&root, now)
+    }
+
+    #[test]
+    fn test_system_predicates() {
+        assert!(matches("*"));
+        assert!(matches("*[System[Provider[@Name='Microsoft-Windows-Security-Auditing']]]"));
+        assert!(!matches("*[System[Provider[@Name='Other']]]"));
+        assert!(matches("*[System[(EventID=4624 or EventID=4625) and Level<=4]]"));
+        assert!(!matches("*[System[EventID!=4625]]"));
+        assert!(matches("Event[System/EventID=4625]"));
+        assert!(matches("*[System[EventRecordID>76 and EventRecordID<78]]"));
+        assert!(matches("*[System[4625=EventID and 4626>EventID]]"));
+        assert!(matches("*[System[not(Level=2)]]"));
+        assert!(parse_query_arg("*[System[Level &lt;= 4]]").unwrap().matches(&Element::parse(EVENT.as_bytes()).unwrap(), Utc::now()));
+        // Only pasted --query text is unescaped
+        assert!(XPathQuery::parse("*[System[Level &lt;= 4]]").is_err());
+    }
+
+    #[test]
+    fn test_functions() {
+        assert!(matches("*[System[band(Keywords,4503599627370496)]]"));
+        assert!(!matches("*[System[band(Keywords,0x0020000000000000)]]"));
+        assert!(matches("*[System[TimeCreated[timediff(@SystemTime) <= 3600000]]]"));
+        assert!(!matches("*[System[TimeCreated[timediff(@SystemTime) < 3600000]]]"));
+        assert!(matches("*[System[TimeCreated[@SystemTime>='2023-05-01T11:00:00.000Z']]]"));
+    }
+
+    #[test]
+    fn test_event_data() {
+        assert!(matches("*[EventData[Data[@Name='TargetUserName']='bob']]"));
+        assert!(!matches("*[EventData[Data[@Name='TargetUserName']='alice']]"));
+        assert!(matches("*[EventData[Data[@Name='LogonType']=3 and Data='10.0.0.5']]"));
+        assert!(matches("*[EventData/Data[@Name='IpAddress']]"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(XPathQuery::parse("*[System[EventID=]]").is_err());
+        assert!(XPathQuery::parse("*[System[EventID=1]").is_err());
+        assert!(XPathQuery::parse("*[System[count(EventID)]]").is_err());
+        assert!(XPathQuery::parse("*[System[EventID='1]]").is_err());
+        assert!(XPathQuery::parse("*[System] extra").is_err());
+    }
+}